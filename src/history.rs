@@ -1,13 +1,17 @@
 #![allow(dead_code)]
 
-use super::strat::{EndsWith, Lap, LapState, Rate, StratRequest, Strategy, TimeSpan};
+use super::strat::{self, EndsWith, Lap, LapState, Rate, StratRequest, Strategy, TimeSpan};
 use druid::{Data, Lens};
+use log::warn;
 use r2d2::ManageConnection;
 use r2d2_sqlite::SqliteConnectionManager;
 use rusqlite::{params, Connection, Error};
 use std::{
-    cmp, error,
+    cell::RefCell,
+    cmp, error, fmt, io,
     path::{Path, PathBuf},
+    sync::mpsc,
+    thread,
 };
 
 #[derive(Clone, Debug, Data, Lens, PartialEq)]
@@ -35,52 +39,338 @@ impl RaceSession {
 pub struct Adjustments {
     pub max_fuel_save: Option<f32>,
     pub min_fuel: Option<f32>,
+    // how many of our laps we are behind the leader.
+    pub laps_behind_leader: Option<f32>,
+    // how many pace laps are still to run before the green flag.
+    pub pre_green_laps: Option<i32>,
+    // how many laps need running under power after the race ends.
+    pub cooldown_laps: Option<i32>,
+    // fuel to plan to have left at the finish.
+    pub finish_reserve: Option<f32>,
+    // plan on using this fraction more fuel per green lap than the recent average.
+    pub fuel_use_margin: Option<f32>,
+    // the caution laps still to run, overrides the guess from the recent yellow laps.
+    pub yellow_togo: Option<i32>,
 }
 impl Adjustments {
     fn none() -> Adjustments {
         Adjustments {
             max_fuel_save: None,
             min_fuel: None,
+            laps_behind_leader: None,
+            pre_green_laps: None,
+            cooldown_laps: None,
+            finish_reserve: None,
+            fuel_use_margin: None,
+            yellow_togo: None,
         }
     }
 }
 
+// picks out sessions by the tag they were saved with, e.g. "league race".
+#[derive(Clone, Debug, PartialEq)]
+pub enum TagFilter {
+    Only(String),
+    Exclude(String),
+}
+
+// the laps from a session that has ended, to carry over into the next one.
+#[derive(Clone, Debug)]
+pub struct CarriedLaps {
+    pub laps: Vec<Lap>,
+    drivers: Vec<i64>, // the id of the driver that ran each lap
+    written: usize,    // the laps from the start of laps already saved to the db
+}
+
 pub struct History {
     cfg: RaceSession,
     laps: Vec<Lap>,
+    lap_drivers: Vec<i64>, // the id of the driver that ran each lap
+    driver: i64,           // the id of the driver currently in the car
+    carried_laps: usize,   // laps at the start of laps that came from the previous session
     db: Option<Db>,
     def_green: Option<Rate>,
     def_yellow: Option<Rate>,
+    def_pit_loss: Option<TimeSpan>,
+    db_lap_counts: (i64, i64), // green & yellow laps for the car/track in the db
+    db_session_limit: Option<u32>,
+    tag_filter: Option<TagFilter>,
+    exclude_blue: bool,
+    baseline_green: Option<Rate>,
+    baseline_yellow: Option<Rate>,
+    // a rough guess at the green rate, used only when there's nothing better.
+    provisional_green: Option<Rate>,
+    // the last strategy calculated and the request (with a rounded fuel_left) it was for.
+    strat_cache: RefCell<Option<(StratRequest, Strategy)>>,
 }
 
 impl History {
-    pub fn new(cfg: RaceSession, db_file: Option<PathBuf>) -> Result<History, Error> {
-        let db = db_file.map(|f| Db::new(&f).ok()).flatten();
+    pub fn new(cfg: RaceSession, db_file: Option<PathBuf>) -> History {
+        // carry on without the db if it can't be opened, there's just no history to use.
+        let db = db_file.and_then(|f| match Db::new(&f) {
+            Ok(db) => Some(db),
+            Err(e) => {
+                warn!("failed to open laps db {}: {}", f.display(), e);
+                None
+            }
+        });
         let mut c = History {
             cfg,
             laps: Vec::with_capacity(16),
+            lap_drivers: Vec::with_capacity(16),
+            driver: 0,
+            carried_laps: 0,
             db,
             def_green: None,
             def_yellow: None,
+            def_pit_loss: None,
+            db_lap_counts: (0, 0),
+            db_session_limit: None,
+            tag_filter: None,
+            exclude_blue: false,
+            baseline_green: None,
+            baseline_yellow: None,
+            provisional_green: None,
+            strat_cache: RefCell::new(None),
         };
-        c.def_green =
-            c.db.as_ref()
-                .map(|db| db.db_green_laps(c.cfg.car_id, c.cfg.track_id))
-                .flatten();
-        c.def_yellow =
-            c.db.as_ref()
-                .map(|db| db.db_yellow_laps(c.cfg.car_id, c.cfg.track_id))
-                .flatten();
+        c.load_defaults();
         if let Some(db) = c.db.as_mut() {
-            db.insert_session(&c.cfg).expect("failed to insert session");
+            if let Err(e) = db.insert_session(&c.cfg) {
+                warn!("failed to add the session to the laps db: {}", e);
+                c.db = None;
+            }
         }
-        Ok(c)
+        c
     }
     pub fn config(&self) -> RaceSession {
         self.cfg.clone()
     }
+    // loads the default green/yellow rates from previous sessions in the DB, optionally
+    // only looking at the most recent db_session_limit sessions and ones matching tag_filter.
+    fn load_defaults(&mut self) {
+        if let Some(db) = self.db.as_ref() {
+            let (car_id, track_id) = (self.cfg.car_id, self.cfg.track_id);
+            let tags = self.tag_filter.as_ref();
+            let (limit, blue) = (self.db_session_limit, self.exclude_blue);
+            self.def_green = db.db_green_laps(car_id, track_id, blue, limit, tags);
+            self.def_yellow = db.db_yellow_laps(car_id, track_id, self.db_session_limit, tags);
+            self.def_pit_loss = db.avg_pit_loss(self.cfg.car_id, self.cfg.track_id);
+            self.db_lap_counts = (
+                db.lap_count(self.cfg.car_id, self.cfg.track_id, LapState::empty()),
+                db.lap_count(self.cfg.car_id, self.cfg.track_id, LapState::YELLOW),
+            );
+        }
+    }
+    // a setup change between sessions can make older laps misleading, this restricts
+    // the defaults to laps from the most recent sessions only.
+    pub fn set_db_session_limit(&mut self, session_limit: Option<u32>) {
+        self.db_session_limit = session_limit;
+        self.load_defaults();
+    }
+    // restricts the defaults to laps from sessions that match the filter.
+    pub fn set_tag_filter(&mut self, filter: Option<TagFilter>) {
+        self.tag_filter = filter;
+        self.load_defaults();
+    }
+    // tags the session in the db, so its laps can be included or left out of the defaults later.
+    pub fn set_session_tag(&mut self, tag: &str) -> Result<(), Error> {
+        match self.db.as_mut() {
+            Some(db) => db.set_session_tag(tag),
+            None => Ok(()),
+        }
+    }
+    // manually entered expected rates, these are only used when there are no rates from
+    // previous sessions for the car/track, and live laps will take over once available.
+    pub fn set_baseline(&mut self, green: Rate, yellow: Option<Rate>) {
+        self.baseline_green = Some(green);
+        self.baseline_yellow = yellow;
+    }
+    // a rough guess at the green rate to show a strategy with at the start of a race, until
+    // there are laps or a baseline/rates from previous sessions to use.
+    pub fn set_provisional(&mut self, green: Option<Rate>) {
+        self.provisional_green = green;
+        self.strat_cache.replace(None);
+    }
+    // the strategy is from the provisional green rate.
+    pub fn is_provisional(&self) -> bool {
+        self.provisional_green.is_some()
+            && self.def_green.or(self.baseline_green).is_none()
+            && self.laps.iter().filter(|l| self.is_green_lap(l)).count() < 2
+    }
+    // laps spent letting faster cars by are slow and use odd amounts of fuel, this
+    // leaves them out of the green flag rate.
+    pub fn set_exclude_blue(&mut self, exclude: bool) {
+        self.exclude_blue = exclude;
+        self.load_defaults();
+        self.strat_cache.replace(None);
+    }
+    pub fn laps(&self) -> &[Lap] {
+        &self.laps
+    }
+    // the laps to carry over to the next session. Any that haven't been saved yet are
+    // left to the next session to write, so they're not written here as well.
+    pub fn carry(&mut self) -> CarriedLaps {
+        let written = match self.db.as_mut() {
+            Some(db) => {
+                if let Err(e) = db.flush() {
+                    warn!("failed to save queued laps {:?}", e);
+                }
+                let written = db.laps_written;
+                db.laps_written = self.laps.len();
+                db.laps_queued = self.laps.len();
+                written
+            }
+            None => self.laps.len(),
+        };
+        CarriedLaps {
+            laps: self.laps.clone(),
+            drivers: self.lap_drivers.clone(),
+            written,
+        }
+    }
+    // going from practice to qualifying to the race starts a new session, laps from the previous
+    // session at the same car/track are kept so the rates learned in it carry on being used.
+    // The ones already saved aren't written to the db again.
+    pub fn carry_over(&mut self, prev: &RaceSession, carried: CarriedLaps) {
+        if prev.car_id != self.cfg.car_id || prev.track_id != self.cfg.track_id {
+            return;
+        }
+        let count = carried.laps.len();
+        let mut laps = carried.laps;
+        laps.append(&mut self.laps);
+        self.laps = laps;
+        let mut drivers = carried.drivers;
+        drivers.append(&mut self.lap_drivers);
+        self.lap_drivers = drivers;
+        self.carried_laps += count;
+        if let Some(db) = self.db.as_mut() {
+            db.laps_written += carried.written;
+            db.laps_queued += carried.written;
+        }
+        self.strat_cache.replace(None);
+    }
     pub fn add_lap(&mut self, l: Lap) {
         self.laps.push(l);
+        self.lap_drivers.push(self.driver);
+        self.strat_cache.replace(None);
+    }
+    // in a team race the drivers can use quite different amounts of fuel, laps are kept
+    // against the driver that ran them and the current driver's laps set the green rate.
+    pub fn set_driver(&mut self, driver: i64) {
+        self.driver = driver;
+        self.strat_cache.replace(None);
+    }
+    pub fn driver(&self) -> i64 {
+        self.driver
+    }
+    // the green rate from the laps run by driver, None if they haven't run any.
+    pub fn driver_green(&self, driver: i64) -> Option<Rate> {
+        let laps = self.laps_by(&self.laps, driver);
+        if laps.is_empty() {
+            return None;
+        }
+        self.recent_green(&laps)
+    }
+    // the green rate from previous sessions in the db, None if there aren't any.
+    pub fn def_green(&self) -> Option<Rate> {
+        self.def_green
+    }
+    // the green rate from the laps run in this session, ignoring any default rates. None
+    // until there's a couple of green laps.
+    pub fn live_green(&self) -> Option<Rate> {
+        let laps = self.green_rate_laps(&self.laps);
+        if laps.iter().filter(|l| self.is_green_lap(l)).count() < 2 {
+            return None;
+        }
+        self.recent_green(&laps)
+    }
+    fn laps_by(&self, laps: &[Lap], driver: i64) -> Vec<Lap> {
+        laps.iter()
+            .zip(&self.lap_drivers)
+            .filter(|(l, d)| **d == driver && self.is_green_lap(l))
+            .map(|(l, _)| *l)
+            .collect()
+    }
+    // the laps to take the green rate from. Until the current driver has run a couple of
+    // green laps, e.g. just after a driver swap, everyone's laps are used.
+    fn green_rate_laps(&self, laps: &[Lap]) -> Vec<Lap> {
+        let mine = self.laps_by(laps, self.driver);
+        if mine.len() >= 2 {
+            mine
+        } else {
+            laps.to_vec()
+        }
+    }
+    // records the time lost to a pitstop, pitstops are infrequent so these are written
+    // straight to the db.
+    pub fn add_pit_stop(&mut self, duration: TimeSpan) -> Result<(), Error> {
+        if let Some(db) = self.db.as_mut() {
+            db.save_pit_stop(duration)?;
+            self.def_pit_loss = db.avg_pit_loss(self.cfg.car_id, self.cfg.track_id);
+        }
+        Ok(())
+    }
+    // the average time lost to previous pitstops at this car/track, None if there
+    // aren't any.
+    pub fn pit_loss(&self) -> Option<TimeSpan> {
+        self.def_pit_loss
+    }
+    // the number of green & yellow laps available for the car/track, from the db when the
+    // session started plus the laps run since. Laps carried over from the previous
+    // session are already in the db.
+    pub fn lap_counts(&self) -> (i64, i64) {
+        let count = |cond: LapState| {
+            self.laps[self.carried_laps..]
+                .iter()
+                .filter(|l| (l.condition & LapState::NOT_GREEN) == cond)
+                .count() as i64
+        };
+        (
+            self.db_lap_counts.0 + count(LapState::empty()),
+            self.db_lap_counts.1 + count(LapState::YELLOW),
+        )
+    }
+    // some cars only report the fuel level in whole liters, which makes the per lap fuel
+    // used meaningless. This spots recent laps that all used a whole number of liters.
+    pub fn coarse_fuel(&self) -> bool {
+        let recent: Vec<f32> = self
+            .laps
+            .iter()
+            .rev()
+            .filter(|l| l.condition.is_green())
+            .take(5)
+            .map(|l| l.fuel_used)
+            .collect();
+        recent.len() >= 3
+            && recent
+                .iter()
+                .all(|f| *f > 0.0 && (f - f.round()).abs() < 0.01)
+    }
+    // a one line recap of the laps run, e.g. for the log at the end of a race.
+    pub fn race_summary(&self) -> String {
+        // the in & out laps of a stop are both pitted laps
+        let pitted = |i: usize| self.laps[i].condition.intersects(LapState::PITTED);
+        let stops = (0..self.laps.len())
+            .filter(|&i| pitted(i) && (i == 0 || !pitted(i - 1)))
+            .count();
+        let fuel = self.laps.iter().fold(0.0, |acc, l| acc + l.fuel_used);
+        let (c, r) = self
+            .laps
+            .iter()
+            .filter(|l| l.condition.is_green())
+            .fold((0, Rate::default()), |acc, lap| (acc.0 + 1, acc.1 + lap));
+        let green = if c > 0 {
+            format!("{:.3}L {}", r.fuel / c as f32, r.time / c)
+        } else {
+            "n/a".to_string()
+        };
+        format!(
+            "{}, {}, {:.2}L used, green lap avg {}",
+            plural(self.laps.len(), "lap"),
+            plural(stops, "stop"),
+            fuel,
+            green
+        )
     }
     pub fn save_laps(&mut self) -> Result<(), Error> {
         if let Some(db) = self.db.as_mut() {
@@ -89,18 +379,38 @@ impl History {
             Ok(())
         }
     }
+    // saves the laps not already written on a background thread, so the caller isn't held up
+    // by a slow disk. Use flush_laps to wait for them to be written. An error from an earlier
+    // background write is returned here, and those laps are queued again next time.
+    pub fn queue_laps(&mut self) -> Result<(), Error> {
+        if let Some(db) = self.db.as_mut() {
+            db.queue_laps(&self.laps)
+        } else {
+            Ok(())
+        }
+    }
+    pub fn flush_laps(&mut self) -> Result<(), Error> {
+        if let Some(db) = self.db.as_mut() {
+            db.flush()
+        } else {
+            Ok(())
+        }
+    }
     // calculates a green lap fuel/time estimate from recently completed green laps. If there are no
-    // laps available will default to data from previous sessions if available.
-    fn recent_green(&self) -> Option<Rate> {
-        let (c, r) = self
-            .laps
+    // laps available will default to the baseline or data from previous sessions if available.
+    fn recent_green(&self, laps: &[Lap]) -> Option<Rate> {
+        let def_green = self
+            .def_green
+            .or(self.baseline_green)
+            .or(self.provisional_green);
+        let (c, r) = laps
             .iter()
             .rev()
-            .filter(|&l| l.condition.is_empty())
+            .filter(|&l| self.is_green_lap(l))
             .take(5)
             .fold((0, Rate::default()), |acc, lap| (acc.0 + 1, acc.1 + lap));
-        if self.def_green.is_some() && c < 2 {
-            self.def_green
+        if def_green.is_some() && c < 2 {
+            def_green
         } else if c >= 1 {
             Some(Rate {
                 fuel: r.fuel / (c as f32),
@@ -110,15 +420,18 @@ impl History {
             None
         }
     }
+    fn is_green_lap(&self, l: &Lap) -> bool {
+        l.condition.is_green() && !(self.exclude_blue && l.condition.intersects(LapState::BLUE))
+    }
     // calculates a yellow flag lap fuel/time estimate from prior yellow laps. If there are no
-    // available laps will default to data from previous sessions if available.
-    fn recent_yellow(&self) -> Option<Rate> {
+    // available laps will default to the baseline or data from previous sessions if available.
+    fn recent_yellow(&self, laps: &[Lap]) -> Option<Rate> {
         // we want to ignore the first lap of the set of yellow laps, as its a partial yellow lap
         // and not indicitive of a "normal" yellow lap.
         let mut yellow_start = false;
         let mut total = Rate::default();
         let mut count = 0;
-        for lap in &self.laps {
+        for lap in laps {
             if lap.condition.intersects(LapState::YELLOW) {
                 if !yellow_start {
                     yellow_start = true;
@@ -131,7 +444,7 @@ impl History {
             }
         }
         if count == 0 {
-            self.def_yellow
+            self.def_yellow.or(self.baseline_yellow)
         } else {
             Some(Rate {
                 fuel: total.fuel / (count as f32),
@@ -141,54 +454,178 @@ impl History {
     }
 
     pub fn strat(&self, fuel_left: f32, adj: &Adjustments, ends: EndsWith) -> Option<Strategy> {
-        let green = self.recent_green()?;
-        let yellow = self.recent_yellow().unwrap_or_else(|| Rate {
+        let r = self.request(&self.laps, fuel_left, adj, ends)?;
+        // small changes in fuel_left during a lap don't change the strategy, so reuse
+        // the last one if the request is otherwise the same.
+        let key = StratRequest {
+            fuel_left: (fuel_left * 10.0).round() / 10.0,
+            ..r.clone()
+        };
+        if let Some((k, s)) = self.strat_cache.borrow().as_ref() {
+            if *k == key {
+                return Some(s.clone());
+            }
+        }
+        let s = r.compute().ok();
+        self.strat_cache
+            .replace(s.as_ref().map(|s| (key, s.clone())));
+        s
+    }
+
+    // how many laps the fuel left will last, taking into account any remaining caution laps.
+    pub fn laps_to_empty(&self, fuel_left: f32) -> Option<f32> {
+        let r = self.request(
+            &self.laps,
+            fuel_left,
+            &Adjustments::none(),
+            EndsWith::Laps(0),
+        )?;
+        Some(strat::laps_to_empty(
+            fuel_left,
+            r.yellow_togo,
+            r.yellow,
+            r.green,
+        ))
+    }
+
+    // how many laps we'd gain by pitting now under the caution rather than staying out.
+    pub fn yellow_pit_gain(
+        &self,
+        fuel_left: f32,
+        adj: &Adjustments,
+        ends: EndsWith,
+        pit_loss: TimeSpan,
+    ) -> Option<f32> {
+        self.request(&self.laps, fuel_left, adj, ends)?
+            .yellow_pit_gain(pit_loss)
+    }
+
+    // replays the session, calculating the strategy as it would of been after the first n laps
+    // using the fuel left at the end of lap n. ends is the race remaining at that point.
+    pub fn strat_at_lap(&self, n: usize, ends: EndsWith) -> Option<Strategy> {
+        let laps = &self.laps[..n.min(self.laps.len())];
+        let fuel_left = laps.last().map_or(self.cfg.fuel_tank_size, |l| l.fuel_left);
+        self.request(laps, fuel_left, &Adjustments::none(), ends)?
+            .compute()
+            .ok()
+    }
+
+    // builds the strategy request based on the supplied laps from this session.
+    fn request(
+        &self,
+        laps: &[Lap],
+        fuel_left: f32,
+        adj: &Adjustments,
+        ends: EndsWith,
+    ) -> Option<StratRequest> {
+        let mut green = self.recent_green(&self.green_rate_laps(laps))?;
+        let yellow = self.recent_yellow(laps).unwrap_or_else(|| Rate {
             fuel: green.fuel / 3.0,
             time: green.time * 4,
         });
-        let yellow_laps = self
-            .laps
+        // In a timed race the leader finishing ends the race for everyone, if we're
+        // behind the leader we'll run out of time before the clock does.
+        let ends = match adj.laps_behind_leader {
+            Some(l) if l > 0.0 => ends.less_time(green.time * l),
+            _ => ends,
+        };
+        // pushing harder than the laps so far, e.g. racing for position, uses more fuel.
+        if let Some(m) = adj.fuel_use_margin {
+            green.fuel *= 1.0 + m.max(0.0);
+        }
+        let yellow_laps = laps
             .iter()
             .rev()
             .take_while(|lap| lap.condition.intersects(LapState::YELLOW))
             .count() as isize;
-        let r = StratRequest {
+        Some(StratRequest {
             fuel_left,
             tank_size: self.cfg.fuel_tank_size,
             max_fuel_save: adj.max_fuel_save.unwrap_or(self.cfg.max_fuel_save),
             min_fuel: adj.min_fuel.unwrap_or(self.cfg.min_fuel),
             // a yellow flag is usually at least 3 laps.
             // TODO, can we detect the 2/1 togo state from iRacing?
-            yellow_togo: if yellow_laps > 0 {
+            yellow_togo: adj.yellow_togo.unwrap_or(if yellow_laps > 0 {
                 cmp::max(0, 3 - yellow_laps) as i32
             } else {
                 0
-            },
+            }),
+            pre_green_laps: adj.pre_green_laps.unwrap_or(0),
+            // pace laps are run at about caution speed
+            pre_green_rate: yellow,
+            cooldown_laps: adj.cooldown_laps.unwrap_or(0),
+            finish_reserve: adj.finish_reserve.unwrap_or(0.0),
             ends,
             green,
             yellow,
-        };
-        r.compute()
+        })
+    }
+}
+#[derive(Debug)]
+pub enum DbError {
+    IOError(io::Error),
+    SqlError(Error),
+}
+impl From<io::Error> for DbError {
+    fn from(e: io::Error) -> Self {
+        DbError::IOError(e)
+    }
+}
+impl From<Error> for DbError {
+    fn from(e: Error) -> Self {
+        DbError::SqlError(e)
     }
 }
+impl fmt::Display for DbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DbError::IOError(e) => write!(f, "{}", e),
+            DbError::SqlError(e) => write!(f, "database error: {}", e),
+        }
+    }
+}
+impl error::Error for DbError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            DbError::IOError(e) => Some(e),
+            DbError::SqlError(e) => Some(e),
+        }
+    }
+}
+
 pub struct Db {
     con_mgr: SqliteConnectionManager,
     con: Connection,
+    // laps the db has confirmed are written, and those handed to the writer
     laps_written: usize,
+    laps_queued: usize,
     id: Option<i64>,
+    writer: Option<LapWriter>, // started by the first queue_laps
 }
 
 impl Db {
-    pub fn new(f: &Path) -> Result<Db, impl error::Error> {
+    pub fn new(f: &Path) -> Result<Db, DbError> {
+        // sqlite's error for a missing folder is just "unable to open database file"
+        if let Some(dir) = f.parent() {
+            if !dir.as_os_str().is_empty() && !dir.exists() {
+                return Err(DbError::IOError(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("the folder {} doesn't exist", dir.display()),
+                )));
+            }
+        }
         let c = r2d2_sqlite::SqliteConnectionManager::file(f);
         let con = c.connect();
         let x = con.map(|con| Db {
             con_mgr: c,
             con,
             laps_written: 0,
+            laps_queued: 0,
             id: None,
+            writer: None,
         })?;
-        x.init_schema().map(|()| x)
+        x.init_schema()?;
+        Ok(x)
     }
 
     fn init_schema(&self) -> Result<(), Error> {
@@ -206,6 +643,8 @@ impl Db {
         let _ = self.con.execute(s, []);
         let s = "ALTER TABLE Session ADD COLUMN min_fuel float DEFAULT 0.2";
         let _ = self.con.execute(s, []);
+        let s = "ALTER TABLE Session ADD COLUMN tag text DEFAULT ''";
+        let _ = self.con.execute(s, []);
 
         let s = "CREATE TABLE IF NOT EXISTS Lap(
                                 id              integer primary key,
@@ -217,6 +656,13 @@ impl Db {
                                 condition       int,
                                 condition_str   text)";
         self.con.execute(s, [])?;
+
+        let s = "CREATE TABLE IF NOT EXISTS PitStop(
+                                id              integer primary key,
+                                session         integer references session(id),
+                                time            text,
+                                duration        float)";
+        self.con.execute(s, [])?;
         Ok(())
     }
     fn insert_session(&mut self, c: &RaceSession) -> Result<(), Error> {
@@ -235,38 +681,108 @@ impl Db {
         self.id = Some(id);
         Ok(())
     }
+    pub fn set_session_tag(&mut self, tag: &str) -> Result<(), Error> {
+        self.con.execute(
+            "UPDATE Session SET tag=? WHERE id=?",
+            params![tag, self.id.unwrap()],
+        )?;
+        Ok(())
+    }
     pub fn save_laps(&mut self, laps: &[Lap]) -> Result<(), Error> {
-        let tx = self.con.transaction()?;
+        // anything already queued goes first, any that failed are written again here.
+        if let Err(e) = self.flush() {
+            warn!("failed to save queued laps, retrying {:?}", e);
+        }
+        insert_laps(&mut self.con, self.id.unwrap(), &laps[self.laps_written..])?;
+        self.laps_written = laps.len();
+        self.laps_queued = laps.len();
+        Ok(())
+    }
+    // hands the laps not already queued to the background writer.
+    pub fn queue_laps(&mut self, laps: &[Lap]) -> Result<(), Error> {
+        if let Some(w) = self.writer.as_ref() {
+            let results = w.results();
+            self.confirm(results)?;
+        }
+        if laps.len() <= self.laps_queued {
+            return Ok(());
+        }
+        if self.writer.is_none() {
+            self.writer = Some(LapWriter::new(self.con_mgr.connect()?));
+        }
+        let batch = laps[self.laps_queued..].to_vec();
+        if self
+            .writer
+            .as_ref()
+            .unwrap()
+            .send(self.id.unwrap(), batch)
+            .is_err()
         {
-            let mut stmt = tx.prepare(
-                "INSERT INTO Lap(session,time,fuel_used,fuel_left,lap_time,condition,condition_str)
-                VALUES (?,datetime('now'),?,?,?,?,?)",
-            )?;
-            for l in laps[self.laps_written..].iter() {
-                stmt.insert(params![
-                    self.id.unwrap(),
-                    l.fuel_used,
-                    l.fuel_left,
-                    l.time.as_secs_f64(),
-                    l.condition.bits(),
-                    format!("{:?}", l.condition),
-                ])?;
+            // the writer's gone, e.g. it panicked, write them here instead.
+            if let Err(e) = self.flush() {
+                warn!("failed to save queued laps, retrying {:?}", e);
+            }
+            insert_laps(&mut self.con, self.id.unwrap(), &laps[self.laps_written..])?;
+            self.laps_written = laps.len();
+        }
+        self.laps_queued = laps.len();
+        Ok(())
+    }
+    // waits for all the queued laps to be written.
+    pub fn flush(&mut self) -> Result<(), Error> {
+        match self.writer.take() {
+            Some(w) => {
+                let results = w.finish();
+                self.confirm(results)
+            }
+            None => Ok(()),
+        }
+    }
+    // counts the laps the writer has written. After a failed write the writer has stopped,
+    // so the laps from there on will be queued again.
+    fn confirm(&mut self, results: Vec<Result<usize, Error>>) -> Result<(), Error> {
+        for r in results {
+            match r {
+                Ok(n) => self.laps_written += n,
+                Err(e) => {
+                    self.writer = None;
+                    self.laps_queued = self.laps_written;
+                    return Err(e);
+                }
             }
         }
-        tx.commit()?;
-        self.laps_written = laps.len();
         Ok(())
     }
-    /// returns the most recent session with enough green flag laps for each car/track/layout combo we know about
-    pub fn sessions(&self) -> Result<Vec<RaceSession>, impl error::Error> {
+    pub fn save_pit_stop(&mut self, duration: TimeSpan) -> Result<(), Error> {
+        self.con.execute(
+            "INSERT INTO PitStop(session,time,duration) VALUES (?,datetime('now'),?)",
+            params![self.id.unwrap(), duration.as_secs_f64()],
+        )?;
+        Ok(())
+    }
+    /// the average time lost to the pitstops recorded for the car/track.
+    pub fn avg_pit_loss(&self, car_id: i64, track_id: i64) -> Option<TimeSpan> {
+        let q = "select avg(p.duration) from pitstop p inner join session s on p.session=s.id
+                    where s.car_id=? and s.track_id=?";
+        self.con
+            .query_row(q, params![car_id, track_id], |row| {
+                row.get::<_, Option<f64>>(0)
+            })
+            .ok()
+            .flatten()
+            .map(TimeSpan::from_secs_f64)
+    }
+    /// returns the most recent session for each car/track that has at least min_laps
+    /// green laps, newest first.
+    pub fn sessions(&self, min_laps: u32) -> Result<Vec<RaceSession>, DbError> {
         let q = "select * from session where id in (
             select max(s.id) from session s inner join lap l on s.id = l.session
-            where l.condition = 0
+            where (l.condition & ?) = 0
             group by s.car_id, s.track_id
-            having count(l.id) > 3) order by id desc
+            having count(l.id) >= ?) order by id desc
         ";
         let mut stmt = self.con.prepare(q)?;
-        let rows = stmt.query_map([], |row| {
+        let rows = stmt.query_map(params![LapState::NOT_GREEN.bits(), min_laps], |row| {
             Ok(RaceSession {
                 fuel_tank_size: row.get("tank_size")?,
                 max_fuel_save: row.get("max_fuel_save")?,
@@ -278,40 +794,208 @@ impl Db {
                 car: row.get("car")?,
             })
         })?;
-        rows.collect()
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+    /// the number of laps for the car/track whose condition, ignoring anything that doesn't
+    /// affect green vs yellow, is condition. i.e. LapState::empty() counts the green laps.
+    pub fn lap_count(&self, car_id: i64, track_id: i64, condition: LapState) -> i64 {
+        let q = "select count(*) from lap l inner join session s on l.session=s.id
+                    where s.car_id=? and s.track_id=? and (l.condition & ?)=?";
+        self.con
+            .query_row(
+                q,
+                params![
+                    car_id,
+                    track_id,
+                    LapState::NOT_GREEN.bits(),
+                    condition.bits()
+                ],
+                |row| row.get(0),
+            )
+            .unwrap_or(0)
     }
-    pub fn db_green_laps(&self, car_id: i64, track_id: i64) -> Option<Rate> {
-        self.db_laps(car_id, track_id, LapState::empty().bits())
+    /// green laps are selected using the same rules as LapState::is_green. If session_limit
+    /// is set only laps from that many of the most recent sessions are considered, and
+    /// if tags is set only laps from sessions that match it. If exclude_blue is set laps
+    /// where the blue flag was shown are left out.
+    pub fn db_green_laps(
+        &self,
+        car_id: i64,
+        track_id: i64,
+        exclude_blue: bool,
+        session_limit: Option<u32>,
+        tags: Option<&TagFilter>,
+    ) -> Option<Rate> {
+        let blue = if exclude_blue {
+            LapState::BLUE
+        } else {
+            LapState::empty()
+        };
+        self.db_laps(
+            car_id,
+            track_id,
+            LapState::NOT_GREEN | blue,
+            LapState::empty(),
+            session_limit,
+            tags,
+        )
     }
-    pub fn db_yellow_laps(&self, car_id: i64, track_id: i64) -> Option<Rate> {
-        self.db_laps(car_id, track_id, LapState::YELLOW.bits())
+    /// yellow laps are ones under caution that weren't also in/out or pace laps.
+    pub fn db_yellow_laps(
+        &self,
+        car_id: i64,
+        track_id: i64,
+        session_limit: Option<u32>,
+        tags: Option<&TagFilter>,
+    ) -> Option<Rate> {
+        self.db_laps(
+            car_id,
+            track_id,
+            LapState::NOT_GREEN,
+            LapState::YELLOW,
+            session_limit,
+            tags,
+        )
     }
-    fn db_laps(&self, car_id: i64, track_id: i64, cond: i32) -> Option<Rate> {
+    // averages the recent laps where the lap's condition masked with mask equals cond.
+    fn db_laps(
+        &self,
+        car_id: i64,
+        track_id: i64,
+        mask: LapState,
+        cond: LapState,
+        session_limit: Option<u32>,
+        tags: Option<&TagFilter>,
+    ) -> Option<Rate> {
         let q_avg = "select avg(fuel_used) as f, avg(lap_time) as t from  (
                             select l.fuel_used,l.lap_time from lap l inner join session s on l.session=s.id 
-                            where s.car_id=? and s.track_id=? and l.condition=? order by l.id desc limit 5)";
-        let x = self
-            .con
-            .query_row(q_avg, params![car_id, track_id, cond], |row| {
+                            where s.car_id=? and s.track_id=? and (l.condition & ?)=? 
+                            and (? is null or (s.tag=?)=?)
+                            and s.id in (select id from session r where r.car_id=s.car_id and r.track_id=s.track_id 
+                                and exists (select 1 from lap where lap.session=r.id) order by r.id desc limit ?)
+                            order by l.id desc limit 5)";
+        // a negative limit in sqlite is no limit
+        let limit = session_limit.map_or(-1, i64::from);
+        let (tag, include) = match tags {
+            None => (None, true),
+            Some(TagFilter::Only(t)) => (Some(t), true),
+            Some(TagFilter::Exclude(t)) => (Some(t), false),
+        };
+        let x = self.con.query_row(
+            q_avg,
+            params![
+                car_id,
+                track_id,
+                mask.bits(),
+                cond.bits(),
+                tag,
+                tag,
+                include,
+                limit
+            ],
+            |row| {
                 Ok(Rate {
                     fuel: row.get("f")?,
                     time: TimeSpan::from_secs_f64(row.get("t")?),
                 })
-            });
+            },
+        );
         x.ok()
     }
 }
 
+fn insert_laps(con: &mut Connection, session: i64, laps: &[Lap]) -> Result<(), Error> {
+    let tx = con.transaction()?;
+    {
+        let mut stmt = tx.prepare(
+            "INSERT INTO Lap(session,time,fuel_used,fuel_left,lap_time,condition,condition_str)
+            VALUES (?,datetime('now'),?,?,?,?,?)",
+        )?;
+        for l in laps.iter() {
+            stmt.insert(params![
+                session,
+                l.fuel_used,
+                l.fuel_left,
+                l.time.as_secs_f64(),
+                l.condition.bits(),
+                format!("{:?}", l.condition),
+            ])?;
+        }
+    }
+    tx.commit()
+}
+
+// e.g. "1 lap" or "2 laps".
+fn plural(n: usize, what: &str) -> String {
+    match n {
+        1 => format!("1 {}", what),
+        _ => format!("{} {}s", n, what),
+    }
+}
+
+// writes batches of laps to the db on its own connection and thread, reporting back
+// how each batch went. It stops at the first failed batch so that the written laps are
+// always the oldest ones. Dropping it waits for everything already sent to be written.
+struct LapWriter {
+    tx: Option<mpsc::Sender<(i64, Vec<Lap>)>>,
+    done: mpsc::Receiver<Result<usize, Error>>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl LapWriter {
+    fn new(mut con: Connection) -> LapWriter {
+        let (tx, rx) = mpsc::channel::<(i64, Vec<Lap>)>();
+        let (done_tx, done) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            for (session, laps) in rx {
+                let r = insert_laps(&mut con, session, &laps).map(|_| laps.len());
+                let failed = r.is_err();
+                if done_tx.send(r).is_err() || failed {
+                    break;
+                }
+            }
+        });
+        LapWriter {
+            tx: Some(tx),
+            done,
+            thread: Some(thread),
+        }
+    }
+    fn send(&self, session: i64, laps: Vec<Lap>) -> Result<(), mpsc::SendError<(i64, Vec<Lap>)>> {
+        self.tx.as_ref().unwrap().send((session, laps))
+    }
+    // the outcome of the batches written since the last call.
+    fn results(&self) -> Vec<Result<usize, Error>> {
+        self.done.try_iter().collect()
+    }
+    // waits for the queued batches to be written and returns how they went.
+    fn finish(mut self) -> Vec<Result<usize, Error>> {
+        self.stop();
+        self.results()
+    }
+    fn stop(&mut self) {
+        // closing the channel ends the thread once it's written what's queued
+        self.tx = None;
+        if let Some(t) = self.thread.take() {
+            let _ = t.join();
+        }
+    }
+}
+
+impl Drop for LapWriter {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::super::strat::Pitstop;
     use super::*;
 
-    #[test]
-    fn no_laps() {
-        // Note in the future a previously calc/saved green rate would be loaded
-        // and this would generate a starting strategy
-        let cfg = RaceSession {
+    // a 10L tank car at a test track, use struct update syntax for anything else.
+    pub(crate) fn cfg() -> RaceSession {
+        RaceSession {
             fuel_tank_size: 10.0,
             max_fuel_save: 0.0,
             min_fuel: 0.0,
@@ -320,25 +1004,230 @@ mod tests {
             layout_name: "Oval".to_string(),
             car_id: 1,
             car: "PM 18".to_string(),
-        };
-        let calc = History::new(cfg, None).unwrap();
+        }
+    }
+
+    #[test]
+    fn no_laps() {
+        // Note in the future a previously calc/saved green rate would be loaded
+        // and this would generate a starting strategy
+        let cfg = cfg();
+        let calc = History::new(cfg, None);
         let strat = calc.strat(10.0, &Adjustments::none(), EndsWith::Laps(50));
         assert!(strat.is_none());
     }
 
     #[test]
-    fn one_lap() {
-        let cfg = RaceSession {
-            fuel_tank_size: 10.0,
-            max_fuel_save: 0.0,
-            min_fuel: 0.0,
-            track_id: 1,
-            track_name: "Test".to_string(),
-            layout_name: "Oval".to_string(),
-            car_id: 1,
-            car: "PM 18".to_string(),
+    fn baseline() {
+        let cfg = cfg();
+        let mut calc = History::new(cfg, None);
+        let green = Rate {
+            fuel: 0.5,
+            time: TimeSpan::new(30, 0),
+        };
+        calc.set_baseline(green, None);
+        let strat = calc
+            .strat(10.0, &Adjustments::none(), EndsWith::Laps(50))
+            .unwrap();
+        assert_eq!(green, strat.green);
+        assert_eq!(vec![20, 20, 10], strat.laps());
+        // once there are enough laps, they take over from the baseline
+        let lap = Lap {
+            fuel_left: 9.0,
+            fuel_used: 1.0,
+            time: TimeSpan::new(30, 0),
+            condition: LapState::empty(),
+        };
+        calc.add_lap(lap);
+        calc.add_lap(lap);
+        let strat = calc
+            .strat(9.0, &Adjustments::none(), EndsWith::Laps(48))
+            .unwrap();
+        assert_eq!(1.0, strat.green.fuel);
+    }
+
+    #[test]
+    fn baseline_only_without_db_rates() {
+        let db_file = test_db_file("baseline_only_without_db_rates");
+        let mut calc = History::new(cfg(), Some(db_file.clone()));
+        for _ in 0..2 {
+            calc.add_lap(Lap {
+                fuel_left: 9.0,
+                fuel_used: 1.0,
+                time: TimeSpan::new(30, 0),
+                condition: LapState::empty(),
+            });
+        }
+        calc.save_laps().unwrap();
+        drop(calc);
+        let baseline = Rate {
+            fuel: 0.5,
+            time: TimeSpan::new(30, 0),
+        };
+        // the car/track has laps in the db, they win over the baseline
+        let mut calc = History::new(cfg(), Some(db_file.clone()));
+        calc.set_baseline(baseline, None);
+        let strat = calc
+            .strat(10.0, &Adjustments::none(), EndsWith::Laps(50))
+            .unwrap();
+        assert_eq!(1.0, strat.green.fuel);
+        drop(calc);
+        // a different car has nothing in the db, so uses the baseline
+        let other = RaceSession { car_id: 2, ..cfg() };
+        let mut calc = History::new(other, Some(db_file.clone()));
+        calc.set_baseline(baseline, None);
+        let strat = calc
+            .strat(10.0, &Adjustments::none(), EndsWith::Laps(50))
+            .unwrap();
+        assert_eq!(baseline, strat.green);
+        drop(calc);
+        let _ = std::fs::remove_file(&db_file);
+    }
+
+    #[test]
+    fn runs_without_an_unusable_db() {
+        let db_file = test_db_file("unusable_db");
+        // a Session table from something else, the session can't be added to it
+        let con = Connection::open(&db_file).unwrap();
+        con.execute(
+            "CREATE TABLE Session(id integer primary key, time text)",
+            [],
+        )
+        .unwrap();
+        drop(con);
+        let mut calc = History::new(cfg(), Some(db_file.clone()));
+        assert!(calc.db.is_none());
+        calc.add_lap(Lap {
+            fuel_left: 9.0,
+            fuel_used: 1.0,
+            time: TimeSpan::new(30, 0),
+            condition: LapState::empty(),
+        });
+        assert!(calc.save_laps().is_ok());
+        drop(calc);
+        let _ = std::fs::remove_file(&db_file);
+    }
+
+    #[test]
+    fn behind_leader() {
+        let cfg = cfg();
+        let mut calc = History::new(cfg, None);
+        calc.set_baseline(
+            Rate {
+                fuel: 0.5,
+                time: TimeSpan::new(60, 0),
+            },
+            None,
+        );
+        let ends = EndsWith::Time(TimeSpan::new(30 * 60, 0));
+        let strat = calc.strat(10.0, &Adjustments::none(), ends).unwrap();
+        // when the clock hits zero, there's one more lap to finish
+        assert_eq!(31, strat.total_laps());
+        let adj = Adjustments {
+            laps_behind_leader: Some(2.0),
+            ..Adjustments::none()
+        };
+        let strat = calc.strat(10.0, &adj, ends).unwrap();
+        assert_eq!(29, strat.total_laps());
+        assert_eq!(vec![20, 9], strat.laps());
+        // laps races are unaffected
+        let strat = calc.strat(10.0, &adj, EndsWith::Laps(30)).unwrap();
+        assert_eq!(30, strat.total_laps());
+    }
+
+    #[test]
+    fn fuel_use_margin() {
+        let cfg = cfg();
+        let mut calc = History::new(cfg, None);
+        calc.add_lap(Lap {
+            fuel_left: 9.5,
+            fuel_used: 0.5,
+            time: TimeSpan::new(30, 0),
+            condition: LapState::empty(),
+        });
+        let ends = EndsWith::Laps(40);
+        let strat = calc.strat(10.0, &Adjustments::none(), ends).unwrap();
+        assert_eq!(1, strat.stops.len());
+        assert_eq!(0.5, strat.green.fuel);
+        // using 10% more fuel than the average no longer makes it on one stop
+        let adj = Adjustments {
+            fuel_use_margin: Some(0.1),
+            ..Adjustments::none()
+        };
+        let strat = calc.strat(10.0, &adj, ends).unwrap();
+        assert_eq!(2, strat.stops.len());
+        assert_eq!(0.55, strat.green.fuel);
+    }
+
+    #[test]
+    fn strat_cache() {
+        let cfg = cfg();
+        let mut calc = History::new(cfg, None);
+        let lap = Lap {
+            fuel_left: 9.5,
+            fuel_used: 0.5,
+            time: TimeSpan::new(30, 0),
+            condition: LapState::empty(),
         };
-        let mut calc = History::new(cfg, None).unwrap();
+        calc.add_lap(lap);
+        let adj = Adjustments::none();
+        let ends = EndsWith::Laps(49);
+        let first = calc.strat(9.51, &adj, ends).unwrap();
+        let cached_fuel = |c: &History| c.strat_cache.borrow().as_ref().map(|(k, _)| k.fuel_left);
+        assert_eq!(Some(9.5), cached_fuel(&calc));
+        // a near identical request reuses the cached strategy
+        let second = calc.strat(9.53, &adj, ends).unwrap();
+        assert_eq!(first, second);
+        assert_eq!(Some(9.5), cached_fuel(&calc));
+        // a different request recomputes
+        let third = calc.strat(5.0, &adj, ends).unwrap();
+        assert_eq!(Some(5.0), cached_fuel(&calc));
+        assert_ne!(first, third);
+        assert_eq!(vec![10, 20, 19], third.laps());
+        let fourth = calc.strat(5.0, &adj, EndsWith::Laps(40)).unwrap();
+        assert_eq!(vec![10, 20, 10], fourth.laps());
+        // a new lap invalidates the cache
+        calc.add_lap(lap);
+        assert_eq!(None, cached_fuel(&calc));
+    }
+
+    #[test]
+    fn strat_at_lap() {
+        let cfg = cfg();
+        let mut calc = History::new(cfg, None);
+        let mut fuel_left = 10.0;
+        for fuel_used in [1.0, 0.5, 0.5, 0.5, 0.5] {
+            fuel_left -= fuel_used;
+            calc.add_lap(Lap {
+                fuel_left,
+                fuel_used,
+                time: TimeSpan::new(30, 0),
+                condition: LapState::empty(),
+            });
+        }
+        assert!(calc.strat_at_lap(0, EndsWith::Laps(30)).is_none());
+        // after 3 laps the rate is still skewed by the heavy first lap
+        let at3 = calc.strat_at_lap(3, EndsWith::Laps(27)).unwrap();
+        assert_eq!(2.0 / 3.0, at3.green.fuel);
+        assert_eq!(vec![12, 15], at3.laps());
+        // by lap 5 its converging on the real rate
+        let at5 = calc.strat_at_lap(5, EndsWith::Laps(25)).unwrap();
+        assert_eq!(0.6, at5.green.fuel);
+        assert_eq!(vec![11, 14], at5.laps());
+        assert_ne!(at3, at5);
+        // past the end of the session is the same as the whole session
+        let all = calc.strat_at_lap(10, EndsWith::Laps(25)).unwrap();
+        assert_eq!(at5, all);
+        assert_eq!(
+            Some(at5),
+            calc.strat(7.0, &Adjustments::none(), EndsWith::Laps(25))
+        );
+    }
+
+    #[test]
+    fn one_lap() {
+        let cfg = cfg();
+        let mut calc = History::new(cfg, None);
         calc.add_lap(Lap {
             fuel_left: 9.5,
             fuel_used: 0.5,
@@ -354,17 +1243,8 @@ mod tests {
 
     #[test]
     fn five_laps() {
-        let cfg = RaceSession {
-            fuel_tank_size: 10.0,
-            max_fuel_save: 0.0,
-            min_fuel: 0.0,
-            track_id: 1,
-            track_name: "Test".to_string(),
-            layout_name: "Oval".to_string(),
-            car_id: 1,
-            car: "PM 18".to_string(),
-        };
-        let mut calc = History::new(cfg, None).unwrap();
+        let cfg = cfg();
+        let mut calc = History::new(cfg, None);
         let mut lap = Lap {
             fuel_left: 9.5,
             fuel_used: 0.5,
@@ -392,19 +1272,170 @@ mod tests {
         assert_eq!(vec![Pitstop::new(5, 15), Pitstop::new(25, 35)], strat.stops);
     }
 
+    fn test_db_file(name: &str) -> PathBuf {
+        let f = std::env::temp_dir().join(format!("naf_calc_{}_{}.db", name, std::process::id()));
+        let _ = std::fs::remove_file(&f);
+        f
+    }
+
     #[test]
-    fn yellow() {
-        let cfg = RaceSession {
-            fuel_tank_size: 10.0,
-            max_fuel_save: 0.0,
-            min_fuel: 0.0,
-            track_id: 1,
-            track_name: "Test".to_string(),
-            layout_name: "Oval".to_string(),
-            car_id: 1,
-            car: "PM 18".to_string(),
+    fn green_laps_policy() {
+        let cfg = cfg();
+        let db_file = test_db_file("green_laps_policy");
+        let mut calc = History::new(cfg, Some(db_file.clone()));
+        let lap = |fuel_used: f32, condition: LapState| Lap {
+            fuel_left: 5.0,
+            fuel_used,
+            time: TimeSpan::new(30, 0),
+            condition,
         };
-        let mut calc = History::new(cfg, None).unwrap();
+        // these count as green laps
+        calc.add_lap(lap(1.0, LapState::empty()));
+        calc.add_lap(lap(2.0, LapState::LOCAL_YELLOW));
+        // and these don't
+        calc.add_lap(lap(5.0, LapState::YELLOW));
+        calc.add_lap(lap(5.0, LapState::PITTED));
+        calc.add_lap(lap(5.0, LapState::PACE_LAP));
+        calc.add_lap(lap(5.0, LapState::LOCAL_YELLOW | LapState::PITTED));
+        assert_eq!(1.5, calc.recent_green(&calc.laps).unwrap().fuel);
+        // the db should pick the same set of laps
+        calc.save_laps().unwrap();
+        let db = Db::new(&db_file).unwrap();
+        assert_eq!(1.5, db.db_green_laps(1, 1, false, None, None).unwrap().fuel);
+        assert_eq!(5.0, db.db_yellow_laps(1, 1, None, None).unwrap().fuel);
+        assert!(db.db_green_laps(2, 1, false, None, None).is_none());
+        drop(db);
+        drop(calc);
+        let _ = std::fs::remove_file(&db_file);
+    }
+
+    #[test]
+    fn lap_count() {
+        let cfg = cfg();
+        let db_file = test_db_file("lap_count");
+        let mut calc = History::new(cfg.clone(), Some(db_file.clone()));
+        let lap = |condition: LapState| Lap {
+            fuel_left: 5.0,
+            fuel_used: 1.0,
+            time: TimeSpan::new(30, 0),
+            condition,
+        };
+        assert_eq!((0, 0), calc.lap_counts());
+        calc.add_lap(lap(LapState::empty()));
+        calc.add_lap(lap(LapState::LOCAL_YELLOW));
+        calc.add_lap(lap(LapState::YELLOW));
+        calc.add_lap(lap(LapState::YELLOW | LapState::PITTED));
+        calc.add_lap(lap(LapState::PACE_LAP));
+        assert_eq!((2, 1), calc.lap_counts());
+        calc.save_laps().unwrap();
+        drop(calc);
+
+        let db = Db::new(&db_file).unwrap();
+        assert_eq!(2, db.lap_count(1, 1, LapState::empty()));
+        assert_eq!(1, db.lap_count(1, 1, LapState::YELLOW));
+        assert_eq!(0, db.lap_count(2, 1, LapState::empty()));
+        drop(db);
+        // a later session starts with the counts from the db
+        let mut calc = History::new(cfg, Some(db_file.clone()));
+        calc.add_lap(lap(LapState::empty()));
+        assert_eq!((3, 1), calc.lap_counts());
+        drop(calc);
+        let _ = std::fs::remove_file(&db_file);
+    }
+
+    #[test]
+    fn db_session_limit() {
+        let cfg = cfg();
+        let db_file = test_db_file("db_session_limit");
+        // 3 sessions of 2 laps each, each session using more fuel
+        for fuel_used in [1.0, 2.0, 3.0] {
+            let mut calc = History::new(cfg.clone(), Some(db_file.clone()));
+            for _ in 0..2 {
+                calc.add_lap(Lap {
+                    fuel_left: 5.0,
+                    fuel_used,
+                    time: TimeSpan::new(30, 0),
+                    condition: LapState::empty(),
+                });
+            }
+            calc.save_laps().unwrap();
+        }
+        let db = Db::new(&db_file).unwrap();
+        // by default its the last 5 laps regardless of session
+        assert_eq!(2.2, db.db_green_laps(1, 1, false, None, None).unwrap().fuel);
+        assert_eq!(
+            3.0,
+            db.db_green_laps(1, 1, false, Some(1), None).unwrap().fuel
+        );
+        assert_eq!(
+            2.5,
+            db.db_green_laps(1, 1, false, Some(2), None).unwrap().fuel
+        );
+        assert_eq!(
+            2.2,
+            db.db_green_laps(1, 1, false, Some(10), None).unwrap().fuel
+        );
+
+        // a new session with no laps doesn't count towards the limit
+        let mut calc = History::new(cfg, Some(db_file.clone()));
+        calc.set_db_session_limit(Some(1));
+        assert_eq!(3.0, calc.recent_green(&calc.laps).unwrap().fuel);
+        drop(db);
+        drop(calc);
+        let _ = std::fs::remove_file(&db_file);
+    }
+
+    #[test]
+    fn tagged_sessions() {
+        let cfg = cfg();
+        let db_file = test_db_file("tagged_sessions");
+        // a tagged session using 1L a lap, and an untagged one using 2L a lap
+        for (fuel_used, tag) in [(1.0, "hosted test"), (2.0, "")] {
+            let mut calc = History::new(cfg.clone(), Some(db_file.clone()));
+            if !tag.is_empty() {
+                calc.set_session_tag(tag).unwrap();
+            }
+            for _ in 0..2 {
+                calc.add_lap(Lap {
+                    fuel_left: 5.0,
+                    fuel_used,
+                    time: TimeSpan::new(30, 0),
+                    condition: LapState::empty(),
+                });
+            }
+            calc.save_laps().unwrap();
+        }
+        let db = Db::new(&db_file).unwrap();
+        let only = TagFilter::Only("hosted test".to_string());
+        let exclude = TagFilter::Exclude("hosted test".to_string());
+        assert_eq!(1.5, db.db_green_laps(1, 1, false, None, None).unwrap().fuel);
+        assert_eq!(
+            1.0,
+            db.db_green_laps(1, 1, false, None, Some(&only))
+                .unwrap()
+                .fuel
+        );
+        assert_eq!(
+            2.0,
+            db.db_green_laps(1, 1, false, None, Some(&exclude))
+                .unwrap()
+                .fuel
+        );
+        let other = TagFilter::Only("league race".to_string());
+        assert!(db.db_green_laps(1, 1, false, None, Some(&other)).is_none());
+
+        let mut calc = History::new(cfg, Some(db_file.clone()));
+        calc.set_tag_filter(Some(exclude));
+        assert_eq!(2.0, calc.recent_green(&calc.laps).unwrap().fuel);
+        drop(db);
+        drop(calc);
+        let _ = std::fs::remove_file(&db_file);
+    }
+
+    #[test]
+    fn yellow() {
+        let cfg = cfg();
+        let mut calc = History::new(cfg, None);
         let mut lap = Lap {
             fuel_left: 9.0,
             fuel_used: 1.0,
@@ -440,4 +1471,401 @@ mod tests {
             .unwrap();
         assert_eq!(vec![5, 10, 10, 10, 9], strat.laps());
     }
+    #[test]
+    fn sessions_min_laps() {
+        let cfg = |car_id: i64| RaceSession {
+            car_id,
+            car: format!("Car {}", car_id),
+            ..cfg()
+        };
+        let db_file = test_db_file("sessions_min_laps");
+        // car 1 has a single lap, car 2 has 2 laps
+        for (car_id, laps) in [(1, 1), (2, 2)] {
+            let mut calc = History::new(cfg(car_id), Some(db_file.clone()));
+            for _ in 0..laps {
+                calc.add_lap(Lap {
+                    fuel_left: 5.0,
+                    fuel_used: 1.0,
+                    time: TimeSpan::new(30, 0),
+                    condition: LapState::empty(),
+                });
+            }
+            calc.save_laps().unwrap();
+        }
+        let db = Db::new(&db_file).unwrap();
+        let cars = |min_laps| -> Vec<i64> {
+            db.sessions(min_laps)
+                .unwrap()
+                .iter()
+                .map(|s| s.car_id)
+                .collect()
+        };
+        assert_eq!(vec![2], cars(2));
+        assert_eq!(vec![2, 1], cars(1));
+        assert!(cars(3).is_empty());
+        drop(db);
+        let _ = std::fs::remove_file(&db_file);
+    }
+    #[test]
+    fn avg_pit_loss() {
+        let cfg = cfg();
+        let db_file = test_db_file("avg_pit_loss");
+        let mut calc = History::new(cfg.clone(), Some(db_file.clone()));
+        assert_eq!(None, calc.pit_loss());
+        calc.add_pit_stop(TimeSpan::new(30, 0)).unwrap();
+        assert_eq!(Some(TimeSpan::new(30, 0)), calc.pit_loss());
+        calc.add_pit_stop(TimeSpan::new(40, 0)).unwrap();
+        assert_eq!(Some(TimeSpan::new(35, 0)), calc.pit_loss());
+        drop(calc);
+        // a later session picks them up from the db
+        let calc = History::new(cfg, Some(db_file.clone()));
+        assert_eq!(Some(TimeSpan::new(35, 0)), calc.pit_loss());
+        let db = Db::new(&db_file).unwrap();
+        assert_eq!(None, db.avg_pit_loss(2, 1));
+        drop(db);
+        drop(calc);
+        let _ = std::fs::remove_file(&db_file);
+    }
+    #[test]
+    fn exclude_blue_laps() {
+        let cfg = cfg();
+        let db_file = test_db_file("exclude_blue_laps");
+        let mut calc = History::new(cfg.clone(), Some(db_file.clone()));
+        let lap = |fuel_used: f32, condition: LapState| Lap {
+            fuel_left: 5.0,
+            fuel_used,
+            time: TimeSpan::new(30, 0),
+            condition,
+        };
+        calc.add_lap(lap(1.0, LapState::empty()));
+        calc.add_lap(lap(0.5, LapState::BLUE));
+        calc.add_lap(lap(1.0, LapState::empty()));
+        // by default blue flag laps count as green laps
+        assert_eq!(2.5 / 3.0, calc.recent_green(&calc.laps).unwrap().fuel);
+        calc.set_exclude_blue(true);
+        assert_eq!(1.0, calc.recent_green(&calc.laps).unwrap().fuel);
+        // and they're left out of the rates from the db too
+        calc.save_laps().unwrap();
+        let db = Db::new(&db_file).unwrap();
+        assert_eq!(
+            2.5 / 3.0,
+            db.db_green_laps(1, 1, false, None, None).unwrap().fuel
+        );
+        assert_eq!(1.0, db.db_green_laps(1, 1, true, None, None).unwrap().fuel);
+        drop(db);
+        drop(calc);
+        let mut next = History::new(cfg, Some(db_file.clone()));
+        next.set_exclude_blue(true);
+        assert_eq!(Some(1.0), next.def_green().map(|r| r.fuel));
+        drop(next);
+        let _ = std::fs::remove_file(&db_file);
+    }
+    #[test]
+    fn race_summary() {
+        let cfg = cfg();
+        let mut calc = History::new(cfg.clone(), None);
+        assert_eq!(
+            "0 laps, 0 stops, 0.00L used, green lap avg n/a",
+            calc.race_summary()
+        );
+        let lap = |fuel_used: f32, secs: u64, condition: LapState| Lap {
+            fuel_left: 5.0,
+            fuel_used,
+            time: TimeSpan::new(secs, 0),
+            condition,
+        };
+        calc.add_lap(lap(1.0, 30, LapState::empty()));
+        calc.add_lap(lap(1.5, 31, LapState::empty()));
+        calc.add_lap(lap(1.1, 45, LapState::PITTED));
+        calc.add_lap(lap(1.0, 50, LapState::PITTED));
+        calc.add_lap(lap(0.3, 60, LapState::YELLOW));
+        calc.add_lap(lap(1.0, 32, LapState::empty()));
+        assert_eq!(
+            "6 laps, 1 stop, 5.90L used, green lap avg 1.167L 00:31",
+            calc.race_summary()
+        );
+        let mut calc = History::new(cfg, None);
+        calc.add_lap(lap(1.0, 30, LapState::empty()));
+        assert_eq!(
+            "1 lap, 0 stops, 1.00L used, green lap avg 1.000L 00:30",
+            calc.race_summary()
+        );
+    }
+    #[test]
+    fn db_open_errors() {
+        let dir = std::env::temp_dir().join(format!("naf_calc_db_errors_{}", std::process::id()));
+        let _ = std::fs::create_dir_all(&dir);
+        let missing = dir.join("no_such_dir").join("laps.db");
+        assert!(matches!(
+            Db::new(&missing),
+            Err(DbError::IOError(e)) if e.kind() == std::io::ErrorKind::NotFound
+        ));
+        let junk = dir.join("junk.db");
+        std::fs::write(
+            &junk,
+            "this isn't a sqlite database, honest, it's much longer than a header",
+        )
+        .unwrap();
+        assert!(matches!(Db::new(&junk), Err(DbError::SqlError(_))));
+        // History carries on without the db
+        let cfg = cfg();
+        assert!(History::new(cfg, Some(missing)).db.is_none());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+    #[test]
+    fn coarse_fuel() {
+        let cfg = RaceSession {
+            fuel_tank_size: 20.0,
+            ..cfg()
+        };
+        let lap = |fuel_left: f32, fuel_used: f32| Lap {
+            fuel_left,
+            fuel_used,
+            time: TimeSpan::new(30, 0),
+            condition: LapState::empty(),
+        };
+        // the fuel level only changes in whole liters, so the laps use 1 or 2 liters
+        let mut calc = History::new(cfg.clone(), None);
+        let levels = [20.0, 19.0, 17.0, 16.0, 15.0, 13.0];
+        for w in levels.windows(2) {
+            calc.add_lap(lap(w[1], w[0] - w[1]));
+            if calc.laps.len() < 3 {
+                assert!(!calc.coarse_fuel());
+            }
+        }
+        assert!(calc.coarse_fuel());
+
+        let mut calc = History::new(cfg, None);
+        for used in [1.52, 1.49, 1.51, 1.0] {
+            calc.add_lap(lap(10.0, used));
+        }
+        assert!(!calc.coarse_fuel());
+    }
+    #[test]
+    fn driver_rates() {
+        let cfg = cfg();
+        let lap = |fuel_used: f32| Lap {
+            fuel_left: 5.0,
+            fuel_used,
+            time: TimeSpan::new(30, 0),
+            condition: LapState::empty(),
+        };
+        let green = |c: &History| {
+            c.strat(5.0, &Adjustments::none(), EndsWith::Laps(20))
+                .unwrap()
+                .green
+                .fuel
+        };
+        let mut calc = History::new(cfg, None);
+        calc.set_driver(123);
+        for _ in 0..3 {
+            calc.add_lap(lap(1.0));
+        }
+        assert_eq!(1.0, green(&calc));
+        // after the swap the first driver's laps are used until the new driver has some of their own
+        calc.set_driver(456);
+        calc.add_lap(lap(2.0));
+        assert_eq!(1.25, green(&calc));
+        calc.add_lap(lap(2.0));
+        assert_eq!(2.0, green(&calc));
+        assert_eq!(Some(1.0), calc.driver_green(123).map(|r| r.fuel));
+        assert_eq!(Some(2.0), calc.driver_green(456).map(|r| r.fuel));
+        assert_eq!(None, calc.driver_green(789));
+        // and swapping back goes back to the first driver's rate
+        calc.set_driver(123);
+        assert_eq!(1.0, green(&calc));
+        calc.add_lap(lap(1.2));
+        assert!((green(&calc) - 1.05).abs() < 0.0001);
+    }
+
+    #[test]
+    fn carry_over_session() {
+        let cfg = cfg();
+        let lap = |fuel_used: f32| Lap {
+            fuel_left: 5.0,
+            fuel_used,
+            time: TimeSpan::new(30, 0),
+            condition: LapState::empty(),
+        };
+        let db_file = test_db_file("carry_over_session");
+        // practice
+        let mut practice = History::new(cfg.clone(), Some(db_file.clone()));
+        practice.set_driver(123);
+        practice.add_lap(lap(1.0));
+        practice.add_lap(lap(1.2));
+        practice.save_laps().unwrap();
+        let green = practice.recent_green(&practice.laps).unwrap();
+        let laps = practice.carry();
+        drop(practice);
+
+        // the race starts with the practice laps, after a driver swap
+        let mut race = History::new(cfg.clone(), Some(db_file.clone()));
+        race.set_driver(456);
+        race.carry_over(&cfg, laps.clone());
+        assert_eq!(Some(green), race.recent_green(&race.laps));
+        // they're still the first driver's
+        assert_eq!(Some(green), race.driver_green(123));
+        assert_eq!(None, race.driver_green(456));
+        // and doesn't count them twice
+        assert_eq!((2, 0), race.lap_counts());
+        // which aren't written to the db again
+        race.add_lap(lap(1.1));
+        assert_eq!((3, 0), race.lap_counts());
+        race.save_laps().unwrap();
+        drop(race);
+        let db = Db::new(&db_file).unwrap();
+        assert_eq!(3, db.lap_count(1, 1, LapState::empty()));
+        drop(db);
+
+        // a different car starts from scratch
+        let mut other = History::new(
+            RaceSession {
+                car_id: 2,
+                ..cfg.clone()
+            },
+            None,
+        );
+        other.carry_over(&cfg, laps);
+        assert!(other.laps().is_empty());
+        let _ = std::fs::remove_file(&db_file);
+    }
+    #[test]
+    fn unsaved_laps_carried_over_are_saved() {
+        let cfg = cfg();
+        let lap = Lap {
+            fuel_left: 5.0,
+            fuel_used: 1.0,
+            time: TimeSpan::new(30, 0),
+            condition: LapState::empty(),
+        };
+        let db_file = test_db_file("carry_over_unsaved");
+        let mut practice = History::new(cfg.clone(), Some(db_file.clone()));
+        practice.add_lap(lap);
+        practice.save_laps().unwrap();
+        // this one's not saved by the end of the session
+        practice.add_lap(lap);
+        let laps = practice.carry();
+        drop(practice);
+        let db = Db::new(&db_file).unwrap();
+        assert_eq!(1, db.lap_count(1, 1, LapState::empty()));
+        drop(db);
+
+        let mut race = History::new(cfg.clone(), Some(db_file.clone()));
+        race.carry_over(&cfg, laps);
+        race.add_lap(lap);
+        race.save_laps().unwrap();
+        drop(race);
+        let db = Db::new(&db_file).unwrap();
+        assert_eq!(3, db.lap_count(1, 1, LapState::empty()));
+        drop(db);
+        let _ = std::fs::remove_file(&db_file);
+    }
+    #[test]
+    fn provisional_strat() {
+        let cfg = cfg();
+        let mut calc = History::new(cfg, None);
+        let ends = EndsWith::Laps(30);
+        assert!(calc.strat(10.0, &Adjustments::none(), ends).is_none());
+        assert!(!calc.is_provisional());
+        calc.set_provisional(Some(Rate {
+            fuel: 0.5,
+            time: TimeSpan::new(30, 0),
+        }));
+        // no laps logged, but there's a strategy from the provisional rate
+        let strat = calc.strat(10.0, &Adjustments::none(), ends).unwrap();
+        assert_eq!(vec![20, 10], strat.laps());
+        assert!(calc.is_provisional());
+        // real laps take over
+        for _ in 0..2 {
+            calc.add_lap(Lap {
+                fuel_left: 9.0,
+                fuel_used: 1.0,
+                time: TimeSpan::new(30, 0),
+                condition: LapState::empty(),
+            });
+        }
+        assert!(!calc.is_provisional());
+        let strat = calc.strat(10.0, &Adjustments::none(), ends).unwrap();
+        assert_eq!(1.0, strat.green.fuel);
+    }
+    #[test]
+    fn queued_laps_written_at_shutdown() {
+        let cfg = cfg();
+        let db_file = test_db_file("queued_laps");
+        let mut calc = History::new(cfg.clone(), Some(db_file.clone()));
+        let lap = Lap {
+            fuel_left: 5.0,
+            fuel_used: 1.0,
+            time: TimeSpan::new(30, 0),
+            condition: LapState::empty(),
+        };
+        for i in 0..50 {
+            calc.add_lap(lap);
+            if i % 10 == 0 {
+                calc.queue_laps().unwrap();
+            }
+        }
+        calc.queue_laps().unwrap();
+        // nothing is queued twice
+        calc.queue_laps().unwrap();
+        drop(calc);
+
+        let db = Db::new(&db_file).unwrap();
+        assert_eq!(50, db.lap_count(1, 1, LapState::empty()));
+        drop(db);
+        // a sync save after queueing doesn't repeat them either
+        let mut calc = History::new(cfg, Some(db_file.clone()));
+        for _ in 0..3 {
+            calc.add_lap(lap);
+        }
+        calc.queue_laps().unwrap();
+        calc.add_lap(lap);
+        calc.save_laps().unwrap();
+        let db = Db::new(&db_file).unwrap();
+        assert_eq!(54, db.lap_count(1, 1, LapState::empty()));
+        drop(db);
+        drop(calc);
+        let _ = std::fs::remove_file(&db_file);
+    }
+    #[test]
+    fn failed_queued_laps_are_queued_again() {
+        let db_file = test_db_file("failed_queue");
+        let mut calc = History::new(cfg(), Some(db_file.clone()));
+        let lap = Lap {
+            fuel_left: 5.0,
+            fuel_used: 1.0,
+            time: TimeSpan::new(30, 0),
+            condition: LapState::empty(),
+        };
+        let rename = |from: &str, to: &str| {
+            // not a Db, that would create the table again
+            Connection::open(&db_file)
+                .unwrap()
+                .execute(&format!("ALTER TABLE {} RENAME TO {}", from, to), [])
+                .unwrap();
+        };
+        calc.add_lap(lap);
+        calc.queue_laps().unwrap();
+        calc.flush_laps().unwrap();
+        // with the table missing the writes fail, and the failure is reported
+        rename("Lap", "LapAway");
+        calc.add_lap(lap);
+        calc.add_lap(lap);
+        calc.queue_laps().unwrap();
+        assert!(calc.flush_laps().is_err());
+        rename("LapAway", "Lap");
+        calc.add_lap(lap);
+        calc.queue_laps().unwrap();
+        calc.flush_laps().unwrap();
+        let db = Db::new(&db_file).unwrap();
+        assert_eq!(4, db.lap_count(1, 1, LapState::empty()));
+        drop(db);
+        // nothing more to write
+        calc.save_laps().unwrap();
+        let db = Db::new(&db_file).unwrap();
+        assert_eq!(4, db.lap_count(1, 1, LapState::empty()));
+        drop(db);
+        drop(calc);
+        let _ = std::fs::remove_file(&db_file);
+    }
 }
@@ -1,18 +1,20 @@
 #![allow(dead_code)]
 
-use super::history::{Adjustments, History, RaceSession};
-use super::strat::{EndsWith, Lap, LapState, Pitstop, Rate, Strategy, TimeSpan};
+use super::history::{Adjustments, CarriedLaps, DbError, History, RaceSession, TagFilter};
+use super::strat::{EndsWith, Lap, LapState, RaceStop, Rate, Stint, Strategy, TimeSpan};
 use chrono::{DateTime, Local};
 use druid::{Data, Lens};
 use ir::flags::{BroadcastMsg, PitCommand};
 use std::fs::File;
 use std::io::BufReader;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::{fmt, io};
 
 use iracing_telem as ir;
 use iracing_telem::flags::{Flags, SessionState, TrackLocation};
 use iracing_telem::DataUpdateResult;
+use log::{info, warn};
+use yaml_rust::Yaml;
 
 #[derive(Clone, Debug, Data, Lens)]
 pub struct AmountLeft {
@@ -20,6 +22,12 @@ pub struct AmountLeft {
     pub laps: f32,
     pub time: TimeSpan,
 }
+impl AmountLeft {
+    // all the amounts on one line, e.g. "3.2L = 6.4 laps = 09:40"
+    pub fn combined(&self) -> String {
+        format!("{:.1}L = {:.1} laps = {}", self.fuel, self.laps, self.time)
+    }
+}
 impl Default for AmountLeft {
     fn default() -> Self {
         AmountLeft {
@@ -32,87 +40,501 @@ impl Default for AmountLeft {
 
 #[derive(Clone, Debug, Data, Lens)]
 pub struct Estimation {
-    pub connected: bool,            // connected to iracing
-    pub car: AmountLeft,            // what's left in the car
-    pub race: AmountLeft,           // what's left to go in the race
-    pub race_tm_estimated: bool,    // the race time left is an estimate
-    pub race_laps_estimated: bool,  // the race laps left is an estimate
-    pub fuel_last_lap: f32,         // fuel used on the last lap
-    pub green: Rate,                // average per lap usage (green flag only)
-    pub stops: i32,                 // pitstops needed to finish race
-    pub next_stop: Option<Pitstop>, // details on the next pitstop
-    pub save: f32,                  // save this much fuel to skip the last pitstop
-    pub save_target: f32,           // target fuel usage per lap to meet save target
-    pub track_temp: f32,            // current track temp
-    pub start_track_temp: f32,      // track temp at the start of the session
+    pub connected: bool,             // connected to iracing
+    pub reconnecting: bool,          // was connected to iracing, waiting for it to come back
+    pub car: AmountLeft,             // what's left in the car
+    pub car_laps_with_yellow: f32,   // car.laps taking into account any caution laps to go
+    pub race: AmountLeft,            // what's left to go in the race
+    pub race_tm_estimated: bool,     // the race time left is an estimate
+    pub race_laps_estimated: bool,   // the race laps left is an estimate
+    pub fuel_last_lap: f32,          // fuel used on the last lap
+    pub green: Rate,                 // average per lap usage (green flag only)
+    pub lap: i32,                    // race laps completed, the stops are in race laps
+    pub stops: i32,                  // pitstops needed to finish race
+    pub next_stop: Option<RaceStop>, // details on the next pitstop
+    // the plan is only a few stops/stints so comparing them on each update is cheap
+    #[data(same_fn = "PartialEq::eq")]
+    pub all_stops: Vec<RaceStop>, // all the pitstops needed to finish the race
+    #[data(same_fn = "PartialEq::eq")]
+    pub stints: Vec<Stint>, // all the stints to finish the race, starting with the current one
+    // fuel per lap to average from now to skip the next stop
+    pub break_even: Option<f32>,
+    // the break even is within the realistic max save
+    pub break_even_achievable: bool,
+    pub save: f32,             // save this much fuel to skip the last pitstop
+    pub save_target: f32,      // target fuel usage per lap to meet save target
+    pub save_achievable: bool, // the save target is within the realistic max save
+    pub track_temp: f32,       // current track temp
+    pub start_track_temp: f32, // track temp at the start of the session
+    pub black_flag: Option<BlackFlagKind>, // we're being shown the black flag or meatball
+    pub max_fuel_save: Option<f32>, // overrides the max fuel save setting from the dash
+    pub fuel_to_add: i16,      // liters to add at the next stop to finish the race
+    pub dry_lap: Option<i32>,  // the lap we'll run out of fuel on at the current rate
+    pub dump_vars: bool,       // request to write all the telemetry vars to the log
+    pub send_fuel: bool,       // request to recompute and send the fuel to add to the car
+    pub incidents: i32,        // incident count that counts towards any incident limit
+    pub yellow_pit_gain: Option<f32>, // laps gained by pitting under the current caution
+    pub pit_call: Option<PitCall>, // pit now or stay out under the current caution
+    pub stint_fuel_used: f32,  // fuel used since we last left the pits
+    pub last_lap: bool,        // the white flag is out, the strategy is locked in
+    pub stops_stable: bool,    // the latest strategy has the same number of stops as shown
+    pub coarse_fuel: bool,     // the car reports fuel in whole liters, estimates are approximate
+    pub provisional: bool,     // the strategy is from a rough guess at the fuel rate
+    pub fuel_margin: Option<f32>, // laps of fuel we have over the average opponent
+    pub green_laps: i64,       // green laps the green rate was learned from, 0 is a default rate
+    pub yellow_laps: i64,      // yellow laps the yellow rate was learned from
+    pub pit_committed: bool,   // we're in the pit window, the next stop is shown until we pit
+    pub pit_critical: bool,    // we'll run dry before the next pit window opens
+    pub fuel_emergency: bool,  // we can't make it round to the pits on the fuel left
+    pub db_green: Option<Rate>, // the green rate from previous sessions in the laps db
+    pub live_green: Option<Rate>, // the green rate from this session's laps only
+    // under caution and close enough to the pit window to pit early
+    pub tactical_pit_now: bool,
+    // the session tag was saved with the new session, so the UI can clear it
+    pub session_tagged: bool,
     #[data(same_fn = "PartialEq::eq")]
     pub now: DateTime<Local>, // current local (the simulator PC) date/time
+    #[data(same_fn = "PartialEq::eq")]
+    pub projected_finish: DateTime<Local>, // local date/time the race is expected to finish
+    #[data(same_fn = "PartialEq::eq")]
+    pub estimated_finish: DateTime<Local>, // lap limited races, from the laps to go at the green rate
 }
 impl Default for Estimation {
     fn default() -> Self {
         Estimation {
             connected: false,
+            reconnecting: false,
             car: AmountLeft::default(),
+            car_laps_with_yellow: 0.0,
             race: AmountLeft::default(),
             race_laps_estimated: true,
             race_tm_estimated: true,
             fuel_last_lap: 0.0,
             green: Rate::default(),
+            lap: 0,
             stops: 0,
             next_stop: None,
+            all_stops: Vec::new(),
+            stints: Vec::new(),
             save: 0.0,
             save_target: 0.0,
+            save_achievable: true,
+            break_even: None,
+            break_even_achievable: true,
             track_temp: 0.0,
             start_track_temp: 0.0,
+            black_flag: None,
+            max_fuel_save: None,
+            fuel_to_add: 0,
+            dry_lap: None,
+            dump_vars: false,
+            send_fuel: false,
+            session_tagged: false,
+            incidents: 0,
+            yellow_pit_gain: None,
+            pit_call: None,
+            tactical_pit_now: false,
+            stint_fuel_used: 0.0,
+            last_lap: false,
+            stops_stable: true,
+            coarse_fuel: false,
+            provisional: false,
+            fuel_margin: None,
+            green_laps: 0,
+            yellow_laps: 0,
+            pit_committed: false,
+            pit_critical: false,
+            fuel_emergency: false,
+            db_green: None,
+            live_green: None,
             now: Local::now(),
+            projected_finish: Local::now(),
+            estimated_finish: Local::now(),
+        }
+    }
+}
+
+impl Estimation {
+    // the per lap fuel target to show on the dash, and if it's achievable. This is the break
+    // even to skip the next stop when that's realistic, otherwise the target to skip the last stop.
+    pub fn dash_save_target(&self) -> (f32, bool) {
+        match self.break_even {
+            Some(t) if self.break_even_achievable => (t, true),
+            _ => (self.save_target, self.save_achievable),
+        }
+    }
+    // how much more (or less if negative) fuel per lap we're using in this session than
+    // our average from previous sessions. None until there's both to compare.
+    pub fn green_vs_average(&self) -> Option<f32> {
+        match (self.db_green, self.live_green) {
+            (Some(db), Some(live)) => Some(live.fuel - db.fuel),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Data)]
+pub enum BlackFlagKind {
+    Black,      // stop & go / drive through penalty
+    Disqualify, // you're out
+    Repair,     // meatball, pit for repairs
+}
+impl BlackFlagKind {
+    // maps the session flags to the most severe black flag showing, if any.
+    fn from_flags(f: Flags) -> Option<BlackFlagKind> {
+        if f.intersects(Flags::DISQUALIFY) {
+            Some(BlackFlagKind::Disqualify)
+        } else if f.intersects(Flags::BLACK) {
+            Some(BlackFlagKind::Black)
+        } else if f.intersects(Flags::REPAIR) {
+            Some(BlackFlagKind::Repair)
+        } else {
+            None
+        }
+    }
+}
+impl fmt::Display for BlackFlagKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlackFlagKind::Black => write!(f, "BLACK FLAG"),
+            BlackFlagKind::Disqualify => write!(f, "DISQUALIFIED"),
+            BlackFlagKind::Repair => write!(f, "MEATBALL - PIT FOR REPAIRS"),
+        }
+    }
+}
+
+/// a callback for when a new iRacing session starts.
+pub type SessionChangeFn = Box<dyn FnMut(&RaceSession)>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Data)]
+pub enum PitCall {
+    PitNow,
+    StayOut,
+}
+impl PitCall {
+    // pitting has to gain something, otherwise stay out and keep track position.
+    fn from_gain(laps: f32) -> PitCall {
+        if laps > 0.0 {
+            PitCall::PitNow
+        } else {
+            PitCall::StayOut
+        }
+    }
+}
+impl fmt::Display for PitCall {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PitCall::PitNow => write!(f, "PIT NOW"),
+            PitCall::StayOut => write!(f, "STAY OUT"),
         }
     }
 }
 
 pub struct Estimator {
-    client: ir::Client,
+    client: Box<dyn TelemetrySource>,
     state: Option<SessionProgress>,
+    // the session and laps from the last session, so the next one can carry on with them.
+    prev_session: Option<(RaceSession, CarriedLaps)>,
+    /// called each time we start tracking a new iRacing session, including when
+    /// reconnecting after the previous session ended.
+    pub on_session_change: Option<SessionChangeFn>,
 }
 
 #[derive(Debug)]
 enum Error {
     TypeMismatch(ir::Error),
     SessionExpired,
+    SessionInfo(SessionInfoError),
+    Db(DbError),
 }
 impl From<ir::Error> for Error {
     fn from(x: ir::Error) -> Self {
         Error::TypeMismatch(x)
     }
 }
+impl From<SessionInfoError> for Error {
+    fn from(x: SessionInfoError) -> Self {
+        Error::SessionInfo(x)
+    }
+}
+impl From<DbError> for Error {
+    fn from(x: DbError) -> Self {
+        Error::Db(x)
+    }
+}
 
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Data, Lens)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Data, Lens)]
+#[serde(default)]
 pub struct UserSettings {
     /// 0-1 the max percentage fuel saving to consider
     pub max_fuel_save: f32,
+    /// the most fuel per lap (as a fraction) that can realistically be saved by lift
+    /// & coast, save targets that need more than this are shown as not achievable.
+    pub max_realistic_save: f32,
     /// cars typically start to stutter around 0.2-0.3L of fuel left
-    /// What's the minimum we should try to keep in it.
-    pub min_fuel: f32,
+    /// What's the minimum we should try to keep in it. None uses the
+    /// default for the car, see default_min_fuel.
+    pub min_fuel: Option<f32>,
     /// when refueling add enough fuel for this many extra laps.
     pub extra_laps: f32,
     /// when refueling add this amount of extra fuel. Will pick the larger
     /// of this or extra_laps.
     pub extra_fuel: f32,
+    /// how much fuel to add at a pitstop, see RefuelPolicy.
+    pub refuel_policy: RefuelPolicy,
     /// always clear tires when setting pitstop options.
     pub clear_tires: bool,
     /// always take tires when setting pitstop options.
     pub take_tires: bool,
+    /// never send any tire commands, leaving whatever was set manually in the car.
+    /// This takes precedence over clear_tires, which takes precedence over take_tires.
+    pub leave_tires: bool,
+    /// expected green flag fuel usage per lap, used to prime the strategy before
+    /// there are any laps for the car/track.
+    pub baseline_fuel: Option<f32>,
+    /// expected green flag lap time in seconds, used along with baseline_fuel.
+    pub baseline_lap_time: Option<f32>,
+    /// with no laps, baseline or previous sessions to go on, a provisional strategy is
+    /// shown assuming a full tank lasts this many laps. 0 turns this off.
+    pub provisional_tank_laps: f32,
+    /// only use laps from this many of the most recent sessions for the car/track
+    /// when calculating the default rates. None uses laps from all sessions.
+    pub db_session_limit: Option<u32>,
+    /// the offline planner only lists car/tracks with at least this many green laps
+    /// in the database, 1 lists them all.
+    pub min_session_laps: u32,
+    /// leave laps where we were shown the blue flag out of the green flag fuel/time.
+    pub exclude_blue_laps: bool,
+    /// saved with the next session in the laps database, e.g. "league race", so that
+    /// its laps can be picked out later. It's cleared once saved, it only tags one session.
+    pub session_tag: String,
+    /// sessions with this tag are left out of the rates from previous sessions,
+    /// empty uses all the sessions.
+    pub exclude_tag: String,
+    /// where to keep the laps database, None uses the default in Documents.
+    #[data(same_fn = "PartialEq::eq")]
+    pub laps_db_path: Option<PathBuf>,
+    /// in a timed race, how many laps behind the leader we expect to finish. The race
+    /// ends when the leader finishes so we plan for this many fewer laps.
+    pub laps_behind_leader: f32,
+    /// laps that need running under power after the checkered flag, e.g. on some
+    /// ovals, these are added to the race distance when planning fuel.
+    pub cooldown_laps: i32,
+    /// fuel on top of min_fuel to plan to finish the race with, e.g. in case of a
+    /// late caution.
+    pub finish_reserve: f32,
+    /// plan on using this fraction more fuel per green lap than the recent average, e.g.
+    /// 0.05 when expecting to push harder than the laps so far.
+    pub fuel_use_margin: f32,
+    /// units to show the track temp in.
+    pub temp_unit: TempUnit,
+    /// which telemetry var to read the track temp from.
+    pub track_temp_source: TrackTempSource,
+    /// how to round the fuel to add at a pitstop.
+    pub fuel_round: FuelRound,
+    /// the time lost to a pitstop under green, in seconds.
+    pub pit_stop_loss: f32,
+    /// use the average time lost at the pitstops recorded for the car/track instead of
+    /// pit_stop_loss, once there are some.
+    pub measured_pit_loss: bool,
+    /// how many updates in a row we need to be approaching the pits before the pit
+    /// commands are sent, 1 sends them straight away.
+    pub pit_approach_ticks: u32,
+    /// once we're in an open pit window the next stop stays shown until we've pitted, even
+    /// if a later lap's fuel use says it's not needed.
+    pub commit_to_pit: bool,
+    /// under caution, call to pit now when the pit window opens within this many laps, to
+    /// pit ahead of the leaders rather than when the fuel says. 0 turns this off.
+    pub early_yellow_pit_laps: i32,
+    /// the incident limit for the race, the incident count on the dash is highlighted
+    /// as it gets close. None for no limit.
+    pub incident_limit: Option<i32>,
+    /// scales the text on the dash, e.g. 2.0 for a 4K screen.
+    pub ui_scale: f32,
+    /// the palette used to highlight values on the dash.
+    pub theme: Theme,
+    /// show the compact dash with just the fuel and pit window, for a small screen.
+    pub compact_dash: bool,
+    /// start on the view that was showing when the app was last closed, e.g. after a crash.
+    pub restore_view: bool,
+    /// show the car's fuel, laps and time left as one combined line on the dash.
+    pub combined_car: bool,
+    /// estimate how our fuel compares to the cars around us, this assumes they use fuel
+    /// at our rate and filled up at their last stop, so it's only a rough guide.
+    pub opponent_fuel: bool,
+    /// in lap limited races show the finish time from the laps to go at the green lap time,
+    /// rather than from the strategy's estimate of the race time left.
+    pub lap_race_finish: bool,
+    /// enables support tools, such as dumping the telemetry vars to the log.
+    pub debug: bool,
 }
 impl Default for UserSettings {
     fn default() -> UserSettings {
         UserSettings {
             max_fuel_save: 0.15,
-            min_fuel: 0.2,
+            max_realistic_save: 0.1,
+            min_fuel: None,
             extra_laps: 2.0,
             extra_fuel: 1.0,
+            refuel_policy: RefuelPolicy::BufferLaps,
             clear_tires: false,
             take_tires: false,
+            leave_tires: false,
+            baseline_fuel: None,
+            baseline_lap_time: None,
+            provisional_tank_laps: 20.0,
+            db_session_limit: None,
+            min_session_laps: 4,
+            exclude_blue_laps: false,
+            session_tag: String::new(),
+            exclude_tag: String::new(),
+            laps_db_path: None,
+            laps_behind_leader: 0.0,
+            cooldown_laps: 0,
+            finish_reserve: 0.0,
+            fuel_use_margin: 0.0,
+            temp_unit: TempUnit::C,
+            track_temp_source: TrackTempSource::Crew,
+            fuel_round: FuelRound::Ceil,
+            pit_stop_loss: 30.0,
+            measured_pit_loss: false,
+            pit_approach_ticks: 3,
+            early_yellow_pit_laps: 0,
+            commit_to_pit: true,
+            incident_limit: None,
+            ui_scale: 1.0,
+            theme: Theme::Standard,
+            compact_dash: false,
+            restore_view: true,
+            combined_car: false,
+            opponent_fuel: false,
+            lap_race_finish: false,
+            debug: false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq, Data)]
+pub enum TempUnit {
+    #[default]
+    C,
+    F,
+}
+impl TempUnit {
+    /// converts a temperature in Celsius (as iRacing reports it) to this unit.
+    pub fn convert(&self, c: f32) -> f32 {
+        match self {
+            TempUnit::C => c,
+            TempUnit::F => c * 9.0 / 5.0 + 32.0,
+        }
+    }
+    /// converts a difference between 2 temperatures in Celsius to this unit.
+    pub fn convert_delta(&self, c: f32) -> f32 {
+        match self {
+            TempUnit::C => c,
+            TempUnit::F => c * 9.0 / 5.0,
+        }
+    }
+}
+/// Which telemetry var to read the track temp from.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq, Data)]
+pub enum TrackTempSource {
+    #[default]
+    Crew, // the temp reported by the crew
+    Sensor, // the track temp sensor
+}
+impl TrackTempSource {
+    /// the name of the telemetry var for this source.
+    pub fn var_name(&self) -> &'static str {
+        match self {
+            TrackTempSource::Crew => "TrackTempCrew",
+            TrackTempSource::Sensor => "TrackTemp",
+        }
+    }
+    // the source to use if this one isn't available.
+    fn fallback(&self) -> TrackTempSource {
+        match self {
+            TrackTempSource::Crew => TrackTempSource::Sensor,
+            TrackTempSource::Sensor => TrackTempSource::Crew,
+        }
+    }
+}
+impl fmt::Display for TrackTempSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrackTempSource::Crew => write!(f, "Crew"),
+            TrackTempSource::Sensor => write!(f, "Sensor"),
+        }
+    }
+}
+/// How the fuel to add at a pitstop is rounded. The iRacing pit command only accepts whole
+/// liters, so an Exact amount is sent to the car, and shown on the dash, as the nearest liter.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq, Data)]
+pub enum FuelRound {
+    #[default]
+    Ceil, // up to the next whole liter
+    Nearest, // to the nearest whole liter
+    Exact,   // no rounding
+}
+impl FuelRound {
+    pub fn round(&self, fuel: f32) -> f32 {
+        match self {
+            FuelRound::Ceil => fuel.ceil(),
+            FuelRound::Nearest => fuel.round(),
+            FuelRound::Exact => fuel,
+        }
+    }
+}
+impl fmt::Display for FuelRound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FuelRound::Ceil => write!(f, "Round Up"),
+            FuelRound::Nearest => write!(f, "Nearest"),
+            FuelRound::Exact => write!(f, "Exact"),
+        }
+    }
+}
+
+/// How much fuel to add at a pitstop.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq, Data)]
+pub enum RefuelPolicy {
+    Planned, // just what the strategy needs
+    #[default]
+    BufferLaps, // the strategy plus the extra laps/fuel from settings
+    FillTank, // a full tank, whenever the strategy needs any fuel
+}
+impl fmt::Display for RefuelPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RefuelPolicy::Planned => write!(f, "Planned"),
+            RefuelPolicy::BufferLaps => write!(f, "Extra Laps"),
+            RefuelPolicy::FillTank => write!(f, "Fill Tank"),
+        }
+    }
+}
+
+/// The palette used to highlight values on the dash.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq, Data)]
+pub enum Theme {
+    #[default]
+    Standard,
+    ColorBlind, // avoids relying on red vs green
+}
+impl fmt::Display for Theme {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Theme::Standard => write!(f, "Standard"),
+            Theme::ColorBlind => write!(f, "Color Blind"),
+        }
+    }
+}
+impl fmt::Display for TempUnit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TempUnit::C => write!(f, "Celsius"),
+            TempUnit::F => write!(f, "Fahrenheit"),
         }
     }
 }
@@ -132,16 +554,47 @@ impl From<serde_json::Error> for JsonLoadError {
         JsonLoadError::JsonError(e)
     }
 }
+impl fmt::Display for JsonLoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JsonLoadError::IOError(e) => write!(f, "{}", e),
+            JsonLoadError::JsonError(e) => write!(f, "invalid settings file: {}", e),
+        }
+    }
+}
 impl UserSettings {
-    pub fn load(path: Option<PathBuf>) -> UserSettings {
+    pub fn baseline(&self) -> Option<Rate> {
+        match (self.baseline_fuel, self.baseline_lap_time) {
+            (Some(fuel), Some(tm)) if fuel > 0.0 && tm > 0.0 => Some(Rate {
+                fuel,
+                time: TimeSpan::from_secs_f32(tm),
+            }),
+            _ => None,
+        }
+    }
+    pub fn tag_filter(&self) -> Option<TagFilter> {
+        if self.exclude_tag.is_empty() {
+            None
+        } else {
+            Some(TagFilter::Exclude(self.exclude_tag.clone()))
+        }
+    }
+    // the min fuel setting, or the default for the car if it's not set.
+    pub fn min_fuel_for(&self, car: &str) -> f32 {
+        self.min_fuel.unwrap_or_else(|| default_min_fuel(car))
+    }
+    pub fn laps_db(&self) -> Option<PathBuf> {
+        self.laps_db_path.clone().or_else(default_laps_db)
+    }
+    /// loads the settings, a missing settings file is the defaults.
+    pub fn load(path: Option<PathBuf>) -> Result<UserSettings, JsonLoadError> {
         match path {
-            None => Self::default(),
+            None => Ok(Self::default()),
             Some(p) => match Self::load_impl(p) {
-                Ok(s) => s,
-                Err(e) => {
-                    println!("Failed to load settings {:?}", e);
-                    Self::default()
+                Err(JsonLoadError::IOError(e)) if e.kind() == io::ErrorKind::NotFound => {
+                    Ok(Self::default())
                 }
+                r => r,
             },
         }
     }
@@ -161,6 +614,53 @@ impl UserSettings {
             }
         }
     }
+    /// writes the settings to a file to share with someone else.
+    pub fn export_to(&self, path: &Path) -> Result<(), JsonLoadError> {
+        self.save(Some(path.to_path_buf()))
+    }
+    /// reads settings that someone else exported, unlike load a missing file is an
+    /// error, and any out of range values are clamped.
+    pub fn import_from(path: &Path) -> Result<UserSettings, JsonLoadError> {
+        let mut s = Self::load_impl(path.to_path_buf())?;
+        s.clamp();
+        Ok(s)
+    }
+    // pulls any values that don't make sense back into range.
+    fn clamp(&mut self) {
+        self.max_fuel_save = self.max_fuel_save.clamp(0.0, 0.5);
+        self.max_realistic_save = self.max_realistic_save.clamp(0.0, 0.5);
+        self.min_fuel = self.min_fuel.map(|f| f.max(0.0));
+        self.extra_laps = self.extra_laps.max(0.0);
+        self.extra_fuel = self.extra_fuel.max(0.0);
+        self.min_session_laps = self.min_session_laps.max(1);
+        self.laps_behind_leader = self.laps_behind_leader.max(0.0);
+        self.cooldown_laps = self.cooldown_laps.max(0);
+        self.finish_reserve = self.finish_reserve.max(0.0);
+        self.fuel_use_margin = self.fuel_use_margin.clamp(0.0, 0.5);
+        self.pit_stop_loss = self.pit_stop_loss.max(0.0);
+        self.pit_approach_ticks = self.pit_approach_ticks.max(1);
+        self.early_yellow_pit_laps = self.early_yellow_pit_laps.max(0);
+        self.provisional_tank_laps = self.provisional_tank_laps.max(0.0);
+        self.incident_limit = self.incident_limit.filter(|l| *l > 0);
+        self.ui_scale = self.ui_scale.clamp(0.5, 4.0);
+    }
+}
+
+// cars that stutter at a different fuel level to most, matched on a substring of the car name.
+const CAR_MIN_FUEL: [(&str, f32); 4] = [
+    ("Dallara IR18", 0.4),
+    ("NASCAR Cup", 0.5),
+    ("Skip Barber", 0.1),
+    ("Ray FF1600", 0.1),
+];
+const DEFAULT_MIN_FUEL: f32 = 0.2;
+
+/// the min fuel to keep in the tank for this car when the user hasn't set one.
+pub fn default_min_fuel(car: &str) -> f32 {
+    CAR_MIN_FUEL
+        .iter()
+        .find(|(name, _)| car.contains(name))
+        .map_or(DEFAULT_MIN_FUEL, |(_, f)| *f)
 }
 
 pub fn default_laps_db() -> Option<PathBuf> {
@@ -169,63 +669,239 @@ pub fn default_laps_db() -> Option<PathBuf> {
 pub fn default_settings_file() -> Option<PathBuf> {
     dirs_next::document_dir().map(|dir| dir.join("naf_calc\\settings.json"))
 }
+pub fn default_export_file() -> Option<PathBuf> {
+    dirs_next::document_dir().map(|dir| dir.join("naf_calc\\settings_export.json"))
+}
+pub fn default_planner_file() -> Option<PathBuf> {
+    dirs_next::document_dir().map(|dir| dir.join("naf_calc\\planner.json"))
+}
+pub fn default_view_file() -> Option<PathBuf> {
+    dirs_next::document_dir().map(|dir| dir.join("naf_calc\\view.json"))
+}
+
+// somewhere to get telemetry sessions from. This is the iRacing client, except in tests
+// which supply scripted telemetry instead.
+trait TelemetrySource {
+    // the session currently running, None if there isn't one.
+    fn connect(&mut self, settings: &UserSettings) -> Option<Box<dyn TelemetrySession>>;
+}
+// the telemetry for a single session, and the car's pit controls.
+trait TelemetrySession {
+    // the session info yaml
+    fn session_info(&self) -> String;
+    // changes each time the session info is updated, e.g. after a driver swap.
+    fn session_info_update(&self) -> i32;
+    // moves on to the latest telemetry, Err(SessionExpired) once the session has ended.
+    fn get_new_data(&mut self) -> Result<(), Error>;
+    fn read(&self) -> Result<IRacingTelemetryRow, Error>;
+    fn read_opponents(&self) -> Result<Option<OpponentRow>, Error>;
+    fn pit_command(&self, cmd: PitCommand);
+    // writes all the telemetry vars and their current values to the log.
+    fn log_vars(&self);
+}
+impl TelemetrySource for ir::Client {
+    fn connect(&mut self, settings: &UserSettings) -> Option<Box<dyn TelemetrySession>> {
+        unsafe { self.session() }.map(|s| {
+            let f = TelemetryFactory::new(&s, settings.track_temp_source);
+            Box::new(IRacingSession { ir: s, f }) as Box<dyn TelemetrySession>
+        })
+    }
+}
+struct IRacingSession {
+    ir: ir::Session,
+    f: TelemetryFactory,
+}
+impl TelemetrySession for IRacingSession {
+    fn session_info(&self) -> String {
+        unsafe { self.ir.session_info() }
+    }
+    fn session_info_update(&self) -> i32 {
+        unsafe { self.ir.session_info_update() }
+    }
+    fn get_new_data(&mut self) -> Result<(), Error> {
+        match unsafe { self.ir.get_new_data() } {
+            DataUpdateResult::SessionExpired => Err(Error::SessionExpired),
+            _ => Ok(()),
+        }
+    }
+    fn read(&self) -> Result<IRacingTelemetryRow, Error> {
+        Ok(self.f.read(&self.ir)?)
+    }
+    fn read_opponents(&self) -> Result<Option<OpponentRow>, Error> {
+        Ok(self.f.read_opponents(&self.ir)?)
+    }
+    fn pit_command(&self, cmd: PitCommand) {
+        unsafe {
+            let _ = self.ir.broadcast_msg(BroadcastMsg::PitCommand(cmd));
+        }
+    }
+    // The client's dump_vars writes to stdout which is lost in the windowed build.
+    fn log_vars(&self) {
+        unsafe {
+            let vars = self.ir.vars();
+            info!("dumping {} telemetry vars", vars.len());
+            for var in vars {
+                info!(
+                    "{} [{}] {:?}",
+                    var.name(),
+                    var.unit(),
+                    self.ir.var_value(&var)
+                );
+            }
+        }
+    }
+}
 
 // state needed by a running calculator
 struct SessionProgress {
-    ir: ir::Session,
+    telem: Box<dyn TelemetrySession>,
     calc: History,
-    f: TelemetryFactory,
     last: IRacingTelemetryRow,
     lap_start: IRacingTelemetryRow,
     first: IRacingTelemetryRow,
+    max_fuel_save: f32,          // the max fuel save used for the current strategy
+    stint_start_fuel: f32,       // the fuel level when we last left the pit box
+    stops: StopCount,            // the stop count shown on the dash
+    pit_visit: Option<PitVisit>, // the pit lane visit in progress
+    blue_this_lap: bool,         // the blue flag was shown at some point this lap
+    summary_logged: bool,        // the race summary has been written to the log
+    approach_ticks: u32,         // how many updates in a row we've been approaching the pits
+    warmup_fuel_sent: bool,      // the opening fuel load has been sent during warmup
+    opponent_stints: Vec<i32>,   // the lap each car last left pit road on, by car idx
+    // the stop we're committed to, if any.
+    committed_stop: Option<RaceStop>,
+    // the strategy from the start of the stint, and the fuel level and lap completed then.
+    stint_plan: Option<(Strategy, f32, i32)>,
+    // the strategy shown on the dash, and the lap completed it was calculated at.
+    shown: Option<(Strategy, i32)>,
+    info_update: i32, // the session info update we last read the driver from
 }
 impl SessionProgress {
-    fn new(session: ir::Session, settings: &UserSettings) -> Result<SessionProgress, ir::Error> {
-        let session_info = IrSessionInfo::parse(unsafe { &session.session_info() }, 0);
+    fn new(
+        telem: Box<dyn TelemetrySession>,
+        settings: &UserSettings,
+    ) -> Result<SessionProgress, Error> {
+        let session_info = IrSessionInfo::parse(&telem.session_info(), 0)?;
         let cfg = RaceSession {
             fuel_tank_size: (session_info.driver_car_fuel_max_ltr
                 * session_info.driver_car_max_fuel_pct) as f32,
             max_fuel_save: settings.max_fuel_save,
-            min_fuel: settings.min_fuel,
+            min_fuel: settings.min_fuel_for(&session_info.car_name),
             track_id: session_info.track_id,
             track_name: session_info.track_display_name,
             layout_name: session_info.track_config_name,
             car_id: session_info.car_id,
             car: session_info.car_name,
         };
-        let calc = History::new(cfg, default_laps_db()).unwrap();
-        let f = TelemetryFactory::new(&session);
-        let last = f.read(&session)?;
+        let mut calc = History::new(cfg, settings.laps_db());
+        if let Some(green) = settings.baseline() {
+            calc.set_baseline(green, None);
+        }
+        if settings.db_session_limit.is_some() {
+            calc.set_db_session_limit(settings.db_session_limit);
+        }
+        if let Some(tags) = settings.tag_filter() {
+            calc.set_tag_filter(Some(tags));
+        }
+        calc.set_exclude_blue(settings.exclude_blue_laps);
+        calc.set_driver(session_info.driver_user_id);
+        calc.set_provisional(provisional_rate(
+            calc.config().fuel_tank_size,
+            settings.provisional_tank_laps,
+            session_info.driver_car_est_lap_time,
+        ));
+        let last = telem.read()?;
+        let info_update = telem.session_info_update();
         Ok(SessionProgress {
-            ir: session,
+            telem,
             calc,
-            f,
             last,
             lap_start: last,
             first: last,
+            max_fuel_save: settings.max_fuel_save,
+            stint_start_fuel: last.fuel_level,
+            stops: StopCount::new(),
+            pit_visit: None,
+            blue_this_lap: false,
+            summary_logged: false,
+            approach_ticks: 0,
+            warmup_fuel_sent: false,
+            opponent_stints: Vec::new(),
+            committed_stop: None,
+            stint_plan: None,
+            shown: None,
+            info_update,
         })
     }
-    fn read(&mut self) -> Result<IRacingTelemetryRow, ir::Error> {
-        self.f.read(&self.ir)
+    // works out the fuel to add and sends it along with the tire changes to the car.
+    fn send_pit_commands(
+        &self,
+        trigger: &str,
+        this: &IRacingTelemetryRow,
+        adj: &Adjustments,
+        settings: &UserSettings,
+        result: &mut Estimation,
+    ) {
+        let add = fuel_liters(pit_fuel(&self.calc, trigger, this, adj, settings));
+        result.fuel_to_add = add;
+        for cmd in pit_commands(settings, add) {
+            self.telem.pit_command(cmd);
+        }
+    }
+    // shows the strategy on the dash straight away, the stop count starts again from it.
+    fn show_strat(
+        &mut self,
+        strat: &Strategy,
+        this: &IRacingTelemetryRow,
+        tank: f32,
+        settings: &UserSettings,
+        result: &mut Estimation,
+    ) {
+        strat_to_result(
+            strat,
+            this.lap_completed,
+            this.fuel_level,
+            tank,
+            settings,
+            result,
+        );
+        self.stops.reset(result.stops);
+        self.shown = Some((strat.clone(), this.lap_completed));
     }
     fn update(&mut self, settings: &UserSettings, result: &mut Estimation) -> Result<(), Error> {
-        unsafe {
-            if self.ir.get_new_data() == DataUpdateResult::SessionExpired {
-                return Err(Error::SessionExpired);
+        self.telem.get_new_data()?;
+        let this = self.telem.read()?;
+        if self.telem.session_info_update() != self.info_update {
+            self.info_update = self.telem.session_info_update();
+            // a driver swap in a team race shows up as a new driver for our car
+            if let Ok(si) = IrSessionInfo::parse(&self.telem.session_info(), this.session_num) {
+                if si.driver_user_id != self.calc.driver() {
+                    info!("driver changed to {}", si.driver_name);
+                    self.calc.set_driver(si.driver_user_id);
+                }
             }
-        };
-        let adj = Adjustments {
-            max_fuel_save: Some(settings.max_fuel_save),
-            min_fuel: Some(settings.min_fuel),
-        };
-        let this = self.read()?;
+        }
+        let adj = adjustments(settings, result, &this);
+        let tank = self.calc.config().fuel_tank_size;
+        result.provisional = self.calc.is_provisional();
+        result.lap = this.lap_completed;
         if this.session_time < self.last.session_time {
             // If the session time goes backwards then we've moved between
             // different sessions inside a single race, e.g. practice -> qualy
-            self.calc.save_laps().unwrap(); // TODO
+            if let Err(e) = self.calc.queue_laps() {
+                warn!("failed to save laps {:?}", e);
+            }
             self.last = this;
             self.lap_start = this;
             self.first = this;
+            self.stint_start_fuel = this.fuel_level;
+            self.pit_visit = None;
+            self.blue_this_lap = false;
+            self.warmup_fuel_sent = false;
+            self.stint_plan = None;
+            self.shown = None;
+            self.summary_logged = false;
+            self.committed_stop = None;
         }
         if (!self.lap_start.is_on_track) && this.is_on_track {
             // ensure lap_start is from when we're in the car.
@@ -237,8 +913,11 @@ impl SessionProgress {
             // reset lap start when we leave the pit box
             self.lap_start = this;
             // show the stratagy if there's one available
+            self.stint_plan = None;
             if let Some(x) = self.calc.strat(this.fuel_level, &adj, this.ends()) {
-                strat_to_result(&x, result);
+                log_strat("pit exit", this.fuel_level, this.ends(), &x);
+                self.show_strat(&x, &this, tank, settings, result);
+                self.stint_plan = Some((x, this.fuel_level, this.lap_completed));
             }
         }
         if this.session_state == SessionState::ParadeLaps
@@ -248,10 +927,34 @@ impl SessionProgress {
             self.lap_start = this;
             // show the stratagy if there's one available
             if let Some(x) = self.calc.strat(this.fuel_level, &adj, this.ends()) {
-                strat_to_result(&x, result);
+                log_strat("parade lap", this.fuel_level, this.ends(), &x);
+                self.show_strat(&x, &this, tank, settings, result);
+            }
+        }
+        if one_to_green(&self.last, &this) && !this.is_last_lap() {
+            // switch to the green flag plan now, rather than waiting for the end of the lap
+            if let Some(x) = self.calc.strat(this.fuel_level, &adj, this.ends()) {
+                log_strat("one to green", this.fuel_level, this.ends(), &x);
+                self.show_strat(&x, &this, tank, settings, result);
+            }
+        }
+        let length_changed = race_length_changed(&self.last, &this);
+        if length_changed && !this.is_last_lap() {
+            // e.g. the race was shortened after a red flag, the stops are all different now
+            if let Some(x) = self.calc.strat(this.fuel_level, &adj, this.ends()) {
+                log_strat("race length", this.fuel_level, this.ends(), &x);
+                self.show_strat(&x, &this, tank, settings, result);
+                self.stint_plan = None;
             }
         }
+        // the blue flag is usually only shown briefly, so remember it for the whole lap
+        self.blue_this_lap |= this.lap_state().intersects(LapState::BLUE);
         if this.lap_progress < 0.1 && self.last.lap_progress > 0.9 {
+            let blue = if self.blue_this_lap {
+                LapState::BLUE
+            } else {
+                LapState::empty()
+            };
             let new_lap = Lap {
                 fuel_left: this.fuel_level,
                 fuel_used: self.lap_start.fuel_level - this.fuel_level,
@@ -262,77 +965,120 @@ impl SessionProgress {
                     this.session_time,
                     0.0,
                 ) - TimeSpan::from_secs_f64(self.lap_start.session_time),
-                condition: this.lap_state() | self.lap_start.lap_state(),
+                condition: this.lap_state() | self.lap_start.lap_state() | blue,
             };
-            if this.session_state != SessionState::Checkered
-                && this.session_state != SessionState::CoolDown
-            {
+            if !this.is_finished() {
                 if new_lap.fuel_used > 0.0 {
                     // reset to pit, towing etc can end up with have a negative fuel used
                     // so skip those, they're junk.
                     self.calc.add_lap(new_lap);
+                    let coarse = self.calc.coarse_fuel();
+                    if coarse && !result.coarse_fuel {
+                        warn!("fuel level is only reported in whole liters, estimates are approximate");
+                    }
+                    result.coarse_fuel = coarse;
+                    (result.green_laps, result.yellow_laps) = self.calc.lap_counts();
+                    result.db_green = self.calc.def_green();
+                    result.live_green = self.calc.live_green();
                 }
-                if let Some(strat) = self.calc.strat(this.fuel_level, &adj, this.ends()) {
-                    strat_to_result(&strat, result)
+                // the strategy is locked in once the white flag is out
+                if !this.is_last_lap() {
+                    if let Some(strat) = self.calc.strat(this.fuel_level, &adj, this.ends()) {
+                        log_strat("lap", this.fuel_level, this.ends(), &strat);
+                        // don't flip flop the strategy when the stop count is near a boundary
+                        let strat = smoothed_strat(
+                            &mut self.stops,
+                            &mut self.shown,
+                            strat,
+                            this.lap_completed,
+                        );
+                        strat_to_result(
+                            &strat,
+                            this.lap_completed,
+                            this.fuel_level,
+                            tank,
+                            settings,
+                            result,
+                        );
+                        let (plan, start_fuel, start_lap) = self.stint_plan.get_or_insert((
+                            strat,
+                            this.fuel_level,
+                            this.lap_completed,
+                        ));
+                        if result.save > 0.0 && plan.fuel_to_save > 0.0 {
+                            // keep to the target set at the start of the stint, adjusted
+                            // for how much we've actually used so far
+                            result.save_target = plan.fuel_target_at(
+                                *start_fuel - this.fuel_level,
+                                this.lap_completed - *start_lap,
+                            );
+                            result.save_achievable = result
+                                .green
+                                .can_save_to(result.save_target, settings.max_realistic_save);
+                        }
+                    }
                 }
             }
             result.fuel_last_lap = new_lap.fuel_used;
             self.lap_start = this;
+            self.blue_this_lap = this.lap_state().intersects(LapState::BLUE);
         }
-        if this.player_track_surface == TrackLocation::ApproachingPits
-            && self.last.player_track_surface != TrackLocation::ApproachingPits
+        if this.session_state == SessionState::Checkered
+            && self.last.session_state != SessionState::Checkered
         {
-            if settings.clear_tires {
-                unsafe {
-                    let _ = self
-                        .ir
-                        .broadcast_msg(BroadcastMsg::PitCommand(PitCommand::ClearTires));
-                }
-            } else if settings.take_tires {
-                unsafe {
-                    let _ = self
-                        .ir
-                        .broadcast_msg(BroadcastMsg::PitCommand(PitCommand::LF(None)));
-                    let _ = self
-                        .ir
-                        .broadcast_msg(BroadcastMsg::PitCommand(PitCommand::RF(None)));
-                    let _ = self
-                        .ir
-                        .broadcast_msg(BroadcastMsg::PitCommand(PitCommand::LR(None)));
-                    let _ = self
-                        .ir
-                        .broadcast_msg(BroadcastMsg::PitCommand(PitCommand::RR(None)));
+            info!("race summary: {}", self.calc.race_summary());
+            self.summary_logged = true;
+        }
+        if this.is_finished() && !self.last.is_finished() {
+            // save the race's laps now, rather than rely on getting to drop the session.
+            // Only laps not already written get saved, so there's no double save later.
+            if let Err(e) = self.calc.queue_laps() {
+                warn!("failed to save laps at the finish {:?}", e);
+            }
+        }
+        if adj.max_fuel_save != Some(self.max_fuel_save) {
+            // the fuel save was changed from the dash, show the updated strategy
+            self.max_fuel_save = adj.max_fuel_save.unwrap();
+            if !this.is_last_lap() {
+                if let Some(strat) = self.calc.strat(this.fuel_level, &adj, this.ends()) {
+                    log_strat("fuel save", this.fuel_level, this.ends(), &strat);
+                    self.show_strat(&strat, &this, tank, settings, result);
                 }
             }
-            match self.calc.strat(this.fuel_level, &adj, this.ends()) {
-                None => unsafe {
-                    let _ = self
-                        .ir
-                        .broadcast_msg(BroadcastMsg::PitCommand(PitCommand::Fuel(Some(
-                            self.calc.config().fuel_tank_size.ceil() as i16,
-                        ))));
-                },
-                Some(x) => unsafe {
-                    let total: f32 = x.total_fuel();
-                    let add = (total - this.fuel_level
-                        + (settings.extra_fuel.max(x.green.fuel * settings.extra_laps)))
-                    .ceil();
-                    if add > 0.0 {
-                        let _ = self
-                            .ir
-                            .broadcast_msg(BroadcastMsg::PitCommand(PitCommand::Fuel(Some(
-                                add as i16,
-                            ))));
-                    } else {
-                        let _ = self
-                            .ir
-                            .broadcast_msg(BroadcastMsg::PitCommand(PitCommand::ClearFuel));
-                    }
-                },
+        }
+        let (ticks, entering) =
+            pit_approach(self.approach_ticks, &this, settings.pit_approach_ticks);
+        self.approach_ticks = ticks;
+        if entering {
+            self.send_pit_commands("pit entry", &this, &adj, settings, result);
+        } else if warmup_fuel_due(self.warmup_fuel_sent, &this) {
+            // get the opening fuel load set before the parade lap, this is a full tank if
+            // the strategy is only from the provisional rate.
+            self.warmup_fuel_sent = true;
+            if let Some(x) = self.calc.strat(this.fuel_level, &adj, this.ends()) {
+                self.show_strat(&x, &this, tank, settings, result);
             }
+            self.send_pit_commands("warmup", &this, &adj, settings, result);
+        } else if result.send_fuel && this.is_on_track {
+            // asked for from the dash, e.g. after changing the pit settings
+            self.send_pit_commands("send fuel", &this, &adj, settings, result);
         }
+        result.send_fuel = false;
         // update car status info in result
         result.car.fuel = this.fuel_level;
+        self.stint_start_fuel = stint_start_fuel(self.stint_start_fuel, &self.last, &this);
+        result.stint_fuel_used = (self.stint_start_fuel - this.fuel_level).max(0.0);
+        let (visit, pit_stop) = pit_visit(self.pit_visit, &self.last, &this);
+        self.pit_visit = visit;
+        if let Some((lane_time, lane_laps)) = pit_stop {
+            info!("pit stop took {}", lane_time);
+            if let Some(loss) = pit_loss(lane_time, lane_laps, result.green) {
+                info!("pit stop lost {} over a green lap", loss);
+                if let Err(e) = self.calc.add_pit_stop(loss) {
+                    warn!("failed to save pit stop {:?}", e);
+                }
+            }
+        }
         if this.is_on_track {
             result.race.fuel =
                 (result.race.fuel - (self.last.fuel_level - this.fuel_level).max(0.0)).max(0.0)
@@ -346,6 +1092,24 @@ impl SessionProgress {
             result.car.laps = 0.0;
             result.car.time = TimeSpan::ZERO;
         }
+        result.car_laps_with_yellow = self
+            .calc
+            .laps_to_empty(this.fuel_level)
+            .unwrap_or(result.car.laps);
+        result.dry_lap = dry_lap(this.lap_completed, this.fuel_level, result.green.fuel);
+        result.pit_critical =
+            result.green.fuel > 0.0 && pit_critical(result.car.laps, result.next_stop, result.lap);
+        result.fuel_emergency =
+            result.green.fuel > 0.0 && fuel_emergency(result.car.laps, &this, result.next_stop);
+        result.fuel_margin = None;
+        if settings.opponent_fuel && result.green.fuel > 0.0 {
+            if let Some(opp) = self.telem.read_opponents()? {
+                opponent_stint_starts(&mut self.opponent_stints, &opp);
+                let tank_laps = self.calc.config().fuel_tank_size / result.green.fuel;
+                result.fuel_margin =
+                    opponent_fuel_margin(result.car.laps, tank_laps, &self.opponent_stints, &opp);
+            }
+        }
         // update race time/laps left from source, not strat
         let tick = this.session_time - self.last.session_time;
         let dtick = TimeSpan::from_secs_f64(tick);
@@ -358,6 +1122,10 @@ impl SessionProgress {
             }
             EndsWith::Time(d) => {
                 result.race.time = d;
+                // keep the estimate in step with the clock, not from the last strategy
+                if let Some(laps) = timed_race_laps(d, result.green) {
+                    result.race.laps = laps;
+                }
                 result.race_laps_estimated = true;
                 result.race_tm_estimated = false;
             }
@@ -368,10 +1136,65 @@ impl SessionProgress {
                 result.race_tm_estimated = false;
             }
         }
+        result.black_flag = BlackFlagKind::from_flags(this.session_flags);
+        result.incidents = this.incidents();
+        result.last_lap = this.is_last_lap();
+        result.stops_stable = self.stops.is_stable();
+        // under caution, re-evaluate pitting when the caution starts and at each lap
+        if !this.lap_state().intersects(LapState::YELLOW) {
+            result.yellow_pit_gain = None;
+        } else if !self.last.lap_state().intersects(LapState::YELLOW)
+            || (this.lap_progress < 0.1 && self.last.lap_progress > 0.9)
+        {
+            let measured = if settings.measured_pit_loss {
+                self.calc.pit_loss()
+            } else {
+                None
+            };
+            result.yellow_pit_gain = self.calc.yellow_pit_gain(
+                this.fuel_level,
+                &adj,
+                this.ends(),
+                measured
+                    .unwrap_or_else(|| TimeSpan::from_secs_f32(settings.pit_stop_loss.max(0.0))),
+            );
+        }
+        result.pit_call = result.yellow_pit_gain.map(PitCall::from_gain);
+        result.tactical_pit_now = tactical_pit_now(
+            this.lap_state().intersects(LapState::YELLOW),
+            result.next_stop,
+            result.lap,
+            settings.early_yellow_pit_laps,
+        );
+        if result.tactical_pit_now {
+            result.pit_call = Some(PitCall::PitNow);
+        }
+        // a change to the race length can mean the stop we were committed to isn't needed
+        let committed = if settings.commit_to_pit && !length_changed {
+            pit_commit(
+                self.committed_stop,
+                result.next_stop,
+                result.lap,
+                &self.last,
+                &this,
+            )
+        } else {
+            None
+        };
+        self.committed_stop = committed;
+        result.pit_committed = committed.is_some();
+        if result.next_stop.is_none() && committed.is_some() {
+            // a lap's fuel reading bounced, carry on showing the stop we're committed to
+            result.next_stop = committed;
+            result.stops = result.stops.max(1);
+        }
         // update track temp & time
         result.track_temp = this.track_temp;
         result.start_track_temp = self.first.track_temp;
         result.now = Local::now();
+        result.projected_finish = projected_finish(result.now, result.race.time);
+        result.estimated_finish =
+            estimated_finish(result.now, this.ends(), result.green, result.race.time);
         self.last = this;
         Ok(())
     }
@@ -390,48 +1213,89 @@ impl SessionProgress {
         if end_of_lap_pos > start_of_lap_pos {
             end_of_lap_pos -= 1.0;
         }
+        if start_of_lap_pos == end_of_lap_pos {
+            // the car didn't move, there's nothing to interpolate between.
+            return TimeSpan::from_secs_f64(start_of_lap_tm);
+        }
         let pct = ((check_pos - end_of_lap_pos) / (start_of_lap_pos - end_of_lap_pos)) as f64;
         TimeSpan::from_secs_f64(end_of_lap_tm + ((start_of_lap_tm - end_of_lap_tm) * pct))
     }
 }
 impl Drop for SessionProgress {
     fn drop(&mut self) {
-        let _ = self.calc.save_laps(); //TODO
+        if !self.summary_logged {
+            // left before the checkered flag
+            info!("session summary: {}", self.calc.race_summary());
+        }
+        if let Err(e) = self.calc.save_laps() {
+            warn!("failed to save laps at the end of the session {:?}", e);
+        }
     }
 }
 impl Estimator {
     pub fn new() -> Estimator {
+        Self::with_source(Box::new(ir::Client::new()))
+    }
+    fn with_source(client: Box<dyn TelemetrySource>) -> Estimator {
         Estimator {
-            client: ir::Client::new(),
+            client,
             state: None,
+            prev_session: None,
+            on_session_change: None,
+        }
+    }
+    fn session_started(&mut self, session: &RaceSession) {
+        if let Some(f) = &mut self.on_session_change {
+            f(session);
         }
     }
     pub fn update(&mut self, settings: &UserSettings, result: &mut Estimation) {
-        unsafe {
-            if self.state.is_none() {
-                match self.client.session() {
-                    None => {
-                        *result = Estimation::default();
+        if self.state.is_none() {
+            match self.client.connect(settings) {
+                None => {
+                    on_disconnect(result);
+                    return;
+                }
+                Some(session) => match SessionProgress::new(session, settings) {
+                    Err(_) => {
+                        on_disconnect(result);
                         return;
                     }
-                    Some(session) => match SessionProgress::new(session, settings) {
-                        Err(_) => {
-                            *result = Estimation::default();
-                            return;
+                    Ok(mut cs) => {
+                        if let Some((prev, laps)) = self.prev_session.take() {
+                            cs.calc.carry_over(&prev, laps);
                         }
-                        Ok(cs) => {
-                            self.state = Some(cs);
-                            result.connected = true;
+                        // the tag is for this session only, the UI clears it once it's saved
+                        if !settings.session_tag.is_empty() {
+                            match cs.calc.set_session_tag(&settings.session_tag) {
+                                Ok(_) => result.session_tagged = true,
+                                Err(e) => warn!("failed to tag session {:?}", e),
+                            }
                         }
-                    },
-                }
+                        self.session_started(&cs.calc.config());
+                        (result.green_laps, result.yellow_laps) = cs.calc.lap_counts();
+                        result.db_green = cs.calc.def_green();
+                        result.live_green = cs.calc.live_green();
+                        self.state = Some(cs);
+                        on_connect(result);
+                    }
+                },
             }
         }
         if let Some(cs) = &mut self.state {
+            if result.dump_vars && settings.debug {
+                cs.telem.log_vars();
+            }
+            result.dump_vars = false;
             match cs.update(settings, result) {
                 Ok(_) => {}
                 Err(Error::SessionExpired) => {
-                    *result = Estimation::default();
+                    on_disconnect(result);
+                    // give the laps a chance to be saved before they're carried over
+                    if let Err(e) = cs.calc.save_laps() {
+                        warn!("failed to save laps at the end of the session {:?}", e);
+                    }
+                    self.prev_session = Some((cs.calc.config(), cs.calc.carry()));
                     self.state = None;
                 }
                 Err(e) => {
@@ -441,73 +1305,480 @@ impl Estimator {
         }
     }
 }
-fn strat_to_result(strat: &Strategy, result: &mut Estimation) {
-    result.save = strat.fuel_to_save;
-    if strat.stops.is_empty() {
-        result.next_stop = None;
-    } else {
-        result.next_stop = Some(*strat.stops.first().unwrap());
+// resets the estimation when there's no iRacing session, remembering if we'd previously
+// been connected so that the UI can show we're waiting for it rather than the planner.
+fn on_disconnect(result: &mut Estimation) {
+    let reconnecting = result.connected || result.reconnecting;
+    *result = Estimation::default();
+    result.reconnecting = reconnecting;
+}
+fn on_connect(result: &mut Estimation) {
+    result.connected = true;
+    result.reconnecting = false;
+}
+// the amount of fuel to add at the next pitstop to get to the end of the race, with
+// the extra laps/fuel from settings. Rounded based on the fuel_round setting, 0 if no fuel is needed.
+fn fuel_to_add(strat: &Strategy, fuel_level: f32, tank_size: f32, settings: &UserSettings) -> f32 {
+    let needed = strat.total_fuel() - fuel_level;
+    let add = match settings.refuel_policy {
+        RefuelPolicy::Planned => needed,
+        RefuelPolicy::BufferLaps => {
+            needed
+                + (settings
+                    .extra_fuel
+                    .max(strat.green.fuel * settings.extra_laps))
+        }
+        RefuelPolicy::FillTank if needed > 0.0 => tank_size - fuel_level,
+        RefuelPolicy::FillTank => 0.0,
+    };
+    settings.fuel_round.round(add.max(0.0))
+}
+// the restart is coming up, the one to green signal has just been shown.
+fn one_to_green(last: &IRacingTelemetryRow, this: &IRacingTelemetryRow) -> bool {
+    this.lap_state().intersects(LapState::ONE_TO_GREEN)
+        && !last.lap_state().intersects(LapState::ONE_TO_GREEN)
+}
+// the opening fuel load is sent once, when we're in the car before the race starts.
+fn warmup_fuel_due(sent: bool, this: &IRacingTelemetryRow) -> bool {
+    !sent
+        && matches!(
+            this.session_state,
+            SessionState::GetInCar | SessionState::Warmup
+        )
+        && this.player_track_surface != TrackLocation::NotInWorld
+}
+// the fuel to add at a pitstop using the latest strategy, or a full tank if there isn't
+// one yet, or it's only from the provisional rate.
+fn pit_fuel(
+    calc: &History,
+    trigger: &str,
+    this: &IRacingTelemetryRow,
+    adj: &Adjustments,
+    settings: &UserSettings,
+) -> f32 {
+    match calc.strat(this.fuel_level, adj, this.ends()) {
+        Some(x) if !calc.is_provisional() => {
+            log_strat(trigger, this.fuel_level, this.ends(), &x);
+            fuel_to_add(&x, this.fuel_level, calc.config().fuel_tank_size, settings)
+        }
+        _ => settings.fuel_round.round(calc.config().fuel_tank_size),
     }
-    result.stops = strat.stops.len() as i32;
-    result.green = strat.green;
-    result.race.laps = strat.total_laps() as f32;
-    result.race.fuel = strat.total_fuel();
-    result.race.time = strat.total_time();
-    result.save_target = strat.fuel_target();
 }
+// the wall clock time that the race will finish, given the race time left.
+fn projected_finish(now: DateTime<Local>, race_time: TimeSpan) -> DateTime<Local> {
+    now + chrono::Duration::milliseconds((race_time.as_secs_f64() * 1000.0) as i64)
+}
+// the wall clock time a lap limited race will finish, from the laps to go at the green lap time.
+// Races with a time limit finish when the race time runs out.
+fn estimated_finish(
+    now: DateTime<Local>,
+    ends: EndsWith,
+    green: Rate,
+    race_time: TimeSpan,
+) -> DateTime<Local> {
+    match ends {
+        EndsWith::Laps(l) => projected_finish(now, green.time * l.max(0) as u32),
+        _ => projected_finish(now, race_time),
+    }
+}
+// how many laps a different stop count has to persist for before it's shown.
+const STOPS_STABLE_LAPS: i32 = 2;
 
-#[derive(Clone, Copy, Debug)]
-struct IRacingTelemetryRow {
-    session_num: i32,
-    session_time: f64,
-    is_on_track: bool,
-    player_track_surface: TrackLocation,
-    session_state: SessionState,
-    session_flags: Flags,
-    session_time_remain: f64,
-    session_laps_remain: i32,
-    session_time_total: f64,
-    session_laps_total: i32,
-    lap: i32,
-    lap_completed: i32,
-    race_laps: i32,
-    fuel_level: f32,
-    lap_progress: f32,
-    track_temp: f32,
+// Smooths the stop count shown on the dash. Near a boundary the number of stops can flip
+// between 2 values from lap to lap, so a new count is only shown once it's been the
+// count for STOPS_STABLE_LAPS laps in a row.
+#[derive(Debug)]
+struct StopCount {
+    shown: Option<i32>,
+    candidate: i32,
+    laps: i32, // how many laps in a row candidate has been the count
 }
-impl IRacingTelemetryRow {
-    fn ends(&self) -> EndsWith {
-        let (tm, laps) = match self.session_state {
-            SessionState::Warmup | SessionState::ParadeLaps => {
-                (self.session_time_total, self.session_laps_total)
-            }
-            _ => (self.session_time_remain, self.session_laps_remain),
-        };
-        // TODO deal with practice better
-        if tm == ir::IRSDK_UNLIMITED_TIME {
-            if laps == ir::IRSDK_UNLIMITED_LAPS {
-                EndsWith::Time(TimeSpan::from_secs_f64(
-                    (30.0 * 60.0 - self.session_time).max(0.0),
-                ))
-            } else {
-                EndsWith::Laps(laps)
-            }
-        } else if laps == ir::IRSDK_UNLIMITED_LAPS {
-            EndsWith::Time(TimeSpan::from_secs_f64(tm.max(0.0)))
-        } else {
-            EndsWith::LapsOrTime(laps, TimeSpan::from_secs_f64(tm.max(0.0)))
+impl StopCount {
+    fn new() -> StopCount {
+        StopCount {
+            shown: None,
+            candidate: 0,
+            laps: 0,
         }
     }
-    fn lap_state(&self) -> LapState {
-        let mut s = LapState::empty();
-        let f = self.session_flags;
-        if f.intersects(
-            Flags::YELLOW | Flags::YELLOW_WAVING | Flags::CAUTION_WAVING | Flags::CAUTION,
-        ) {
-            s |= LapState::YELLOW
+    // the stop count from a lap's strategy, returns the count to show.
+    fn update(&mut self, stops: i32) -> i32 {
+        match self.shown {
+            Some(s) if s != stops => {
+                if self.laps > 0 && self.candidate == stops {
+                    self.laps += 1;
+                } else {
+                    self.candidate = stops;
+                    self.laps = 1;
+                }
+                if self.laps >= STOPS_STABLE_LAPS {
+                    self.reset(stops);
+                }
+            }
+            _ => self.reset(stops),
         }
-        if self.player_track_surface == TrackLocation::ApproachingPits
-            || self.player_track_surface == TrackLocation::InPitStall
+        self.shown.unwrap()
+    }
+    // shows this count straight away, e.g. after a pitstop the count is expected to change.
+    fn reset(&mut self, stops: i32) {
+        self.shown = Some(stops);
+        self.laps = 0;
+    }
+    fn is_stable(&self) -> bool {
+        self.laps == 0
+    }
+}
+
+// the strategy to show for a lap's strategy. Until a new stop count has stuck the strategy shown
+// before is kept, run on to this lap, so the stops, windows and fuel shown all agree.
+fn smoothed_strat(
+    count: &mut StopCount,
+    shown: &mut Option<(Strategy, i32)>,
+    strat: Strategy,
+    lap: i32,
+) -> Strategy {
+    let stops = strat.planned_stops().len() as i32;
+    match shown {
+        Some((s, start)) if count.update(stops) != stops => s.after_laps(lap - *start),
+        _ => {
+            count.reset(stops);
+            *shown = Some((strat.clone(), lap));
+            strat
+        }
+    }
+}
+// counts the updates in a row that we've been approaching the pits, returns the new count
+// and true once it reaches ticks_needed. Some tracks briefly flag approaching the pits
+// when cutting across pit entry, this stops that from sending the pit commands.
+fn pit_approach(ticks: u32, this: &IRacingTelemetryRow, ticks_needed: u32) -> (u32, bool) {
+    if this.player_track_surface == TrackLocation::ApproachingPits {
+        let ticks = ticks.saturating_add(1);
+        (ticks, ticks == ticks_needed.max(1))
+    } else {
+        (0, false)
+    }
+}
+// once we're inside an open pit window we're committed to stopping. This stays set until we leave
+// the pit box, so the stop isn't dropped because one lap's fuel reading came in low. latched is
+// the stop we're already committed to and the stop committed to is returned. If its window
+// closes without us stopping, the stop wasn't needed after all.
+fn pit_commit(
+    latched: Option<RaceStop>,
+    next_stop: Option<RaceStop>,
+    lap: i32,
+    last: &IRacingTelemetryRow,
+    this: &IRacingTelemetryRow,
+) -> Option<RaceStop> {
+    if last.player_track_surface == TrackLocation::InPitStall
+        && this.player_track_surface != TrackLocation::InPitStall
+    {
+        return None;
+    }
+    match next_stop {
+        Some(s) if s.is_open(lap) && lap <= s.close => Some(s),
+        _ => latched.filter(|s| lap <= s.close),
+    }
+}
+// the fuel level at the start of the current stint, a new stint starts when we leave the pit box.
+fn stint_start_fuel(
+    stint_start: f32,
+    last: &IRacingTelemetryRow,
+    this: &IRacingTelemetryRow,
+) -> f32 {
+    if last.player_track_surface == TrackLocation::InPitStall
+        && this.player_track_surface != TrackLocation::InPitStall
+    {
+        this.fuel_level
+    } else {
+        stint_start
+    }
+}
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct PitVisit {
+    entered: f64,  // session time we entered pit road
+    progress: f32, // how far round the lap we were when we entered pit road
+    stopped: bool, // we stopped in our pit box
+}
+
+// tracks a trip down pit road, returning the time spent in the pit lane and how much of a lap
+// it covered when we rejoin the track after stopping in the pit box. Drive throughs aren't pitstops.
+fn pit_visit(
+    visit: Option<PitVisit>,
+    last: &IRacingTelemetryRow,
+    this: &IRacingTelemetryRow,
+) -> (Option<PitVisit>, Option<(TimeSpan, f32)>) {
+    match visit {
+        None if this.on_pit_road() && !last.on_pit_road() => (
+            Some(PitVisit {
+                entered: this.session_time,
+                progress: this.lap_progress,
+                stopped: this.player_track_surface == TrackLocation::InPitStall,
+            }),
+            None,
+        ),
+        None => (None, None),
+        Some(v) if this.on_pit_road() => (
+            Some(PitVisit {
+                stopped: v.stopped || this.player_track_surface == TrackLocation::InPitStall,
+                ..v
+            }),
+            None,
+        ),
+        Some(v) if v.stopped && this.player_track_surface == TrackLocation::OnTrack => (
+            None,
+            Some((
+                TimeSpan::from_secs_f64(this.session_time - v.entered),
+                (this.lap_progress - v.progress).rem_euclid(1.0),
+            )),
+        ),
+        Some(_) => (None, None),
+    }
+}
+// the time lost to a pitstop, the time spent in the pit lane less the time it takes to cover the
+// same part of a lap at the green rate. None if there's no green rate yet.
+fn pit_loss(lane_time: TimeSpan, lane_laps: f32, green: Rate) -> Option<TimeSpan> {
+    if green.time > TimeSpan::ZERO {
+        let loss = lane_time.as_secs_f32() - lane_laps * green.time.as_secs_f32();
+        Some(TimeSpan::from_secs_f32(loss.max(0.0)))
+    } else {
+        None
+    }
+}
+// the lap number we'd run out of fuel on if we don't pit, None if there's no fuel rate yet.
+fn dry_lap(lap_completed: i32, fuel_level: f32, fuel_per_lap: f32) -> Option<i32> {
+    if fuel_per_lap > 0.0 {
+        Some(lap_completed + (fuel_level / fuel_per_lap).floor() as i32)
+    } else {
+        None
+    }
+}
+// the fuel in the car won't last until the next pit window opens, so we have to stop early.
+fn pit_critical(car_laps: f32, next_stop: Option<RaceStop>, lap: i32) -> bool {
+    next_stop.is_some_and(|s| car_laps < (s.open - lap) as f32)
+}
+// the laps to go in a timed race, at the green rate. None if there's no green rate yet.
+fn timed_race_laps(time_left: TimeSpan, green: Rate) -> Option<f32> {
+    if green.time > TimeSpan::ZERO {
+        Some(time_left.as_secs_f32() / green.time.as_secs_f32())
+    } else {
+        None
+    }
+}
+// the laps or time to go changed by more than the race running on would, e.g. the race
+// was shortened.
+fn race_length_changed(last: &IRacingTelemetryRow, this: &IRacingTelemetryRow) -> bool {
+    let elapsed = this.session_time - last.session_time;
+    if last.session_state != SessionState::Racing
+        || this.session_state != SessionState::Racing
+        || elapsed < 0.0
+    {
+        return false;
+    }
+    let laps = last.session_laps_remain != ir::IRSDK_UNLIMITED_LAPS
+        && this.session_laps_remain != ir::IRSDK_UNLIMITED_LAPS
+        && !(last.session_laps_remain - 1..=last.session_laps_remain)
+            .contains(&this.session_laps_remain);
+    // allow some slack for the time remaining and session time not being exactly in step
+    let time = last.session_time_remain != ir::IRSDK_UNLIMITED_TIME
+        && this.session_time_remain != ir::IRSDK_UNLIMITED_TIME
+        && (this.session_time_remain - (last.session_time_remain - elapsed)).abs() > 5.0;
+    laps || time
+}
+// the caution is a chance to pit before the leaders do, if the pit window opens within
+// the next early_laps laps anyway.
+fn tactical_pit_now(yellow: bool, next_stop: Option<RaceStop>, lap: i32, early_laps: i32) -> bool {
+    yellow && early_laps > 0 && next_stop.is_some_and(|s| s.open - lap <= early_laps)
+}
+// there's a stop still to make and not enough fuel to get round to the pits, we're about to
+// run dry. The pit entry isn't known, so it's taken to be at the start/finish line. Once on
+// pit road we've made it.
+fn fuel_emergency(car_laps: f32, this: &IRacingTelemetryRow, next_stop: Option<RaceStop>) -> bool {
+    next_stop.is_some() && !this.on_pit_road() && car_laps < 1.0 - this.lap_progress
+}
+
+// the pit commands to send as we approach the pits, the tire commands based on the
+// settings, followed by the fuel to add, or clearing the fuel if none is needed.
+fn pit_commands(settings: &UserSettings, liters: i16) -> Vec<PitCommand> {
+    let mut cmds = Vec::with_capacity(5);
+    if settings.leave_tires {
+        // leave the tires alone
+    } else if settings.clear_tires {
+        cmds.push(PitCommand::ClearTires);
+    } else if settings.take_tires {
+        cmds.extend([
+            PitCommand::LF(None),
+            PitCommand::RF(None),
+            PitCommand::LR(None),
+            PitCommand::RR(None),
+        ]);
+    }
+    if liters > 0 {
+        cmds.push(PitCommand::Fuel(Some(liters)));
+    } else {
+        cmds.push(PitCommand::ClearFuel);
+    }
+    cmds
+}
+
+// the pit command only takes whole liters, an exact amount is sent as the nearest liter.
+fn fuel_liters(fuel: f32) -> i16 {
+    fuel.round() as i16
+}
+
+// the adjustments to the strategy from settings, the dash and the session state.
+fn adjustments(
+    settings: &UserSettings,
+    result: &Estimation,
+    this: &IRacingTelemetryRow,
+) -> Adjustments {
+    Adjustments {
+        max_fuel_save: Some(result.max_fuel_save.unwrap_or(settings.max_fuel_save)),
+        // None uses the car specific default from the session
+        min_fuel: settings.min_fuel,
+        laps_behind_leader: Some(settings.laps_behind_leader),
+        cooldown_laps: Some(settings.cooldown_laps),
+        finish_reserve: Some(settings.finish_reserve),
+        fuel_use_margin: Some(settings.fuel_use_margin),
+        // at one to green the restart is at the end of this lap, there's no more caution laps
+        yellow_togo: if this.lap_state().intersects(LapState::ONE_TO_GREEN) {
+            Some(0)
+        } else {
+            None
+        },
+        // the race laps don't start until after the pace lap
+        pre_green_laps: if this.session_state == SessionState::ParadeLaps {
+            Some(1)
+        } else {
+            None
+        },
+    }
+}
+
+// logs the inputs & outputs of a strategy recompute, these only happen at specific
+// points in the lap so this won't flood the log.
+fn log_strat(trigger: &str, fuel_left: f32, ends: EndsWith, strat: &Strategy) {
+    info!(
+        "strat [{}] fuel_left: {:.2} ends: {:?} stops: {:?} save: {:.2}",
+        trigger, fuel_left, ends, strat.stops, strat.fuel_to_save
+    );
+}
+
+// a guess at the green rate from how many laps a full tank is expected to last, and iRacing's
+// estimated lap time for the car. None if either is unknown.
+fn provisional_rate(tank_size: f32, tank_laps: f32, est_lap_time: f64) -> Option<Rate> {
+    if tank_size > 0.0 && tank_laps > 0.0 && est_lap_time > 0.0 {
+        Some(Rate {
+            fuel: tank_size / tank_laps,
+            time: TimeSpan::from_secs_f64(est_lap_time),
+        })
+    } else {
+        None
+    }
+}
+
+// the strategy was computed at the end of race lap lap, its stops are shown in race laps.
+fn strat_to_result(
+    strat: &Strategy,
+    lap: i32,
+    fuel_level: f32,
+    tank_size: f32,
+    settings: &UserSettings,
+    result: &mut Estimation,
+) {
+    result.save = strat.fuel_to_save;
+    // the plan is to save, so the stop that saves isn't one to make
+    let stops = strat.planned_stops();
+    result.next_stop = stops.first().map(|s| s.to_race_laps(lap));
+    result.stops = stops.len() as i32;
+    result.all_stops = stops.iter().map(|s| s.to_race_laps(lap)).collect();
+    result.stints = strat.stints.clone();
+    result.green = strat.green;
+    result.race.laps = strat.total_laps() as f32;
+    result.race.fuel = strat.total_fuel();
+    result.race.time = strat.total_time();
+    result.save_target = strat.fuel_target();
+    result.save_achievable = strat
+        .green
+        .can_save_to(result.save_target, settings.max_realistic_save);
+    result.break_even = strat.break_even_target(fuel_level, 0);
+    result.break_even_achievable = result
+        .break_even
+        .is_none_or(|t| strat.green.can_save_to(t, settings.max_realistic_save));
+    result.fuel_to_add = fuel_liters(fuel_to_add(strat, fuel_level, tank_size, settings));
+}
+
+#[derive(Clone, Copy, Debug)]
+struct IRacingTelemetryRow {
+    session_num: i32,
+    session_time: f64,
+    is_on_track: bool,
+    player_track_surface: TrackLocation,
+    session_state: SessionState,
+    session_flags: Flags,
+    session_time_remain: f64,
+    session_laps_remain: i32,
+    session_time_total: f64,
+    session_laps_total: i32,
+    lap: i32,
+    lap_completed: i32,
+    race_laps: i32,
+    fuel_level: f32,
+    lap_progress: f32,
+    track_temp: f32,
+    my_incidents: Option<i32>,
+    team_incidents: Option<i32>,
+}
+impl IRacingTelemetryRow {
+    // in team races the limit applies to the team's count, for solo races they're the same.
+    // older sdk versions might not have the incident vars at all.
+    fn incidents(&self) -> i32 {
+        self.team_incidents.or(self.my_incidents).unwrap_or(0)
+    }
+    fn on_pit_road(&self) -> bool {
+        self.player_track_surface == TrackLocation::ApproachingPits
+            || self.player_track_surface == TrackLocation::InPitStall
+    }
+    fn is_last_lap(&self) -> bool {
+        self.session_flags.intersects(Flags::WHITE)
+    }
+    // the checkered flag is out, or we're on the cooldown lap after it.
+    fn is_finished(&self) -> bool {
+        self.session_state == SessionState::Checkered
+            || self.session_state == SessionState::CoolDown
+    }
+    fn ends(&self) -> EndsWith {
+        let (tm, laps) = match self.session_state {
+            SessionState::Warmup | SessionState::ParadeLaps => {
+                (self.session_time_total, self.session_laps_total)
+            }
+            _ => (self.session_time_remain, self.session_laps_remain),
+        };
+        // TODO deal with practice better
+        if tm == ir::IRSDK_UNLIMITED_TIME {
+            if laps == ir::IRSDK_UNLIMITED_LAPS {
+                EndsWith::Time(TimeSpan::from_secs_f64(
+                    (30.0 * 60.0 - self.session_time).max(0.0),
+                ))
+            } else {
+                EndsWith::Laps(laps)
+            }
+        } else if laps == ir::IRSDK_UNLIMITED_LAPS {
+            EndsWith::Time(TimeSpan::from_secs_f64(tm.max(0.0)))
+        } else {
+            EndsWith::LapsOrTime(laps, TimeSpan::from_secs_f64(tm.max(0.0)))
+        }
+    }
+    fn lap_state(&self) -> LapState {
+        let mut s = LapState::empty();
+        let f = self.session_flags;
+        if f.intersects(Flags::CAUTION_WAVING | Flags::CAUTION) {
+            s |= LapState::YELLOW
+        } else if f.intersects(Flags::YELLOW | Flags::YELLOW_WAVING) {
+            // a yellow for an incident on part of the track, not a full course caution
+            s |= LapState::LOCAL_YELLOW
+        }
+        if self.player_track_surface == TrackLocation::ApproachingPits
+            || self.player_track_surface == TrackLocation::InPitStall
         {
             s |= LapState::PITTED
         }
@@ -516,6 +1787,9 @@ impl IRacingTelemetryRow {
         {
             s |= LapState::PACE_LAP
         }
+        if f.intersects(Flags::BLUE) {
+            s |= LapState::BLUE
+        }
         if f.intersects(Flags::ONE_TO_GREEN) && s.intersects(LapState::YELLOW) {
             s |= LapState::ONE_TO_GREEN
         }
@@ -558,9 +1832,14 @@ struct TelemetryFactory {
     fuel_level: ir::Var,
     lap_progress: ir::Var,
     track_temp: ir::Var,
+    my_incidents: Option<ir::Var>,
+    team_incidents: Option<ir::Var>,
+    player_car_idx: Option<ir::Var>,
+    car_idx_lap_completed: Option<ir::Var>,
+    car_idx_on_pit_road: Option<ir::Var>,
 }
 impl TelemetryFactory {
-    fn new(c: &ir::Session) -> TelemetryFactory {
+    fn new(c: &ir::Session, track_temp: TrackTempSource) -> TelemetryFactory {
         unsafe {
             TelemetryFactory {
                 session_num: c.find_var("SessionNum").unwrap(),
@@ -578,7 +1857,15 @@ impl TelemetryFactory {
                 race_laps: c.find_var("RaceLaps").unwrap(),
                 fuel_level: c.find_var("FuelLevel").unwrap(),
                 lap_progress: c.find_var("LapDistPct").unwrap(),
-                track_temp: c.find_var("TrackTempCrew").unwrap(),
+                track_temp: c
+                    .find_var(track_temp.var_name())
+                    .or_else(|| c.find_var(track_temp.fallback().var_name()))
+                    .unwrap(),
+                my_incidents: c.find_var("PlayerCarMyIncidentCount"),
+                team_incidents: c.find_var("PlayerCarTeamIncidentCount"),
+                player_car_idx: c.find_var("PlayerCarIdx"),
+                car_idx_lap_completed: c.find_var("CarIdxLapCompleted"),
+                car_idx_on_pit_road: c.find_var("CarIdxOnPitRoad"),
             }
         }
     }
@@ -601,9 +1888,75 @@ impl TelemetryFactory {
                 fuel_level: c.value(&self.fuel_level)?,
                 lap_progress: c.value(&self.lap_progress)?,
                 track_temp: c.value(&self.track_temp)?,
+                my_incidents: self.my_incidents.as_ref().map(|v| c.value(v)).transpose()?,
+                team_incidents: self
+                    .team_incidents
+                    .as_ref()
+                    .map(|v| c.value(v))
+                    .transpose()?,
             })
         }
     }
+    // the per car arrays are only read when needed, they don't fit in the (Copy) telemetry row.
+    fn read_opponents(&self, c: &ir::Session) -> Result<Option<OpponentRow>, ir::Error> {
+        match (
+            &self.player_car_idx,
+            &self.car_idx_lap_completed,
+            &self.car_idx_on_pit_road,
+        ) {
+            (Some(player), Some(laps), Some(pit_road)) => unsafe {
+                Ok(Some(OpponentRow {
+                    player: c.value(player)?,
+                    lap_completed: c.value::<&[i32]>(laps)?.to_vec(),
+                    on_pit_road: c.value::<&[bool]>(pit_road)?.to_vec(),
+                }))
+            },
+            _ => Ok(None),
+        }
+    }
+}
+
+// the state of every car in the session, indexed by car idx.
+#[derive(Clone, Debug)]
+struct OpponentRow {
+    player: i32,             // our car idx
+    lap_completed: Vec<i32>, // -1 for car idxs that aren't in use
+    on_pit_road: Vec<bool>,
+}
+
+// tracks the lap each car last left pit road on, cars start the race with a full tank.
+fn opponent_stint_starts(starts: &mut Vec<i32>, opp: &OpponentRow) {
+    starts.resize(opp.lap_completed.len(), 0);
+    for (i, (lap, pit_road)) in opp.lap_completed.iter().zip(&opp.on_pit_road).enumerate() {
+        if *pit_road {
+            starts[i] = (*lap).max(0);
+        }
+    }
+}
+
+// how many more laps of fuel we have than the average opponent, assuming they use fuel at
+// our rate and filled up when they last left pit road. None if there are no opponents.
+fn opponent_fuel_margin(
+    my_laps: f32,
+    tank_laps: f32,
+    starts: &[i32],
+    opp: &OpponentRow,
+) -> Option<f32> {
+    let (count, total) = opp
+        .lap_completed
+        .iter()
+        .zip(starts)
+        .enumerate()
+        .filter(|(i, (lap, _))| *i as i32 != opp.player && **lap >= 0)
+        .fold((0, 0.0), |(count, total), (_, (lap, start))| {
+            let laps_left = (tank_laps - (lap - start) as f32).max(0.0);
+            (count + 1, total + laps_left)
+        });
+    if count == 0 {
+        None
+    } else {
+        Some(my_laps - total / count as f32)
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -620,44 +1973,259 @@ struct IrSessionInfo {
     driver_car_max_fuel_pct: f64, // 0.050
     driver_car_est_lap_time: f64, // 24.1922
     // Drivers
-    car_id: i64,      // 120
-    car_name: String, // Indy Pro 2000 PM-18
+    car_id: i64,         // 120
+    car_name: String,    // Indy Pro 2000 PM-18
+    driver_user_id: i64, // 123456, the driver currently in our car
+    driver_name: String, // Test Driver
     // SessionInfo
     session_name: String, // QUALIFY
 }
 
+#[derive(Debug)]
+enum SessionInfoError {
+    Yaml(yaml_rust::ScanError),
+    Missing(&'static str), // a required field is missing or has the wrong type
+}
+impl From<yaml_rust::ScanError> for SessionInfoError {
+    fn from(e: yaml_rust::ScanError) -> Self {
+        SessionInfoError::Yaml(e)
+    }
+}
+fn yaml_str(y: &Yaml, field: &'static str) -> Result<String, SessionInfoError> {
+    y[field]
+        .as_str()
+        .map(|s| s.to_string())
+        .ok_or(SessionInfoError::Missing(field))
+}
+fn yaml_i64(y: &Yaml, field: &'static str) -> Result<i64, SessionInfoError> {
+    y[field].as_i64().ok_or(SessionInfoError::Missing(field))
+}
+fn yaml_f64(y: &Yaml, field: &'static str) -> Result<f64, SessionInfoError> {
+    y[field].as_f64().ok_or(SessionInfoError::Missing(field))
+}
+
+// the free text fields in the session info. iRacing writes them without any quoting, so a
+// name with yaml syntax in it, e.g. "Team: Fast" or "*Joe", stops the whole document parsing.
+const SESSION_INFO_NAMES: [&str; 10] = [
+    "UserName",
+    "TeamName",
+    "AbbrevName",
+    "Initials",
+    "DriverSetupName",
+    "CarScreenName",
+    "TrackDisplayName",
+    "TrackDisplayShortName",
+    "TrackConfigName",
+    "TrackCity",
+];
+// makes the session info (decoded from Windows-1252) safe to parse as yaml. Control characters,
+// which yaml doesn't allow, are dropped, and the free text name fields are quoted.
+fn sanitize_session_info(session_info: &str) -> String {
+    session_info
+        .lines()
+        .map(|line| {
+            let line: String = line
+                .chars()
+                .filter(|c| !c.is_control() || *c == '\t')
+                .collect();
+            match line.split_once(": ") {
+                Some((key, value))
+                    if SESSION_INFO_NAMES.contains(&key.trim_start_matches([' ', '-']))
+                        && !value.trim().is_empty()
+                        && !value.starts_with(['\'', '"']) =>
+                {
+                    format!("{}: '{}'", key, value.trim_end().replace('\'', "''"))
+                }
+                _ => line,
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
 impl IrSessionInfo {
-    fn parse(session_info: &str, session_num: i32) -> IrSessionInfo {
-        let yamls = yaml_rust::YamlLoader::load_from_str(session_info).unwrap(); // TODO
-        let si = &yamls[0];
+    fn parse(session_info: &str, session_num: i32) -> Result<IrSessionInfo, SessionInfoError> {
+        let session_info = sanitize_session_info(session_info);
+        let yamls = yaml_rust::YamlLoader::load_from_str(&session_info)?;
+        let si = yamls.first().ok_or(SessionInfoError::Missing("document"))?;
         let di = &si["DriverInfo"];
         let wi = &si["WeekendInfo"];
-        let driver = &di["Drivers"][di["DriverCarIdx"].as_i64().unwrap() as usize];
+        let driver = &di["Drivers"][yaml_i64(di, "DriverCarIdx")? as usize];
         let sessions = &si["SessionInfo"]["Sessions"];
-        IrSessionInfo {
-            track_id: wi["TrackID"].as_i64().unwrap(),
-            track_display_name: wi["TrackDisplayName"].as_str().unwrap().to_string(),
-            track_display_short_name: wi["TrackDisplayShortName"].as_str().unwrap().to_string(),
+        Ok(IrSessionInfo {
+            track_id: yaml_i64(wi, "TrackID")?,
+            track_display_name: yaml_str(wi, "TrackDisplayName")?,
+            track_display_short_name: yaml_str(wi, "TrackDisplayShortName")?,
             // TrackConfigName doesn't appear for tracks that don't have multiple configs
             track_config_name: wi["TrackConfigName"].as_str().unwrap_or("").to_string(),
-            event_type: wi["EventType"].as_str().unwrap().to_string(),
-            category: wi["Category"].as_str().unwrap().to_string(),
-            driver_car_fuel_max_ltr: di["DriverCarFuelMaxLtr"].as_f64().unwrap(),
-            driver_car_max_fuel_pct: di["DriverCarMaxFuelPct"].as_f64().unwrap(),
-            driver_car_est_lap_time: di["DriverCarEstLapTime"].as_f64().unwrap(),
-            car_id: driver["CarID"].as_i64().unwrap(),
-            car_name: driver["CarScreenName"].as_str().unwrap().to_string(),
-            session_name: sessions[session_num as usize]["SessionName"]
-                .as_str()
-                .unwrap()
-                .to_string(),
-        }
+            event_type: yaml_str(wi, "EventType")?,
+            category: yaml_str(wi, "Category")?,
+            driver_car_fuel_max_ltr: yaml_f64(di, "DriverCarFuelMaxLtr")?,
+            driver_car_max_fuel_pct: yaml_f64(di, "DriverCarMaxFuelPct")?,
+            driver_car_est_lap_time: yaml_f64(di, "DriverCarEstLapTime")?,
+            car_id: yaml_i64(driver, "CarID")?,
+            car_name: yaml_str(driver, "CarScreenName")?,
+            driver_user_id: yaml_i64(driver, "UserID")?,
+            driver_name: yaml_str(driver, "UserName")?,
+            session_name: yaml_str(&sessions[session_num as usize], "SessionName")?,
+        })
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::SessionProgress;
+    use super::{
+        adjustments, default_laps_db, default_min_fuel, dry_lap, estimated_finish, fuel_emergency,
+        fuel_liters, fuel_to_add, on_connect, on_disconnect, one_to_green, opponent_fuel_margin,
+        opponent_stint_starts, pit_approach, pit_commands, pit_commit, pit_critical, pit_fuel,
+        pit_loss, pit_visit, projected_finish, provisional_rate, race_length_changed,
+        sanitize_session_info, smoothed_strat, stint_start_fuel, strat_to_result, tactical_pit_now,
+        timed_race_laps, warmup_fuel_due, AmountLeft, BlackFlagKind, Error, Estimation, Estimator,
+        Flags, FuelRound, IRacingTelemetryRow, IrSessionInfo, JsonLoadError, OpponentRow, PitCall,
+        PitCommand, RaceStop, RefuelPolicy, SessionInfoError, SessionProgress, SessionState,
+        StopCount, TelemetrySession, TelemetrySource, TempUnit, TrackLocation, TrackTempSource,
+        UserSettings,
+    };
+    use crate::history::tests::cfg;
+    use crate::history::{History, RaceSession};
+    use crate::strat::{
+        EndsWith, Lap, LapState, Rate, StratRequest, StratRequestBuilder, TimeSpan,
+    };
+    use chrono::{DateTime, Local};
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::path::PathBuf;
+    use std::rc::Rc;
+
+    // 3.2L left in a 10L tank with 10 laps to go at 0.5L a lap.
+    fn request() -> StratRequest {
+        StratRequestBuilder::new(
+            10.0,
+            EndsWith::Laps(10),
+            Rate {
+                fuel: 0.5,
+                time: TimeSpan::new(30, 0),
+            },
+        )
+        .fuel_left(3.2)
+        .build()
+    }
+    fn racing_row() -> IRacingTelemetryRow {
+        IRacingTelemetryRow {
+            session_num: 0,
+            session_time: 100.0,
+            is_on_track: true,
+            player_track_surface: TrackLocation::OnTrack,
+            session_state: SessionState::Racing,
+            session_flags: Flags::GREEN,
+            session_time_remain: 1000.0,
+            session_laps_remain: 20,
+            session_time_total: 1100.0,
+            session_laps_total: 25,
+            lap: 5,
+            lap_completed: 4,
+            race_laps: 4,
+            fuel_level: 10.0,
+            lap_progress: 0.5,
+            track_temp: 30.0,
+            my_incidents: None,
+            team_incidents: None,
+        }
+    }
+
+    #[test]
+    fn test_lap_state() {
+        let mut r = racing_row();
+        assert_eq!(LapState::empty(), r.lap_state());
+        r.session_flags = Flags::YELLOW;
+        assert_eq!(LapState::LOCAL_YELLOW, r.lap_state());
+        assert!(r.lap_state().is_green());
+        r.session_flags = Flags::YELLOW | Flags::CAUTION;
+        assert_eq!(LapState::YELLOW, r.lap_state());
+        r.session_flags = Flags::CAUTION_WAVING | Flags::ONE_TO_GREEN;
+        assert_eq!(LapState::YELLOW | LapState::ONE_TO_GREEN, r.lap_state());
+        r.session_flags = Flags::GREEN;
+        r.player_track_surface = TrackLocation::InPitStall;
+        assert_eq!(LapState::PITTED, r.lap_state());
+        assert!(!r.lap_state().is_green());
+        r.session_flags = Flags::BLUE;
+        r.player_track_surface = TrackLocation::OnTrack;
+        assert_eq!(LapState::BLUE, r.lap_state());
+        assert!(r.lap_state().is_green());
+    }
+
+    #[test]
+    fn test_fuel_to_add() {
+        let r = request();
+        let strat = r.compute().unwrap();
+        let settings = UserSettings::default();
+        // 5.0L for the race, 3.2 in the car, +1.0 for extra laps/fuel
+        // rounded up to the next liter
+        assert_eq!(3.0, fuel_to_add(&strat, 3.2, 10.0, &settings));
+        let settings = UserSettings {
+            extra_laps: 4.0,
+            ..UserSettings::default()
+        };
+        assert_eq!(4.0, fuel_to_add(&strat, 3.2, 10.0, &settings));
+        // already got enough to finish
+        assert_eq!(0.0, fuel_to_add(&strat, 8.0, 10.0, &settings));
+    }
+
+    #[test]
+    fn test_fuel_round() {
+        let r = request();
+        let strat = r.compute().unwrap();
+        let mut settings = UserSettings::default();
+        // 5.0L for the race, 3.2 in the car, +1.0 for extra laps/fuel = 2.8
+        assert_eq!(FuelRound::Ceil, settings.fuel_round);
+        assert_eq!(3.0, fuel_to_add(&strat, 3.2, 10.0, &settings));
+        assert_eq!(3.0, fuel_to_add(&strat, 3.9, 10.0, &settings));
+        settings.fuel_round = FuelRound::Nearest;
+        assert_eq!(3.0, fuel_to_add(&strat, 3.2, 10.0, &settings));
+        assert_eq!(2.0, fuel_to_add(&strat, 3.9, 10.0, &settings));
+        settings.fuel_round = FuelRound::Exact;
+        assert!((fuel_to_add(&strat, 3.9, 10.0, &settings) - 2.1).abs() < 0.0001);
+        assert_eq!(0.0, fuel_to_add(&strat, 7.0, 10.0, &settings));
+        // exact amounts are sent to the car as the nearest liter
+        assert!(matches!(
+            pit_commands(&settings, fuel_liters(2.1))[..],
+            [PitCommand::Fuel(Some(2))]
+        ));
+        assert!(matches!(
+            pit_commands(&settings, fuel_liters(0.4))[..],
+            [PitCommand::ClearFuel]
+        ));
+    }
+
+    #[test]
+    fn test_black_flag() {
+        assert_eq!(None, BlackFlagKind::from_flags(Flags::empty()));
+        assert_eq!(
+            None,
+            BlackFlagKind::from_flags(Flags::GREEN | Flags::YELLOW | Flags::BLUE)
+        );
+        assert_eq!(
+            Some(BlackFlagKind::Black),
+            BlackFlagKind::from_flags(Flags::BLACK)
+        );
+        assert_eq!(
+            Some(BlackFlagKind::Disqualify),
+            BlackFlagKind::from_flags(Flags::DISQUALIFY)
+        );
+        assert_eq!(
+            Some(BlackFlagKind::Repair),
+            BlackFlagKind::from_flags(Flags::REPAIR)
+        );
+        // the most severe flag wins
+        assert_eq!(
+            Some(BlackFlagKind::Black),
+            BlackFlagKind::from_flags(Flags::BLACK | Flags::REPAIR)
+        );
+        assert_eq!(
+            Some(BlackFlagKind::Disqualify),
+            BlackFlagKind::from_flags(Flags::DISQUALIFY | Flags::BLACK | Flags::GREEN)
+        );
+    }
 
     #[test]
     fn test_interopolate_tm() {
@@ -670,4 +2238,1426 @@ mod tests {
         let tm3 = SessionProgress::interpolate_checkpoint_time(0.99, 112.1, 0.02, 112.4, 0.0);
         assert!(f64::abs(tm3.as_secs_f64() - 112.2) < 0.0001);
     }
+    #[test]
+    fn test_interpolate_tm_edges() {
+        let near = |tm: TimeSpan, exp: f64| (tm.as_secs_f64() - exp).abs() < 0.0001;
+        // right on either side of the line
+        let tm = SessionProgress::interpolate_checkpoint_time(0.999, 100.0, 0.001, 100.2, 0.0);
+        assert!(near(tm, 100.1), "{:?}", tm);
+        let tm = SessionProgress::interpolate_checkpoint_time(0.9, 100.0, 0.1, 100.2, 0.05);
+        assert!(near(tm, 100.15), "{:?}", tm);
+        // a checkpoint part way round the lap, no wrap
+        let tm = SessionProgress::interpolate_checkpoint_time(0.4, 10.0, 0.6, 12.0, 0.5);
+        assert!(near(tm, 11.0), "{:?}", tm);
+        let tm = SessionProgress::interpolate_checkpoint_time(0.4, 10.0, 0.6, 12.0, 0.45);
+        assert!(near(tm, 10.5), "{:?}", tm);
+        // no movement between the samples
+        let tm = SessionProgress::interpolate_checkpoint_time(0.5, 10.0, 0.5, 10.1, 0.5);
+        assert!(near(tm, 10.1), "{:?}", tm);
+        let tm = SessionProgress::interpolate_checkpoint_time(0.0, 10.0, 0.0, 10.1, 0.0);
+        assert!(near(tm, 10.1), "{:?}", tm);
+    }
+
+    #[test]
+    fn test_laps_db_path() {
+        let settings = UserSettings::default();
+        assert_eq!(default_laps_db(), settings.laps_db());
+
+        let f = std::env::temp_dir().join(format!("naf_calc_custom_{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&f);
+        let settings = UserSettings {
+            laps_db_path: Some(f.clone()),
+            ..UserSettings::default()
+        };
+        assert_eq!(Some(f.clone()), settings.laps_db());
+        let json = serde_json::to_string(&settings).unwrap();
+        let loaded: UserSettings = serde_json::from_str(&json).unwrap();
+        assert_eq!(Some(f.clone()), loaded.laps_db());
+
+        let cfg = cfg();
+        let mut calc = History::new(cfg, loaded.laps_db());
+        calc.add_lap(Lap {
+            fuel_left: 5.0,
+            fuel_used: 1.0,
+            time: TimeSpan::new(30, 0),
+            condition: LapState::empty(),
+        });
+        calc.save_laps().unwrap();
+        drop(calc);
+        assert!(f.exists());
+        let _ = std::fs::remove_file(&f);
+    }
+
+    #[test]
+    fn test_dry_lap() {
+        assert_eq!(Some(25), dry_lap(5, 10.0, 0.5));
+        assert_eq!(Some(7), dry_lap(5, 1.2, 0.5));
+        assert_eq!(Some(5), dry_lap(5, 0.0, 0.5));
+        assert_eq!(None, dry_lap(5, 10.0, 0.0));
+    }
+
+    #[test]
+    fn test_session_info_oval() {
+        let si =
+            IrSessionInfo::parse(include_str!("../fixtures/session_info/oval.yaml"), 2).unwrap();
+        assert_eq!(419, si.track_id);
+        assert_eq!("Phoenix Raceway", si.track_display_name);
+        assert_eq!("Phoenix", si.track_display_short_name);
+        assert_eq!("Oval w/open dogleg", si.track_config_name);
+        assert_eq!("Race", si.event_type);
+        assert_eq!("Oval", si.category);
+        assert_eq!(40.0, si.driver_car_fuel_max_ltr);
+        assert_eq!(0.05, si.driver_car_max_fuel_pct);
+        assert_eq!(24.1922, si.driver_car_est_lap_time);
+        assert_eq!(120, si.car_id);
+        assert_eq!("Indy Pro 2000 PM-18", si.car_name);
+        assert_eq!("RACE", si.session_name);
+    }
+
+    #[test]
+    fn test_session_info_road() {
+        let si =
+            IrSessionInfo::parse(include_str!("../fixtures/session_info/road.yaml"), 0).unwrap();
+        assert_eq!(47, si.track_id);
+        assert_eq!("Laguna Seca", si.track_display_short_name);
+        // no TrackConfigName for single config tracks
+        assert_eq!("", si.track_config_name);
+        assert_eq!("Road", si.category);
+        assert_eq!(120.0, si.driver_car_fuel_max_ltr);
+        assert_eq!(1.0, si.driver_car_max_fuel_pct);
+        assert_eq!(132, si.car_id);
+        assert_eq!("BMW M4 GT3", si.car_name);
+        assert_eq!("TESTING", si.session_name);
+    }
+
+    #[test]
+    fn test_session_info_multiclass() {
+        let si = IrSessionInfo::parse(include_str!("../fixtures/session_info/multiclass.yaml"), 1)
+            .unwrap();
+        assert_eq!(192, si.track_id);
+        assert_eq!("Road Course", si.track_config_name);
+        // the driver's car, not the first one in the list
+        assert_eq!(128, si.car_id);
+        assert_eq!("Dallara P217", si.car_name);
+        assert_eq!(123456, si.driver_user_id);
+        assert_eq!("Test Driver", si.driver_name);
+        assert_eq!(100.0, si.driver_car_fuel_max_ltr);
+        assert_eq!(0.9, si.driver_car_max_fuel_pct);
+        assert_eq!("QUALIFY", si.session_name);
+    }
+
+    #[test]
+    fn test_session_info_errors() {
+        let oval = include_str!("../fixtures/session_info/oval.yaml");
+        assert!(matches!(
+            IrSessionInfo::parse(oval, 5),
+            Err(SessionInfoError::Missing("SessionName"))
+        ));
+        assert!(matches!(
+            IrSessionInfo::parse(&oval.replace("TrackID: 419", "TrackID: x"), 0),
+            Err(SessionInfoError::Missing("TrackID"))
+        ));
+        assert!(matches!(
+            IrSessionInfo::parse(&oval.replace(" DriverCarIdx: 0", " DriverCarIdx: 7"), 0),
+            Err(SessionInfoError::Missing("CarID"))
+        ));
+        assert!(matches!(
+            IrSessionInfo::parse("", 0),
+            Err(SessionInfoError::Missing("document"))
+        ));
+        assert!(matches!(
+            IrSessionInfo::parse("WeekendInfo: [", 0),
+            Err(SessionInfoError::Yaml(_))
+        ));
+    }
+    #[test]
+    fn test_session_info_names() {
+        let road = include_str!("../fixtures/session_info/road.yaml")
+            .replace(
+                "TrackDisplayName: WeatherTech Raceway at Laguna Seca",
+                "TrackDisplayName: Nürburgring Grand-Prix-Strecke",
+            )
+            .replace(
+                "CarScreenName: BMW M4 GT3",
+                "CarScreenName: BMW M4 GT3\u{81}",
+            )
+            .replace("UserName: Test Driver", "UserName: *Jörg: l'Écurie ");
+        let si = IrSessionInfo::parse(&road, 0).unwrap();
+        assert_eq!("Nürburgring Grand-Prix-Strecke", si.track_display_name);
+        assert_eq!("BMW M4 GT3", si.car_name);
+        assert_eq!(
+            "UserName: '*Jörg: l''Écurie'\nUserName: 'quoted'\nUserName:",
+            sanitize_session_info("UserName: *Jörg: l'Écurie \nUserName: 'quoted'\nUserName:")
+        );
+    }
+
+    #[test]
+    fn test_connection_state() {
+        let mut e = Estimation::default();
+        // never connected, so not waiting for a reconnect
+        on_disconnect(&mut e);
+        assert!(!e.connected);
+        assert!(!e.reconnecting);
+        on_connect(&mut e);
+        assert!(e.connected);
+        assert!(!e.reconnecting);
+        e.fuel_to_add = 5;
+        // iRacing goes away
+        on_disconnect(&mut e);
+        assert!(!e.connected);
+        assert!(e.reconnecting);
+        assert_eq!(0, e.fuel_to_add);
+        // still waiting on later ticks
+        on_disconnect(&mut e);
+        assert!(e.reconnecting);
+        on_connect(&mut e);
+        assert!(e.connected);
+        assert!(!e.reconnecting);
+        // the user can dismiss the reconnecting state
+        on_disconnect(&mut e);
+        e.reconnecting = false;
+        on_disconnect(&mut e);
+        assert!(!e.reconnecting);
+    }
+    #[test]
+    fn test_temp_unit() {
+        assert_eq!(25.0, TempUnit::C.convert(25.0));
+        assert_eq!(77.0, TempUnit::F.convert(25.0));
+        assert_eq!(32.0, TempUnit::F.convert(0.0));
+        assert_eq!(-40.0, TempUnit::F.convert(-40.0));
+        assert_eq!(-2.5, TempUnit::C.convert_delta(-2.5));
+        assert_eq!(1.8, TempUnit::F.convert_delta(1.0));
+        assert_eq!(-4.5, TempUnit::F.convert_delta(-2.5));
+        let json = serde_json::to_string(&UserSettings {
+            temp_unit: TempUnit::F,
+            ..UserSettings::default()
+        })
+        .unwrap();
+        let s: UserSettings = serde_json::from_str(&json).unwrap();
+        assert_eq!(TempUnit::F, s.temp_unit);
+    }
+    #[test]
+    fn test_fuel_save_override() {
+        let settings = UserSettings {
+            max_fuel_save: 0.0,
+            min_fuel: Some(0.0),
+            ..UserSettings::default()
+        };
+        let mut result = Estimation::default();
+        let row = racing_row();
+        let adj = adjustments(&settings, &result, &row);
+        assert_eq!(Some(0.0), adj.max_fuel_save);
+        let cfg = cfg();
+        let mut calc = History::new(cfg, None);
+        calc.set_baseline(
+            Rate {
+                fuel: 1.0,
+                time: TimeSpan::new(30, 0),
+            },
+            None,
+        );
+        let strat = calc.strat(10.0, &adj, EndsWith::Laps(21)).unwrap();
+        strat_to_result(&strat, 0, 10.0, 10.0, &settings, &mut result);
+        assert_eq!(2, result.stops);
+        assert_eq!(0.0, result.save);
+        // turning up the fuel save from the dash shows we can skip the last stop
+        result.max_fuel_save = Some(0.1);
+        let adj = adjustments(&settings, &result, &row);
+        assert_eq!(Some(0.1), adj.max_fuel_save);
+        let strat = calc.strat(10.0, &adj, EndsWith::Laps(21)).unwrap();
+        strat_to_result(&strat, 0, 10.0, 10.0, &settings, &mut result);
+        assert_eq!(1, result.stops);
+        assert_eq!(1, result.all_stops.len());
+        assert_eq!(1.0, result.save);
+        assert_eq!(0.95, result.save_target);
+        // and turning it back down puts the stop back
+        result.max_fuel_save = Some(0.0);
+        let adj = adjustments(&settings, &result, &row);
+        let strat = calc.strat(10.0, &adj, EndsWith::Laps(21)).unwrap();
+        strat_to_result(&strat, 0, 10.0, 10.0, &settings, &mut result);
+        assert_eq!(2, result.stops);
+    }
+    #[test]
+    fn test_one_to_green() {
+        let settings = UserSettings {
+            min_fuel: Some(0.0),
+            ..UserSettings::default()
+        };
+        let cfg = cfg();
+        let mut calc = History::new(cfg, None);
+        calc.set_baseline(
+            Rate {
+                fuel: 1.0,
+                time: TimeSpan::new(30, 0),
+            },
+            None,
+        );
+        calc.add_lap(Lap {
+            fuel_left: 10.0,
+            fuel_used: 0.3,
+            time: TimeSpan::new(90, 0),
+            condition: LapState::YELLOW,
+        });
+        let mut caution = racing_row();
+        caution.session_flags = Flags::CAUTION;
+        let adj = adjustments(&settings, &Estimation::default(), &caution);
+        assert_eq!(None, adj.yellow_togo);
+        // 2 more caution laps are guessed at
+        let strat = calc.strat(10.0, &adj, EndsWith::Laps(20)).unwrap();
+        assert!(strat.total_fuel() < 19.0);
+
+        let mut restart = caution;
+        restart.session_flags = Flags::CAUTION | Flags::ONE_TO_GREEN;
+        assert!(one_to_green(&caution, &restart));
+        assert!(!one_to_green(&restart, &restart));
+        assert!(!one_to_green(&restart, &racing_row()));
+        let adj = adjustments(&settings, &Estimation::default(), &restart);
+        assert_eq!(Some(0), adj.yellow_togo);
+        // every lap to go is a green lap
+        let strat = calc.strat(10.0, &adj, EndsWith::Laps(20)).unwrap();
+        assert_eq!(20.0, strat.total_fuel());
+        assert_eq!(vec![10, 10], strat.laps());
+    }
+    #[test]
+    fn test_pit_commands() {
+        let mut s = UserSettings::default();
+        assert!(matches!(
+            pit_commands(&s, 5)[..],
+            [PitCommand::Fuel(Some(5))]
+        ));
+        assert!(matches!(pit_commands(&s, 0)[..], [PitCommand::ClearFuel]));
+        s.take_tires = true;
+        assert!(matches!(
+            pit_commands(&s, 5)[..],
+            [
+                PitCommand::LF(None),
+                PitCommand::RF(None),
+                PitCommand::LR(None),
+                PitCommand::RR(None),
+                PitCommand::Fuel(Some(5))
+            ]
+        ));
+        s.clear_tires = true;
+        assert!(matches!(
+            pit_commands(&s, 0)[..],
+            [PitCommand::ClearTires, PitCommand::ClearFuel]
+        ));
+        s.leave_tires = true;
+        assert!(matches!(pit_commands(&s, 0)[..], [PitCommand::ClearFuel]));
+        assert!(matches!(
+            pit_commands(&s, 2)[..],
+            [PitCommand::Fuel(Some(2))]
+        ));
+    }
+    #[test]
+    fn test_on_session_change() {
+        let script = [racing_row(), racing_row()];
+        let mut f = Scripted::new("session_change", &script);
+        // no callback is fine
+        f.run(1);
+        assert!(f.result.connected);
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let s = seen.clone();
+        f.e.on_session_change = Some(Box::new(move |rs: &RaceSession| {
+            s.borrow_mut().push(rs.car_track());
+        }));
+        // it's only called when a session starts
+        f.run(1);
+        assert!(seen.borrow().is_empty());
+        // the script running out ends the session, the next one to connect is a new session
+        f.run(1);
+        assert!(!f.result.connected);
+        f.reconnect(&script);
+        f.run(1);
+        assert!(f.result.connected);
+        assert_eq!(
+            vec!["BMW M4 GT3 @ WeatherTech Raceway at Laguna Seca"],
+            *seen.borrow()
+        );
+        f.run(2);
+        f.reconnect(&script);
+        f.run(1);
+        assert_eq!(2, seen.borrow().len());
+    }
+    #[test]
+    fn test_incidents() {
+        let mut r = racing_row();
+        assert_eq!(0, r.incidents());
+        r.my_incidents = Some(4);
+        assert_eq!(4, r.incidents());
+        // the team count is the one that the limit applies to
+        r.team_incidents = Some(9);
+        assert_eq!(9, r.incidents());
+        r.my_incidents = None;
+        assert_eq!(9, r.incidents());
+    }
+    #[test]
+    fn test_projected_finish() {
+        let now: DateTime<Local> = "2022-03-05T13:20:00Z".parse().unwrap();
+        let f = projected_finish(now, TimeSpan::new(50 * 60, 0));
+        let expected: DateTime<Local> = "2022-03-05T14:10:00Z".parse().unwrap();
+        assert_eq!(expected, f);
+        let f = projected_finish(now, TimeSpan::new(90 * 60, 500_000_000));
+        let expected: DateTime<Local> = "2022-03-05T14:50:00.5Z".parse().unwrap();
+        assert_eq!(expected, f);
+    }
+    #[test]
+    fn test_pit_critical() {
+        // the window opens in 5 laps
+        let stop = Some(RaceStop::new(15, 22));
+        assert!(!pit_critical(6.0, stop, 10));
+        assert!(!pit_critical(5.0, stop, 10));
+        assert!(pit_critical(4.99, stop, 10));
+        assert!(pit_critical(0.5, stop, 10));
+        // once the window is open we can pit whenever
+        assert!(!pit_critical(0.5, stop, 15));
+        assert!(!pit_critical(0.5, stop, 18));
+        assert!(!pit_critical(0.5, None, 10));
+    }
+    #[test]
+    fn test_fuel_emergency() {
+        let stop = RaceStop::new(0, 1);
+        // half way round the lap
+        let this = racing_row();
+        assert!(!fuel_emergency(1.5, &this, Some(stop)));
+        assert!(!fuel_emergency(0.5, &this, Some(stop)));
+        assert!(fuel_emergency(0.49, &this, Some(stop)));
+        assert!(fuel_emergency(0.0, &this, Some(stop)));
+        // just over the line it takes the whole lap to get back to the pits
+        let start = IRacingTelemetryRow {
+            lap_progress: 0.0,
+            ..racing_row()
+        };
+        assert!(!fuel_emergency(1.0, &start, Some(stop)));
+        assert!(fuel_emergency(0.99, &start, Some(stop)));
+        // with no stop to make we're going to the finish on what's left
+        assert!(!fuel_emergency(0.2, &this, None));
+        // and once on pit road we've made it
+        for surface in [TrackLocation::ApproachingPits, TrackLocation::InPitStall] {
+            let pits = IRacingTelemetryRow {
+                player_track_surface: surface,
+                ..racing_row()
+            };
+            assert!(!fuel_emergency(0.0, &pits, Some(stop)));
+        }
+    }
+    #[test]
+    fn test_estimated_finish() {
+        let now: DateTime<Local> = "2022-03-05T13:20:00Z".parse().unwrap();
+        let green = Rate {
+            fuel: 1.0,
+            time: TimeSpan::new(90, 0),
+        };
+        // 20 laps at 1:30
+        let f = estimated_finish(now, EndsWith::Laps(20), green, TimeSpan::new(60, 0));
+        let expected: DateTime<Local> = "2022-03-05T13:50:00Z".parse().unwrap();
+        assert_eq!(expected, f);
+        // a timed race is the race time left
+        let race_time = TimeSpan::new(10 * 60, 0);
+        let expected: DateTime<Local> = "2022-03-05T13:30:00Z".parse().unwrap();
+        assert_eq!(
+            expected,
+            estimated_finish(now, EndsWith::Time(race_time), green, race_time)
+        );
+        assert_eq!(
+            expected,
+            estimated_finish(now, EndsWith::LapsOrTime(20, race_time), green, race_time)
+        );
+    }
+    #[test]
+    fn test_pit_call() {
+        assert_eq!(PitCall::PitNow, PitCall::from_gain(0.4));
+        assert_eq!(PitCall::StayOut, PitCall::from_gain(0.0));
+        assert_eq!(PitCall::StayOut, PitCall::from_gain(-1.2));
+        assert_eq!("PIT NOW", PitCall::PitNow.to_string());
+    }
+    #[test]
+    fn test_default_min_fuel() {
+        assert_eq!(0.4, default_min_fuel("Dallara IR18"));
+        assert_eq!(
+            0.5,
+            default_min_fuel("NASCAR Cup Series Next Gen Chevrolet Camaro ZL1")
+        );
+        assert_eq!(0.2, default_min_fuel("Global Mazda MX-5 Cup"));
+        let mut s = UserSettings::default();
+        assert_eq!(0.4, s.min_fuel_for("Dallara IR18"));
+        // the setting overrides the car default
+        s.min_fuel = Some(0.3);
+        assert_eq!(0.3, s.min_fuel_for("Dallara IR18"));
+    }
+    #[test]
+    fn test_settings_load_save_errors() {
+        let dir = std::env::temp_dir().join(format!("naf_calc_settings_{}", std::process::id()));
+        let _ = std::fs::create_dir_all(&dir);
+        let bad_path = dir.join("no_such_dir").join("settings.json");
+        let s = UserSettings::default();
+        assert!(matches!(
+            s.save(Some(bad_path.clone())),
+            Err(JsonLoadError::IOError(_))
+        ));
+        // a missing file is just the defaults
+        assert_eq!(s, UserSettings::load(Some(bad_path)).unwrap());
+        let f = dir.join("settings.json");
+        std::fs::write(&f, "{ not json").unwrap();
+        assert!(matches!(
+            UserSettings::load(Some(f.clone())),
+            Err(JsonLoadError::JsonError(_))
+        ));
+        let s2 = UserSettings {
+            cooldown_laps: 2,
+            ..UserSettings::default()
+        };
+        s2.save(Some(f.clone())).unwrap();
+        assert_eq!(s2, UserSettings::load(Some(f)).unwrap());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+    #[test]
+    fn test_stint_start_fuel() {
+        let mut last = racing_row();
+        last.fuel_level = 20.0;
+        let mut start = last.fuel_level;
+        // run a few laps
+        for _ in 0..3 {
+            let mut this = last;
+            this.fuel_level -= 1.5;
+            start = stint_start_fuel(start, &last, &this);
+            last = this;
+        }
+        assert_eq!(20.0, start);
+        assert_eq!(4.5, start - last.fuel_level);
+        // pit and refuel
+        let mut this = last;
+        this.player_track_surface = TrackLocation::InPitStall;
+        start = stint_start_fuel(start, &last, &this);
+        last = this;
+        this.fuel_level = 25.0;
+        start = stint_start_fuel(start, &last, &this);
+        assert_eq!(20.0, start);
+        last = this;
+        // leaving the pit box starts the new stint
+        this.player_track_surface = TrackLocation::OnTrack;
+        this.fuel_level = 24.9;
+        start = stint_start_fuel(start, &last, &this);
+        assert_eq!(24.9, start);
+    }
+    #[test]
+    fn test_last_lap() {
+        let mut r = racing_row();
+        assert!(!r.is_last_lap());
+        r.session_flags = Flags::WHITE;
+        assert!(r.is_last_lap());
+        r.session_flags = Flags::WHITE | Flags::CAUTION;
+        assert!(r.is_last_lap());
+        r.session_flags = Flags::CHECKERED;
+        assert!(!r.is_last_lap());
+    }
+    #[test]
+    fn test_strat_to_result() {
+        let r = StratRequestBuilder::new(
+            10.0,
+            EndsWith::Laps(25),
+            Rate {
+                fuel: 1.0,
+                time: TimeSpan::new(30, 0),
+            },
+        )
+        .fuel_left(5.0)
+        .build();
+        let strat = r.compute().unwrap();
+        let mut result = Estimation::default();
+        strat_to_result(&strat, 7, 5.0, 10.0, &UserSettings::default(), &mut result);
+        assert_eq!(2, result.stops);
+        // computed at the end of lap 7, the stops are in race laps
+        assert_eq!(Some(strat.stops[0].to_race_laps(7)), result.next_stop);
+        assert_eq!(
+            strat
+                .stops
+                .iter()
+                .map(|s| s.to_race_laps(7))
+                .collect::<Vec<_>>(),
+            result.all_stops
+        );
+        assert_eq!(
+            vec![5, 10, 10],
+            result.stints.iter().map(|s| s.laps).collect::<Vec<_>>()
+        );
+        assert_eq!(25.0, result.race.laps);
+    }
+    #[test]
+    fn fuel_to_add_is_what_is_sent() {
+        let strat = request().compute().unwrap();
+        let settings = UserSettings {
+            fuel_round: FuelRound::Exact,
+            ..UserSettings::default()
+        };
+        let mut result = Estimation::default();
+        // 2.1L exact
+        strat_to_result(&strat, 0, 3.9, 10.0, &settings, &mut result);
+        assert_eq!(2, result.fuel_to_add);
+        let shown = result.fuel_to_add.to_string();
+        match pit_commands(&settings, result.fuel_to_add)[..] {
+            [PitCommand::Fuel(Some(l))] => assert_eq!(shown, l.to_string()),
+            ref c => panic!("unexpected pit commands {:?}", c),
+        }
+    }
+    #[test]
+    fn test_track_temp_source() {
+        assert_eq!(
+            TrackTempSource::Crew,
+            UserSettings::default().track_temp_source
+        );
+        assert_eq!("TrackTempCrew", TrackTempSource::Crew.var_name());
+        assert_eq!("TrackTemp", TrackTempSource::Sensor.var_name());
+        assert_eq!("TrackTemp", TrackTempSource::Crew.fallback().var_name());
+        assert_eq!(
+            "TrackTempCrew",
+            TrackTempSource::Sensor.fallback().var_name()
+        );
+    }
+    #[test]
+    fn test_stop_count() {
+        let mut c = StopCount::new();
+        assert!(c.is_stable());
+        assert_eq!(2, c.update(2));
+        // flip flopping doesn't change the count
+        for _ in 0..3 {
+            assert_eq!(2, c.update(3));
+            assert!(!c.is_stable());
+            assert_eq!(2, c.update(2));
+            assert!(c.is_stable());
+        }
+        // it changes once its been the same for long enough
+        assert_eq!(2, c.update(3));
+        assert_eq!(3, c.update(3));
+        assert!(c.is_stable());
+        // a reset changes it straight away
+        c.reset(2);
+        assert_eq!(2, c.update(2));
+        assert!(c.is_stable());
+    }
+    #[test]
+    fn test_pit_visit() {
+        let mut last = racing_row();
+        last.session_time = 100.0;
+        let mut visit = None;
+        let mut step = |last: &mut IRacingTelemetryRow, t: f64, surface: TrackLocation| {
+            let mut this = *last;
+            this.session_time = t;
+            this.player_track_surface = surface;
+            let (v, d) = pit_visit(visit, last, &this);
+            visit = v;
+            *last = this;
+            d
+        };
+        // a drive through isn't a pitstop
+        assert_eq!(None, step(&mut last, 101.0, TrackLocation::ApproachingPits));
+        assert_eq!(None, step(&mut last, 120.0, TrackLocation::OnTrack));
+        // a stop in the pit box is timed from pit road entry to rejoining the track, along
+        // with how much of the lap pit road covers
+        last.lap_progress = 0.9;
+        assert_eq!(None, step(&mut last, 200.0, TrackLocation::ApproachingPits));
+        assert_eq!(None, step(&mut last, 210.0, TrackLocation::InPitStall));
+        assert_eq!(None, step(&mut last, 225.0, TrackLocation::ApproachingPits));
+        last.lap_progress = 0.15;
+        let (time, laps) = step(&mut last, 235.0, TrackLocation::OnTrack).unwrap();
+        assert_eq!(TimeSpan::new(35, 0), time);
+        assert!((laps - 0.25).abs() < 0.0001);
+        assert_eq!(None, step(&mut last, 236.0, TrackLocation::OnTrack));
+    }
+    #[test]
+    fn test_pit_loss() {
+        let green = Rate {
+            fuel: 1.0,
+            time: TimeSpan::new(80, 0),
+        };
+        // a quarter of a lap takes 20s at racing speed
+        assert_eq!(
+            Some(TimeSpan::new(15, 0)),
+            pit_loss(TimeSpan::new(35, 0), 0.25, green)
+        );
+        assert_eq!(
+            Some(TimeSpan::ZERO),
+            pit_loss(TimeSpan::new(10, 0), 0.25, green)
+        );
+        assert_eq!(None, pit_loss(TimeSpan::new(35, 0), 0.25, Rate::default()));
+    }
+    #[test]
+    fn test_pit_fuel() {
+        let cfg = RaceSession {
+            fuel_tank_size: 20.0,
+            ..cfg()
+        };
+        let settings = UserSettings::default();
+        let this = racing_row();
+        let adj = adjustments(&settings, &Estimation::default(), &this);
+        let mut calc = History::new(cfg, None);
+        // no strategy yet, fill it up
+        assert_eq!(20.0, pit_fuel(&calc, "test", &this, &adj, &settings));
+        // or one only from the provisional rate
+        calc.set_provisional(Some(Rate {
+            fuel: 0.5,
+            time: TimeSpan::new(60, 0),
+        }));
+        assert!(calc.is_provisional());
+        assert_eq!(20.0, pit_fuel(&calc, "test", &this, &adj, &settings));
+        for _ in 0..3 {
+            calc.add_lap(Lap {
+                fuel_left: 10.0,
+                fuel_used: 0.5,
+                time: TimeSpan::new(60, 0),
+                condition: LapState::empty(),
+            });
+        }
+        // 1000 secs left is 17 laps, 8.5L + 2 extra laps is 9.5L, less the 10L
+        // in the car leaves nothing to add.
+        assert_eq!(0.0, pit_fuel(&calc, "test", &this, &adj, &settings));
+        let mut low = this;
+        low.fuel_level = 2.0;
+        assert_eq!(8.0, pit_fuel(&calc, "test", &low, &adj, &settings));
+    }
+    #[test]
+    fn test_warmup_fuel() {
+        let cfg = RaceSession {
+            fuel_tank_size: 20.0,
+            ..cfg()
+        };
+        let settings = UserSettings::default();
+        let mut warmup = racing_row();
+        warmup.session_state = SessionState::Warmup;
+        warmup.player_track_surface = TrackLocation::InPitStall;
+        warmup.fuel_level = 5.0;
+        assert!(warmup_fuel_due(false, &warmup));
+        // only sent once
+        assert!(!warmup_fuel_due(true, &warmup));
+        let mut r = warmup;
+        r.session_state = SessionState::GetInCar;
+        assert!(warmup_fuel_due(false, &r));
+        r.player_track_surface = TrackLocation::NotInWorld;
+        assert!(!warmup_fuel_due(false, &r));
+        assert!(!warmup_fuel_due(false, &racing_row()));
+
+        let adj = adjustments(&settings, &Estimation::default(), &warmup);
+        let mut calc = History::new(cfg, None);
+        // a rough guess at the rate isn't enough to short fill the car with
+        calc.set_provisional(Some(Rate {
+            fuel: 0.5,
+            time: TimeSpan::new(60, 0),
+        }));
+        assert!(calc.strat(warmup.fuel_level, &adj, warmup.ends()).is_some());
+        assert_eq!(20.0, pit_fuel(&calc, "warmup", &warmup, &adj, &settings));
+        calc.set_baseline(
+            Rate {
+                fuel: 0.5,
+                time: TimeSpan::new(60, 0),
+            },
+            None,
+        );
+        // the whole 1100 sec race is 19 laps, 9.5L + 2 extra laps is 10.5L, less the 5L
+        // in the car, rounded up.
+        assert_eq!(6.0, pit_fuel(&calc, "warmup", &warmup, &adj, &settings));
+    }
+    #[test]
+    fn test_opponent_fuel_margin() {
+        // we're car 1, car 3 isn't in use
+        let mut opp = OpponentRow {
+            player: 1,
+            lap_completed: vec![10, 10, 8, -1],
+            on_pit_road: vec![false, false, false, false],
+        };
+        let mut starts = Vec::new();
+        opponent_stint_starts(&mut starts, &opp);
+        assert_eq!(vec![0, 0, 0, 0], starts);
+        // a 20 lap tank, car 0 has 10 laps left, car 2 has 12
+        assert_eq!(Some(4.0), opponent_fuel_margin(15.0, 20.0, &starts, &opp));
+        // car 0 pits on lap 12
+        opp.lap_completed = vec![12, 12, 10, -1];
+        opp.on_pit_road[0] = true;
+        opponent_stint_starts(&mut starts, &opp);
+        opp.lap_completed = vec![13, 13, 11, -1];
+        opp.on_pit_road[0] = false;
+        opponent_stint_starts(&mut starts, &opp);
+        assert_eq!(vec![12, 0, 0, 0], starts);
+        // car 0 now has 19 laps left, car 2 has 9
+        assert_eq!(Some(-2.0), opponent_fuel_margin(12.0, 20.0, &starts, &opp));
+        // a car can't have less than no fuel
+        opp.lap_completed = vec![13, 13, 30, -1];
+        assert_eq!(Some(2.5), opponent_fuel_margin(12.0, 20.0, &starts, &opp));
+        // nobody else on track
+        opp.lap_completed = vec![-1, 13, -1, -1];
+        assert_eq!(None, opponent_fuel_margin(12.0, 20.0, &starts, &opp));
+    }
+    #[test]
+    fn test_provisional_rate() {
+        let r = provisional_rate(20.0, 10.0, 24.5).unwrap();
+        assert_eq!(2.0, r.fuel);
+        assert_eq!(TimeSpan::from_secs_f64(24.5), r.time);
+        assert_eq!(None, provisional_rate(20.0, 0.0, 24.5));
+        assert_eq!(None, provisional_rate(20.0, 10.0, 0.0));
+        assert_eq!(None, provisional_rate(0.0, 10.0, 24.5));
+    }
+    #[test]
+    fn provisional_strat_from_est_lap_time() {
+        // no laps, no baseline and no db, the only thing to go on is iRacing's lap time
+        // estimate for the car.
+        let si =
+            IrSessionInfo::parse(include_str!("../fixtures/session_info/oval.yaml"), 2).unwrap();
+        let cfg = cfg();
+        let mut calc = History::new(cfg, None);
+        calc.set_provisional(provisional_rate(
+            calc.config().fuel_tank_size,
+            20.0,
+            si.driver_car_est_lap_time,
+        ));
+        let settings = UserSettings::default();
+        let adj = adjustments(&settings, &Estimation::default(), &racing_row());
+        let strat = calc.strat(10.0, &adj, EndsWith::Laps(30)).unwrap();
+        assert!(calc.is_provisional());
+        assert_eq!(vec![20, 10], strat.laps());
+        assert_eq!(TimeSpan::from_secs_f64(24.1922), strat.green.time);
+    }
+
+    #[test]
+    fn test_pit_approach() {
+        let on_track = racing_row();
+        let mut approaching = racing_row();
+        approaching.player_track_surface = TrackLocation::ApproachingPits;
+        let run = |surfaces: &[&IRacingTelemetryRow], needed: u32| -> Vec<bool> {
+            let mut ticks = 0;
+            surfaces
+                .iter()
+                .map(|r| {
+                    let (t, entering) = pit_approach(ticks, r, needed);
+                    ticks = t;
+                    entering
+                })
+                .collect()
+        };
+        // a one tick blip doesn't send anything
+        assert_eq!(
+            vec![false, false, false],
+            run(&[&on_track, &approaching, &on_track], 3)
+        );
+        // it fires once after enough ticks
+        assert_eq!(
+            vec![false, false, false, true, false],
+            run(
+                &[
+                    &on_track,
+                    &approaching,
+                    &approaching,
+                    &approaching,
+                    &approaching
+                ],
+                3
+            )
+        );
+        // 0 or 1 fires straight away
+        assert_eq!(vec![true, false], run(&[&approaching, &approaching], 1));
+        assert_eq!(vec![true, false], run(&[&approaching, &approaching], 0));
+    }
+    #[test]
+    fn test_settings_export_import() {
+        let dir = std::env::temp_dir().join(format!("naf_calc_export_{}", std::process::id()));
+        let _ = std::fs::create_dir_all(&dir);
+        let f = dir.join("export.json");
+        assert!(matches!(
+            UserSettings::import_from(&f),
+            Err(JsonLoadError::IOError(_))
+        ));
+        let s = UserSettings {
+            cooldown_laps: 2,
+            incident_limit: Some(17),
+            ..UserSettings::default()
+        };
+        s.export_to(&f).unwrap();
+        assert_eq!(s, UserSettings::import_from(&f).unwrap());
+        // out of range values are clamped
+        let bad = UserSettings {
+            max_fuel_save: 3.0,
+            extra_laps: -2.0,
+            cooldown_laps: -1,
+            pit_approach_ticks: 0,
+            incident_limit: Some(-5),
+            ui_scale: 40.0,
+            ..s.clone()
+        };
+        bad.export_to(&f).unwrap();
+        let i = UserSettings::import_from(&f).unwrap();
+        assert_eq!(0.5, i.max_fuel_save);
+        assert_eq!(0.0, i.extra_laps);
+        assert_eq!(0, i.cooldown_laps);
+        assert_eq!(1, i.pit_approach_ticks);
+        assert_eq!(None, i.incident_limit);
+        assert_eq!(4.0, i.ui_scale);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+    #[test]
+    fn test_amount_left_combined() {
+        let a = AmountLeft {
+            fuel: 3.24,
+            laps: 6.38,
+            time: TimeSpan::new(9 * 60 + 40, 0),
+        };
+        assert_eq!("3.2L = 6.4 laps = 09:40", a.combined());
+        assert_eq!("0.0L = 0.0 laps = 00:00", AmountLeft::default().combined());
+    }
+    // a telemetry session that plays back scripted rows, and remembers the pit commands sent to it.
+    struct ScriptedSession {
+        row: IRacingTelemetryRow,
+        rows: VecDeque<IRacingTelemetryRow>,
+        pit_commands: Rc<RefCell<Vec<PitCommand>>>,
+    }
+    impl TelemetrySession for ScriptedSession {
+        fn session_info(&self) -> String {
+            include_str!("../fixtures/session_info/road.yaml").to_string()
+        }
+        fn session_info_update(&self) -> i32 {
+            1
+        }
+        fn get_new_data(&mut self) -> Result<(), Error> {
+            self.row = self.rows.pop_front().ok_or(Error::SessionExpired)?;
+            Ok(())
+        }
+        fn read(&self) -> Result<IRacingTelemetryRow, Error> {
+            Ok(self.row)
+        }
+        fn read_opponents(&self) -> Result<Option<OpponentRow>, Error> {
+            Ok(None)
+        }
+        fn pit_command(&self, cmd: PitCommand) {
+            self.pit_commands.borrow_mut().push(cmd);
+        }
+        fn log_vars(&self) {}
+    }
+    struct ScriptedSource(Option<ScriptedSession>);
+    impl TelemetrySource for ScriptedSource {
+        fn connect(&mut self, _settings: &UserSettings) -> Option<Box<dyn TelemetrySession>> {
+            self.0
+                .take()
+                .map(|s| Box::new(s) as Box<dyn TelemetrySession>)
+        }
+    }
+    // adds a row to the script where the car has moved on to lap_progress, using fuel
+    // (or gaining it in the pits) and taking secs to get there.
+    fn drive(
+        script: &mut Vec<IRacingTelemetryRow>,
+        surface: TrackLocation,
+        lap_progress: f32,
+        fuel: f32,
+        secs: f64,
+    ) {
+        let mut r = *script.last().unwrap();
+        if lap_progress < r.lap_progress {
+            r.lap += 1;
+            r.lap_completed += 1;
+            r.race_laps += 1;
+            r.session_laps_remain -= 1;
+        }
+        r.player_track_surface = surface;
+        r.lap_progress = lap_progress;
+        r.fuel_level += fuel;
+        r.session_time += secs;
+        r.session_time_remain -= secs;
+        script.push(r);
+    }
+    // an Estimator that plays back script, with its own laps db that's removed once it's dropped.
+    struct Scripted {
+        e: Estimator,
+        settings: UserSettings,
+        result: Estimation,
+        pit_commands: Rc<RefCell<Vec<PitCommand>>>,
+        db_file: PathBuf,
+    }
+    impl Scripted {
+        fn new(name: &str, script: &[IRacingTelemetryRow]) -> Scripted {
+            let db_file =
+                std::env::temp_dir().join(format!("naf_calc_{}_{}.db", name, std::process::id()));
+            let _ = std::fs::remove_file(&db_file);
+            let pit_commands = Rc::new(RefCell::new(Vec::new()));
+            Scripted {
+                e: Estimator::with_source(Self::source(script, pit_commands.clone())),
+                settings: UserSettings {
+                    laps_db_path: Some(db_file.clone()),
+                    ..UserSettings::default()
+                },
+                result: Estimation::default(),
+                pit_commands,
+                db_file,
+            }
+        }
+        fn source(
+            script: &[IRacingTelemetryRow],
+            pit_commands: Rc<RefCell<Vec<PitCommand>>>,
+        ) -> Box<dyn TelemetrySource> {
+            Box::new(ScriptedSource(Some(ScriptedSession {
+                row: script[0],
+                rows: script[1..].iter().copied().collect(),
+                pit_commands,
+            })))
+        }
+        // the next session to connect plays back script.
+        fn reconnect(&mut self, script: &[IRacingTelemetryRow]) {
+            self.e.client = Self::source(script, self.pit_commands.clone());
+        }
+        fn run(&mut self, updates: usize) {
+            for _ in 0..updates {
+                self.e.update(&self.settings, &mut self.result);
+            }
+        }
+        fn state(&self) -> &SessionProgress {
+            self.e.state.as_ref().unwrap()
+        }
+        // the laps with condition saved to the db, flushing any that are queued first.
+        fn saved(&mut self, condition: LapState) -> i64 {
+            if let Some(cs) = self.e.state.as_mut() {
+                cs.calc.flush_laps().unwrap();
+            }
+            // the car & track from road.yaml
+            let db = crate::history::Db::new(&self.db_file).unwrap();
+            db.lap_count(132, 47, condition)
+        }
+    }
+    impl Drop for Scripted {
+        fn drop(&mut self) {
+            // the session has to let go of the db before it can be removed
+            self.e.state = None;
+            let _ = std::fs::remove_file(&self.db_file);
+        }
+    }
+    #[test]
+    fn scripted_stint_with_pitstop() {
+        use TrackLocation::{ApproachingPits, InPitStall, OnTrack};
+        let mut script = vec![IRacingTelemetryRow {
+            fuel_level: 20.0,
+            ..racing_row()
+        }];
+        // we join half way round a lap, then do 2 full green laps
+        drive(&mut script, OnTrack, 0.95, -0.9, 40.0);
+        drive(&mut script, OnTrack, 0.05, -0.2, 8.0);
+        for _ in 0..2 {
+            drive(&mut script, OnTrack, 0.5, -0.9, 40.0);
+            drive(&mut script, OnTrack, 0.95, -0.9, 40.0);
+            drive(&mut script, OnTrack, 0.05, -0.2, 8.0);
+        }
+        // the in lap, down pit road, fill up and back out
+        drive(&mut script, OnTrack, 0.5, -0.9, 40.0);
+        drive(&mut script, ApproachingPits, 0.92, -0.8, 36.0);
+        drive(&mut script, ApproachingPits, 0.93, 0.0, 2.0);
+        drive(&mut script, ApproachingPits, 0.94, 0.0, 2.0);
+        drive(&mut script, InPitStall, 0.95, 0.0, 5.0);
+        drive(&mut script, InPitStall, 0.95, 30.0, 10.0);
+        drive(&mut script, ApproachingPits, 0.98, -0.1, 5.0);
+        drive(&mut script, OnTrack, 0.02, -0.1, 5.0);
+        // the out lap
+        drive(&mut script, OnTrack, 0.5, -0.9, 40.0);
+        drive(&mut script, OnTrack, 0.95, -0.9, 40.0);
+        drive(&mut script, OnTrack, 0.05, -0.2, 8.0);
+
+        let mut f = Scripted::new("scripted_stint", &script);
+        // the first 3 laps
+        f.run(8);
+        assert!(f.result.connected);
+        assert_eq!(3, f.state().calc.laps().len());
+        assert!((f.result.fuel_last_lap - 2.0).abs() < 0.0001);
+        assert!(f.pit_commands.borrow().is_empty());
+        // the fuel is sent once we've been approaching the pits for long enough
+        f.run(4);
+        assert!(f
+            .pit_commands
+            .borrow()
+            .iter()
+            .any(|c| matches!(c, PitCommand::Fuel(Some(_)))));
+        // the pit stop and crossing the line at the end of pit road
+        f.run(4);
+        let cs = f.state();
+        assert_eq!(4, cs.calc.laps().len());
+        // 29s on pit road, less the 0.1 of a lap it covers at the ~70.7s green lap time
+        let loss = cs.calc.pit_loss().unwrap().as_secs_f32();
+        assert!((loss - 21.93).abs() < 0.01);
+        // the out lap, the stint restarted when we left the pit box
+        f.run(3);
+        assert_eq!(5, f.state().calc.laps().len());
+        assert!((f.result.fuel_last_lap - 2.0).abs() < 0.0001);
+        assert!((f.result.stint_fuel_used - 2.1).abs() < 0.0001);
+        assert!((f.result.car.fuel - 41.0).abs() < 0.0001);
+        // then the script runs out, which is the session ending
+        f.run(1);
+    }
+    #[test]
+    fn scripted_race() {
+        use TrackLocation::{ApproachingPits, InPitStall, OnTrack};
+        let mut script = vec![IRacingTelemetryRow {
+            fuel_level: 10.0,
+            session_laps_remain: 8,
+            session_time_remain: iracing_telem::IRSDK_UNLIMITED_TIME,
+            ..racing_row()
+        }];
+        let lap = |script: &mut Vec<IRacingTelemetryRow>| {
+            drive(script, OnTrack, 0.5, -0.9, 40.0);
+            drive(script, OnTrack, 0.95, -0.9, 40.0);
+            drive(script, OnTrack, 0.05, -0.2, 8.0);
+        };
+        // the green flag laps, starting part way round the first one
+        drive(&mut script, OnTrack, 0.95, -0.9, 40.0);
+        drive(&mut script, OnTrack, 0.05, -0.2, 8.0);
+        lap(&mut script);
+        lap(&mut script);
+        // the caution comes out as we cross the line, and we pit under it
+        let caution = Flags::CAUTION | Flags::YELLOW;
+        script.last_mut().unwrap().session_flags = caution;
+        drive(&mut script, OnTrack, 0.5, -0.5, 60.0);
+        drive(&mut script, OnTrack, 0.95, -0.5, 60.0);
+        drive(&mut script, OnTrack, 0.05, -0.1, 10.0);
+        drive(&mut script, OnTrack, 0.5, -0.5, 60.0);
+        drive(&mut script, ApproachingPits, 0.92, -0.4, 50.0);
+        drive(&mut script, ApproachingPits, 0.93, 0.0, 2.0);
+        drive(&mut script, ApproachingPits, 0.94, 0.0, 2.0);
+        drive(&mut script, InPitStall, 0.95, 0.0, 5.0);
+        // with a couple of liters more than the 6 asked for
+        drive(&mut script, InPitStall, 0.95, 8.0, 10.0);
+        drive(&mut script, ApproachingPits, 0.98, -0.1, 5.0);
+        // and it goes green as we leave pit road
+        drive(&mut script, OnTrack, 0.02, -0.1, 5.0);
+        script.last_mut().unwrap().session_flags = Flags::GREEN;
+        lap(&mut script);
+        lap(&mut script);
+        // the leader takes the checkered flag while we're on our last lap
+        drive(&mut script, OnTrack, 0.5, -0.9, 40.0);
+        script.last_mut().unwrap().session_state = SessionState::Checkered;
+        drive(&mut script, OnTrack, 0.95, -0.9, 40.0);
+        drive(&mut script, OnTrack, 0.05, -0.2, 8.0);
+
+        let mut f = Scripted::new("scripted_race", &script);
+        // in the pit stall, one stop to go and the fuel for it sent
+        f.run(16);
+        assert_eq!(1, f.result.stops);
+        assert_eq!(4.0, f.result.race.laps);
+        assert_eq!(6, f.result.fuel_to_add);
+        assert_eq!(vec![PitCommand::Fuel(Some(6))], *f.pit_commands.borrow());
+        // the rest of the race, the lap we take the checkered flag on isn't recorded
+        f.run(12);
+        assert_eq!(0, f.result.stops);
+        assert_eq!(0.0, f.result.race.laps);
+        assert_eq!(0, f.result.fuel_to_add);
+        assert_eq!(vec![PitCommand::Fuel(Some(6))], *f.pit_commands.borrow());
+        assert_eq!(7, f.state().calc.laps().len());
+        // the laps were saved at the checkered flag, the in lap is neither green or yellow
+        let counts = |f: &mut Scripted| (f.saved(LapState::empty()), f.saved(LapState::YELLOW));
+        assert_eq!((4, 2), counts(&mut f));
+        // and aren't saved again when the session ends
+        f.run(1);
+        assert!(f.e.state.is_none());
+        assert_eq!((4, 2), counts(&mut f));
+    }
+    #[test]
+    fn session_tag_saved_with_the_session() {
+        let script = [racing_row(), racing_row()];
+        let mut f = Scripted::new("session_tag", &script);
+        f.settings.session_tag = "league".to_string();
+        f.run(1);
+        assert!(f.result.connected);
+        // the UI clears the tag from the settings once it's been saved
+        assert!(f.result.session_tagged);
+        drop(f);
+        // no tag, nothing to clear
+        let mut f = Scripted::new("session_tag", &script);
+        f.run(1);
+        assert!(f.result.connected);
+        assert!(!f.result.session_tagged);
+    }
+    #[test]
+    fn laps_saved_at_checkered() {
+        let mut script = vec![IRacingTelemetryRow {
+            fuel_level: 20.0,
+            ..racing_row()
+        }];
+        drive(&mut script, TrackLocation::OnTrack, 0.95, -0.9, 40.0);
+        drive(&mut script, TrackLocation::OnTrack, 0.05, -0.2, 8.0);
+        drive(&mut script, TrackLocation::OnTrack, 0.5, -0.9, 40.0);
+        drive(&mut script, TrackLocation::OnTrack, 0.95, -0.9, 40.0);
+        drive(&mut script, TrackLocation::OnTrack, 0.05, -0.2, 8.0);
+        drive(&mut script, TrackLocation::OnTrack, 0.1, -0.1, 4.0);
+        script.last_mut().unwrap().session_state = SessionState::Checkered;
+        drive(&mut script, TrackLocation::OnTrack, 0.2, -0.1, 4.0);
+
+        let mut f = Scripted::new("checkered", &script);
+        f.run(5);
+        assert_eq!(2, f.state().calc.laps().len());
+        assert_eq!(0, f.saved(LapState::empty()));
+        // the checkered flag saves the laps while the session is still going
+        f.run(1);
+        assert!(f.e.state.is_some());
+        assert_eq!(2, f.saved(LapState::empty()));
+        f.run(1);
+        assert_eq!(2, f.saved(LapState::empty()));
+    }
+    #[test]
+    fn test_pit_commit() {
+        let on_track = racing_row();
+        let in_stall = IRacingTelemetryRow {
+            player_track_surface: TrackLocation::InPitStall,
+            ..racing_row()
+        };
+        // on lap 10, the window is open until the end of lap 14
+        let open = RaceStop::new(10, 14);
+        // not committed until the window is open
+        assert_eq!(None, pit_commit(None, None, 10, &on_track, &on_track));
+        let later = RaceStop::new(12, 16);
+        assert_eq!(
+            None,
+            pit_commit(None, Some(later), 10, &on_track, &on_track)
+        );
+        assert_eq!(
+            Some(open),
+            pit_commit(None, Some(open), 10, &on_track, &on_track)
+        );
+        // or once the window has closed
+        let closed = RaceStop::new(5, 9);
+        assert_eq!(
+            None,
+            pit_commit(None, Some(closed), 10, &on_track, &on_track)
+        );
+        // a noisy fuel reading gives a strategy with no stop, we stay committed
+        assert_eq!(
+            Some(open),
+            pit_commit(Some(open), None, 10, &on_track, &on_track)
+        );
+        assert_eq!(
+            Some(open),
+            pit_commit(Some(open), None, 10, &on_track, &in_stall)
+        );
+        assert_eq!(
+            Some(open),
+            pit_commit(Some(open), None, 10, &in_stall, &in_stall)
+        );
+        // the latched stop stays where it was as the laps go by
+        assert_eq!(
+            Some(open),
+            pit_commit(Some(open), None, 12, &on_track, &on_track)
+        );
+        assert_eq!(
+            Some(open),
+            pit_commit(Some(open), None, 14, &on_track, &on_track)
+        );
+        // the window closed and we still don't need to stop, so the stop wasn't needed
+        assert_eq!(None, pit_commit(Some(open), None, 15, &on_track, &on_track));
+        // until we leave the pit box
+        assert_eq!(None, pit_commit(Some(open), None, 10, &in_stall, &on_track));
+        assert_eq!(
+            None,
+            pit_commit(Some(open), Some(open), 10, &in_stall, &on_track)
+        );
+    }
+    #[test]
+    fn test_green_vs_average() {
+        let rate = |fuel| Rate {
+            fuel,
+            time: TimeSpan::new(60, 0),
+        };
+        let mut e = Estimation::default();
+        assert_eq!(None, e.green_vs_average());
+        e.db_green = Some(rate(2.0));
+        assert_eq!(None, e.green_vs_average());
+        e.live_green = Some(rate(2.03));
+        assert!((e.green_vs_average().unwrap() - 0.03).abs() < 0.0001);
+        e.live_green = Some(rate(1.9));
+        assert!((e.green_vs_average().unwrap() + 0.1).abs() < 0.0001);
+        e.db_green = None;
+        assert_eq!(None, e.green_vs_average());
+    }
+    #[test]
+    fn test_dash_save_target() {
+        let mut e = Estimation {
+            save_target: 0.9,
+            save_achievable: true,
+            ..Estimation::default()
+        };
+        assert_eq!((0.9, true), e.dash_save_target());
+        e.break_even = Some(0.75);
+        assert_eq!((0.75, true), e.dash_save_target());
+        // skipping the next stop isn't realistic, fallback to the last stop's target
+        e.break_even_achievable = false;
+        assert_eq!((0.9, true), e.dash_save_target());
+    }
+    #[test]
+    fn test_refuel_policy() {
+        let r = request();
+        let strat = r.compute().unwrap();
+        let mut settings = UserSettings {
+            fuel_round: FuelRound::Exact,
+            ..UserSettings::default()
+        };
+        // 5.0L for the race, 3.2 in the car
+        assert_eq!(RefuelPolicy::BufferLaps, settings.refuel_policy);
+        assert!((fuel_to_add(&strat, 3.2, 10.0, &settings) - 2.8).abs() < 0.0001);
+        settings.refuel_policy = RefuelPolicy::Planned;
+        assert!((fuel_to_add(&strat, 3.2, 10.0, &settings) - 1.8).abs() < 0.0001);
+        settings.refuel_policy = RefuelPolicy::FillTank;
+        assert!((fuel_to_add(&strat, 3.2, 10.0, &settings) - 6.8).abs() < 0.0001);
+        // no fuel needed is none added, whatever the policy
+        for p in [
+            RefuelPolicy::Planned,
+            RefuelPolicy::BufferLaps,
+            RefuelPolicy::FillTank,
+        ] {
+            settings.refuel_policy = p;
+            assert_eq!(0.0, fuel_to_add(&strat, 7.0, 10.0, &settings));
+        }
+    }
+    #[test]
+    fn test_tactical_pit_now() {
+        // on lap 20, the window opens in 3 laps
+        let stop = Some(RaceStop::new(23, 32));
+        // under caution with the window opening within range
+        assert!(tactical_pit_now(true, stop, 20, 3));
+        assert!(tactical_pit_now(true, stop, 20, 5));
+        assert!(tactical_pit_now(true, stop, 23, 1));
+        assert!(tactical_pit_now(true, stop, 22, 1));
+        // too far from the window
+        assert!(!tactical_pit_now(true, stop, 20, 2));
+        // green flag, no stop, or turned off
+        assert!(!tactical_pit_now(false, stop, 20, 5));
+        assert!(!tactical_pit_now(true, None, 20, 5));
+        assert!(!tactical_pit_now(true, stop, 20, 0));
+    }
+    #[test]
+    fn test_race_length_changed() {
+        let last = racing_row();
+        let mut this = racing_row();
+        this.session_time += 1.0;
+        this.session_time_remain -= 1.0;
+        assert!(!race_length_changed(&last, &this));
+        // a lap completed is the normal count down
+        this.session_laps_remain -= 1;
+        assert!(!race_length_changed(&last, &this));
+        // shortened
+        this.session_laps_remain = 10;
+        assert!(race_length_changed(&last, &this));
+        this.session_laps_remain = 19;
+        this.session_time_remain = 500.0;
+        assert!(race_length_changed(&last, &this));
+        // but not outside of the race
+        this.session_state = SessionState::Checkered;
+        assert!(!race_length_changed(&last, &this));
+    }
+    #[test]
+    fn shortened_race_recomputes_strategy() {
+        let mut script = vec![IRacingTelemetryRow {
+            fuel_level: 20.0,
+            session_time_remain: iracing_telem::IRSDK_UNLIMITED_TIME,
+            ..racing_row()
+        }];
+        drive(&mut script, TrackLocation::OnTrack, 0.95, -0.9, 40.0);
+        drive(&mut script, TrackLocation::OnTrack, 0.05, -0.2, 8.0);
+        drive(&mut script, TrackLocation::OnTrack, 0.5, -0.9, 40.0);
+        drive(&mut script, TrackLocation::OnTrack, 0.95, -0.9, 40.0);
+        drive(&mut script, TrackLocation::OnTrack, 0.05, -0.2, 8.0);
+        drive(&mut script, TrackLocation::OnTrack, 0.1, -0.1, 4.0);
+        // part way round the lap the race is cut to 5 laps to go
+        drive(&mut script, TrackLocation::OnTrack, 0.2, -0.1, 4.0);
+        script.last_mut().unwrap().session_laps_remain = 5;
+
+        let mut f = Scripted::new("shortened", &script);
+        let total_laps = |r: &Estimation| r.stints.iter().map(|s| s.laps).sum::<i32>();
+        f.run(6);
+        assert_eq!(18, total_laps(&f.result));
+        assert!(f.result.stops > 0);
+        f.run(1);
+        assert_eq!(5, total_laps(&f.result));
+        assert_eq!(0, f.result.stops);
+    }
+    #[test]
+    fn test_timed_race_laps() {
+        let green = Rate {
+            fuel: 1.0,
+            time: TimeSpan::new(90, 0),
+        };
+        assert_eq!(Some(10.0), timed_race_laps(TimeSpan::new(900, 0), green));
+        assert_eq!(Some(0.5), timed_race_laps(TimeSpan::new(45, 0), green));
+        assert_eq!(
+            None,
+            timed_race_laps(TimeSpan::new(900, 0), Rate::default())
+        );
+    }
+    #[test]
+    fn timed_race_laps_follow_the_clock() {
+        let mut script = vec![IRacingTelemetryRow {
+            fuel_level: 20.0,
+            session_laps_remain: iracing_telem::IRSDK_UNLIMITED_LAPS,
+            session_time_remain: 1800.0,
+            lap_progress: 0.05,
+            ..racing_row()
+        }];
+        for _ in 0..3 {
+            drive(&mut script, TrackLocation::OnTrack, 0.5, -0.9, 40.0);
+            drive(&mut script, TrackLocation::OnTrack, 0.95, -0.9, 40.0);
+            drive(&mut script, TrackLocation::OnTrack, 0.05, -0.2, 8.0);
+        }
+        drive(&mut script, TrackLocation::OnTrack, 0.5, -0.9, 40.0);
+        drive(&mut script, TrackLocation::OnTrack, 0.9, -0.8, 36.0);
+        // drive counts the laps down, a timed race reports unlimited laps throughout
+        for r in script.iter_mut() {
+            r.session_laps_remain = iracing_telem::IRSDK_UNLIMITED_LAPS;
+        }
+
+        let mut f = Scripted::new("timed_race", &script);
+        let mut laps = Vec::new();
+        for _ in 1..script.len() {
+            f.run(1);
+            let result = &f.result;
+            assert!(result.race_laps_estimated);
+            if result.green.time > TimeSpan::ZERO {
+                // mid lap as well as at the line
+                let expected = result.race.time.as_secs_f32() / result.green.time.as_secs_f32();
+                assert!((result.race.laps - expected).abs() < 0.0001);
+            }
+            laps.push((result.race.laps * 100.0).round() / 100.0);
+        }
+        // there's no green rate until the first lap is done, then the laps count down with
+        // the clock rather than jumping at each lap.
+        assert_eq!(
+            vec![0.0, 0.0, 20.37, 19.9, 19.42, 19.33, 18.86, 18.38, 18.29, 17.81, 17.38],
+            laps
+        );
+        assert_eq!(TimeSpan::new(1460, 0), f.result.race.time);
+    }
+    #[test]
+    fn test_smoothed_strat() {
+        let green = Rate {
+            fuel: 1.0,
+            time: TimeSpan::new(30, 0),
+        };
+        let two_stops = StratRequestBuilder::new(10.0, EndsWith::Laps(25), green)
+            .fuel_left(6.0)
+            .build()
+            .compute()
+            .unwrap();
+        let one_stop = StratRequestBuilder::new(10.0, EndsWith::Laps(14), green)
+            .fuel_left(6.0)
+            .build()
+            .compute()
+            .unwrap();
+        assert_eq!(2, two_stops.stops.len());
+        assert_eq!(1, one_stop.stops.len());
+        let mut count = StopCount::new();
+        let mut shown = None;
+        assert_eq!(
+            two_stops,
+            smoothed_strat(&mut count, &mut shown, two_stops.clone(), 10)
+        );
+        // a single lap with a different count keeps the last strategy, a lap on
+        let s = smoothed_strat(&mut count, &mut shown, one_stop.clone(), 11);
+        assert_eq!(two_stops.after_laps(1), s);
+        assert_eq!(2, s.stops.len());
+        // once it sticks the new strategy is shown
+        assert_eq!(
+            one_stop,
+            smoothed_strat(&mut count, &mut shown, one_stop.clone(), 12)
+        );
+        assert_eq!(
+            one_stop,
+            smoothed_strat(&mut count, &mut shown, one_stop.clone(), 13)
+        );
+        assert!(count.is_stable());
+    }
 }
@@ -1,30 +1,39 @@
 // On Windows platform, don't show a console when opening the app.
 #![windows_subsystem = "windows"]
 
+use chrono::{DateTime, Local};
 use druid::debug_state::DebugState;
 use druid::piet::{Text, TextLayout, TextLayoutBuilder};
 use druid::widget::{
-    Align, Button, Checkbox, Flex, Label, LabelText, Painter, SizedBox, TextBox, ViewSwitcher,
+    Align, Button, Checkbox, Controller, Either, Flex, Label, LabelText, MainAxisAlignment,
+    Painter, Scroll, SizedBox, TextBox, ViewSwitcher,
 };
 use druid::{
-    AppLauncher, BoxConstraints, Color, Data, Env, Event, EventCtx, FontDescriptor, FontFamily,
-    FontWeight, Insets, Key, KeyOrValue, LayoutCtx, Lens, LifeCycle, LifeCycleCtx, PaintCtx, Point,
-    Rect, RenderContext, Size, UnitPoint, UpdateCtx, Widget, WidgetExt, WidgetId, WidgetPod,
-    WindowDesc,
+    AppDelegate, AppLauncher, BoxConstraints, Color, Data, DelegateCtx, Env, Event, EventCtx,
+    FontDescriptor, FontFamily, FontWeight, Insets, KbKey, Key, KeyOrValue, LayoutCtx, Lens,
+    LifeCycle, LifeCycleCtx, Modifiers, PaintCtx, Point, Rect, RenderContext, Size, UnitPoint,
+    UpdateCtx, Widget, WidgetExt, WidgetId, WidgetPod, WindowDesc, WindowId,
 };
 use druid::{LensExt, TimerToken};
 use druid_widget_nursery::DropdownSelect;
 use flexi_logger::{Duplicate, FileSpec, Logger};
-use history::RaceSession;
-use ircalc::{AmountLeft, Estimation, UserSettings};
-use log::info;
+use history::{RaceSession, TagFilter};
+use ircalc::{
+    AmountLeft, BlackFlagKind, Estimation, FuelRound, JsonLoadError, PitCall, RefuelPolicy,
+    TempUnit, Theme, TrackTempSource, UserSettings,
+};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
 use std::fmt::Display;
+use std::fs::File;
+use std::io::{self, BufReader};
 use std::marker::PhantomData;
 use std::mem;
 use std::ops::Add;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::time::Duration;
-use strat::{EndsWith, Rate, StratRequest, TimeSpan};
+use strat::{EndsWith, PitWindow, Rate, StratRequestBuilder, TimeSpan};
 
 mod history;
 mod ircalc;
@@ -60,10 +69,27 @@ fn main() {
         logger.flush();
         std::process::exit(-1);
     }));
-    let sessions = history::Db::new(&ircalc::default_laps_db().unwrap())
-        .unwrap()
-        .sessions()
-        .unwrap();
+    let (settings, settings_error) = match UserSettings::load(ircalc::default_settings_file()) {
+        Ok(s) => (s, None),
+        Err(e) => {
+            warn!("failed to load settings {:?}", e);
+            (
+                UserSettings::default(),
+                Some(format!("Failed to load settings, using defaults: {}", e)),
+            )
+        }
+    };
+    let laps_db = settings.laps_db();
+    let laps_db_file = laps_db.as_ref().unwrap();
+    let sessions = history::Db::new(laps_db_file)
+        .and_then(|db| db.sessions(settings.min_session_laps))
+        .unwrap_or_else(|e| {
+            panic!(
+                "couldn't read the laps database {}: {}",
+                laps_db_file.display(),
+                e
+            )
+        });
     // create the initial app state
     let mut initial_state = UiState {
         offline: OfflineState {
@@ -74,14 +100,36 @@ fn main() {
             time: Some(TimeSpan::new(50 * 60, 0)),
             fuel_tank_size: None,
             max_fuel_save: None,
+            target_stops: None,
+            expected_caution_laps: None,
+            time_axis: false,
             strat: None,
+            db_session_limit: settings.db_session_limit,
+            tag_filter: settings.tag_filter(),
+            exclude_blue_laps: settings.exclude_blue_laps,
+            laps_db,
         },
         online: ircalc::Estimation::default(),
         settings_editor: EditableSettings::default(),
-        settings: UserSettings::load(ircalc::default_settings_file()),
+        settings,
         show_settings: false,
+        settings_error,
+        forced_view: None,
+        last_view: None,
     };
-    initial_state.offline.on_session_change();
+    if initial_state.settings.restore_view {
+        match SavedView::load(ircalc::default_view_file()) {
+            Ok(v) => v.restore(&mut initial_state),
+            Err(e) => warn!("failed to load the last view {:?}", e),
+        }
+    }
+    if let Err(e) = initial_state
+        .offline
+        .load_inputs(ircalc::default_planner_file(), &sessions)
+    {
+        warn!("failed to load planner inputs {:?}", e);
+        initial_state.offline.on_session_change();
+    }
     initial_state.offline.recalc();
 
     let monitors = druid::Screen::get_monitors();
@@ -100,53 +148,278 @@ fn main() {
 
     // start the application
     AppLauncher::with_window(main_window)
+        .delegate(Delegate)
         .launch(initial_state)
         .expect("Failed to launch application");
 }
 
+// app level handling that isn't tied to a widget.
+struct Delegate;
+
+impl AppDelegate<UiState> for Delegate {
+    // the delegate sees key presses before any widget, whether or not something has the focus.
+    fn event(
+        &mut self,
+        _ctx: &mut DelegateCtx,
+        _id: WindowId,
+        event: Event,
+        data: &mut UiState,
+        _env: &Env,
+    ) -> Option<Event> {
+        if let Event::KeyDown(k) = &event {
+            let start = SavedView::of(data);
+            if view_key(data, &k.key, k.mods) {
+                save_view(data, &start);
+                return None;
+            }
+        }
+        Some(event)
+    }
+    fn window_removed(
+        &mut self,
+        _id: WindowId,
+        data: &mut UiState,
+        _env: &Env,
+        _ctx: &mut DelegateCtx,
+    ) {
+        // the planner inputs change on every keystroke, so they're only saved on the way out.
+        if let Err(e) = data.offline.save_inputs(ircalc::default_planner_file()) {
+            warn!("failed to save planner inputs {:?}", e);
+        }
+    }
+}
+
 fn build_root_widget() -> impl Widget<UiState> {
     let mut calc = ircalc::Estimator::new();
     let vs = ViewSwitcher::new(
-        |v: &UiState, _env: &Env| {
-            if !v.show_settings {
-                if v.online.connected {
-                    UiView::Online
-                } else {
-                    UiView::Offline
-                }
-            } else {
-                UiView::Settings
+        |v: &UiState, _env: &Env| select_view(v),
+        |active: &UiView, s: &UiState, _env: &Env| match *active {
+            UiView::Online => {
+                with_fuel_emergency(build_active_dash(s.settings.combined_car)).boxed()
             }
-        },
-        |active: &UiView, _s: &UiState, _env: &Env| match *active {
-            UiView::Online => build_active_dash().boxed(),
-            UiView::Offline => build_offline_widget().boxed(),
+            UiView::Compact => with_fuel_emergency(build_compact_dash()).boxed(),
+            UiView::Reconnecting => build_reconnecting_widget().boxed(),
+            UiView::Offline => build_offline_widget(
+                s.offline.laps_db.as_ref().unwrap(),
+                s.settings.min_session_laps,
+            )
+            .boxed(),
             UiView::Settings => build_settings_widget().boxed(),
         },
     );
+    // shows any problem with the settings file until it's clicked on.
+    let settings_error = Either::new(
+        |d: &UiState, _e: &Env| d.settings_error.is_some(),
+        Label::new(|d: &UiState, _e: &Env| d.settings_error.clone().unwrap_or_default())
+            .with_text_size(SMALL_SIZE_KEY)
+            .padding(6.0)
+            .expand_width()
+            .background(Color::RED)
+            .on_click(|_ctx, data: &mut UiState, _env| data.settings_error = None),
+        SizedBox::empty(),
+    );
     TimerWidget {
-        on_fire: move |d: &mut UiState| calc.update(&d.settings, &mut d.online),
+        on_fire: move |d: &mut UiState| {
+            calc.update(&d.settings, &mut d.online);
+            if clear_session_tag(d) {
+                if let Err(e) = d.settings.save(ircalc::default_settings_file()) {
+                    warn!("failed to save settings {:?}", e);
+                }
+            }
+        },
         timer_id: TimerToken::INVALID,
-        widget: vs,
+        widget: Flex::column()
+            .with_child(settings_error)
+            .with_flex_child(vs, 1.0)
+            .controller(ViewTracker)
+            .env_scope(|env, d: &UiState| {
+                set_text_sizes(env, d.settings.ui_scale);
+                set_theme_colors(env, d.settings.theme);
+            }),
         p: PhantomData,
     }
 }
 
+// the session tag only applies to the session it was saved with, returns true if it was
+// cleared from the settings.
+fn clear_session_tag(d: &mut UiState) -> bool {
+    let tagged = d.online.session_tagged;
+    d.online.session_tagged = false;
+    if tagged {
+        d.settings.session_tag.clear();
+        d.settings_editor.session_tag.clear();
+    }
+    tagged
+}
+
+// the view to show, settings are shown when asked for, otherwise it's any view forced
+// from the keyboard, or the one that fits the connection state.
+fn select_view(v: &UiState) -> UiView {
+    let view = if v.show_settings {
+        UiView::Settings
+    } else if let Some(f) = v.forced_view {
+        f
+    } else if v.online.connected {
+        UiView::Online
+    } else if v.online.reconnecting {
+        UiView::Reconnecting
+    } else {
+        UiView::Offline
+    };
+    match view {
+        UiView::Online if v.settings.compact_dash => UiView::Compact,
+        _ => view,
+    }
+}
+
+// the view to remember as the last one in use, the compact dash counts as the dash.
+fn dash_or_planner(v: UiView) -> Option<UiView> {
+    match v {
+        UiView::Online | UiView::Compact => Some(UiView::Online),
+        UiView::Offline => Some(UiView::Offline),
+        UiView::Reconnecting | UiView::Settings => None,
+    }
+}
+
+// keyboard shortcuts to switch views, O for the dash, F for the offline planner,
+// S for settings and A to go back to picking the view from the connection state.
+// C switches between the full and compact dash. The planner and settings have text
+// boxes that need the keys, so there the shortcuts are used with Alt. Ctrl is left
+// alone for the text boxes' own keys, e.g. Ctrl+A and Ctrl+C. Returns true if the key
+// was a shortcut.
+fn view_key(data: &mut UiState, key: &KbKey, mods: Modifiers) -> bool {
+    let typing = matches!(select_view(data), UiView::Offline | UiView::Settings);
+    let c = match key {
+        KbKey::Character(c) if !mods.ctrl() && (mods.alt() || !typing) => c.to_ascii_lowercase(),
+        _ => return false,
+    };
+    match c.as_str() {
+        "o" => data.forced_view = Some(UiView::Online),
+        "f" => data.forced_view = Some(UiView::Offline),
+        "a" => data.forced_view = None,
+        "c" => data.settings.compact_dash = !data.settings.compact_dash,
+        "s" if !data.show_settings => {
+            data.settings_editor.load(&data.settings);
+            data.show_settings = true;
+        }
+        _ => return false,
+    }
+    true
+}
+
+// keeps track of the last dash or planner view, and saves the view when it changes.
+fn save_view(data: &mut UiState, start: &SavedView) {
+    if let Some(v) = dash_or_planner(select_view(data)) {
+        data.last_view = Some(v);
+    }
+    let view = SavedView::of(data);
+    if view != *start && data.settings.restore_view {
+        if let Err(e) = view.save(ircalc::default_view_file()) {
+            warn!("failed to save the view {:?}", e);
+        }
+    }
+}
+
+// saves the view after anything in the UI changes it, e.g. the settings buttons.
+struct ViewTracker;
+
+impl<W: Widget<UiState>> Controller<UiState, W> for ViewTracker {
+    fn event(
+        &mut self,
+        child: &mut W,
+        ctx: &mut EventCtx,
+        event: &Event,
+        data: &mut UiState,
+        env: &Env,
+    ) {
+        let start = SavedView::of(data);
+        child.event(ctx, event, data, env);
+        save_view(data, &start);
+    }
+}
+
+// the view picked by the user, remembered between runs so that a restart, e.g. after a
+// crash, goes back to it.
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+#[serde(default)]
+struct SavedView {
+    forced_view: Option<UiView>,
+    show_settings: bool,
+    last_view: Option<UiView>,
+}
+impl SavedView {
+    fn of(d: &UiState) -> SavedView {
+        SavedView {
+            forced_view: d.forced_view,
+            show_settings: d.show_settings,
+            last_view: d.last_view,
+        }
+    }
+    fn restore(&self, d: &mut UiState) {
+        d.forced_view = self.forced_view;
+        d.last_view = self.last_view;
+        // if the dash was last in use, wait for iRacing rather than starting on the planner.
+        // The dash still takes over when a session appears, and the planner is a click away.
+        if self.last_view == Some(UiView::Online) {
+            d.online.reconnecting = true;
+        }
+        if self.show_settings {
+            d.settings_editor.load(&d.settings);
+            d.show_settings = true;
+        }
+    }
+    // loads the saved view, if nothing's been saved yet this is the default view.
+    fn load(path: Option<PathBuf>) -> Result<SavedView, JsonLoadError> {
+        match path.map(File::open) {
+            Some(Ok(f)) => Ok(serde_json::from_reader(BufReader::new(f))?),
+            Some(Err(e)) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(SavedView::default()),
+        }
+    }
+    fn save(&self, path: Option<PathBuf>) -> Result<(), JsonLoadError> {
+        if let Some(p) = path {
+            let file = File::create(p)?;
+            serde_json::to_writer_pretty(file, self)?;
+        }
+        Ok(())
+    }
+}
+
 const LABEL_TEXT_SIZE: f64 = 32.0;
+const SMALL_TEXT_SIZE: f64 = 24.0;
+const VALUE_TEXT_SIZE: f64 = 48.0;
+
+// the text sizes with the ui scale from the settings applied, these are set in the
+// env at the root so that a change to the scale applies everywhere.
+const LABEL_SIZE_KEY: Key<f64> = Key::new("naf.label-size");
+const SMALL_SIZE_KEY: Key<f64> = Key::new("naf.small-size");
+const VALUE_FONT_KEY: Key<FontDescriptor> = Key::new("naf.value-font");
+
+fn scaled(size: f64, scale: f32) -> f64 {
+    // keep silly values in the settings file from making the dash unusable
+    size * (scale as f64).clamp(0.5, 4.0)
+}
+fn set_text_sizes(env: &mut Env, scale: f32) {
+    env.set(LABEL_SIZE_KEY, scaled(LABEL_TEXT_SIZE, scale));
+    env.set(SMALL_SIZE_KEY, scaled(SMALL_TEXT_SIZE, scale));
+    env.set(
+        VALUE_FONT_KEY,
+        FontDescriptor::new(FontFamily::SYSTEM_UI)
+            .with_weight(FontWeight::BOLD)
+            .with_size(scaled(VALUE_TEXT_SIZE, scale)),
+    );
+}
 
 fn lbl<T: Data>(l: impl Into<LabelText<T>>, align: UnitPoint) -> impl Widget<T> {
     SizedBox::new(Align::new(
         align,
         Label::new(l)
-            .with_text_size(LABEL_TEXT_SIZE)
+            .with_text_size(LABEL_SIZE_KEY)
             .with_text_color(Color::grey8(200)),
     ))
 }
 fn val<T: Data>(text: impl Into<LabelText<T>>, color: Option<KeyOrValue<Color>>) -> impl Widget<T> {
-    let font = FontDescriptor::new(FontFamily::SYSTEM_UI)
-        .with_weight(FontWeight::BOLD)
-        .with_size(48.0);
-    let mut lbl = Label::<T>::new(text).with_font(font);
+    let mut lbl = Label::<T>::new(text).with_font(VALUE_FONT_KEY);
     if let Some(c) = color {
         lbl = lbl.with_text_color(c);
     }
@@ -157,7 +430,51 @@ const COLOR_BG_KEY: Key<Color> = Key::new("color-bg-key");
 const COLOR_KEY: Key<Color> = Key::new("color-key");
 const COLOR_CLEAR: Color = Color::rgba8(0, 0, 0, 0);
 
+// the highlight colors, these are set from the theme.
+const GOOD_COLOR_KEY: Key<Color> = Key::new("naf.color-good");
+const MARGINAL_COLOR_KEY: Key<Color> = Key::new("naf.color-marginal");
+const BAD_COLOR_KEY: Key<Color> = Key::new("naf.color-bad");
+const WARN_COLOR_KEY: Key<Color> = Key::new("naf.color-warn");
+const INFO_COLOR_KEY: Key<Color> = Key::new("naf.color-info");
+
+#[derive(Debug, Clone, PartialEq)]
+struct Palette {
+    good: Color,     // enough fuel, pit window open, on the save target
+    marginal: Color, // only just enough fuel
+    bad: Color,      // pit window closing, save not achievable
+    warn: Color,     // getting close to a limit
+    info: Color,     // missed the save target
+}
+fn palette(theme: Theme) -> Palette {
+    match theme {
+        Theme::Standard => Palette {
+            good: Color::GREEN,
+            marginal: Color::PURPLE,
+            bad: Color::RED,
+            warn: Color::YELLOW,
+            info: Color::BLUE,
+        },
+        // based on the Okabe-Ito palette, which avoids red/green pairs
+        Theme::ColorBlind => Palette {
+            good: Color::rgb8(0, 114, 178),
+            marginal: Color::rgb8(204, 121, 167),
+            bad: Color::rgb8(213, 94, 0),
+            warn: Color::rgb8(240, 228, 66),
+            info: Color::rgb8(86, 180, 233),
+        },
+    }
+}
+fn set_theme_colors(env: &mut Env, theme: Theme) {
+    let p = palette(theme);
+    env.set(GOOD_COLOR_KEY, p.good);
+    env.set(MARGINAL_COLOR_KEY, p.marginal);
+    env.set(BAD_COLOR_KEY, p.bad);
+    env.set(WARN_COLOR_KEY, p.warn);
+    env.set(INFO_COLOR_KEY, p.info);
+}
+
 fn colorer<T: PartialOrd + Copy + Add<Output = T>>(
+    env: &Env,
     enable: bool,
     car: T,
     race: T,
@@ -166,9 +483,9 @@ fn colorer<T: PartialOrd + Copy + Add<Output = T>>(
     if !enable {
         COLOR_CLEAR
     } else if car >= race + buffer {
-        Color::GREEN
+        env.get(GOOD_COLOR_KEY)
     } else if car >= race {
-        Color::PURPLE
+        env.get(MARGINAL_COLOR_KEY)
     } else {
         Color::BLACK
     }
@@ -177,30 +494,94 @@ fn colorer<T: PartialOrd + Copy + Add<Output = T>>(
 const GRID: Color = Color::GRAY;
 const GWIDTH: f64 = 1.0;
 
-#[derive(Default, Debug, Clone, Copy, Data, Lens)]
+#[derive(Default, Debug, Clone, Data, Lens)]
 struct EditableSettings {
     max_fuel_save: Option<f32>,
+    max_realistic_save: Option<f32>,
     min_fuel: Option<f32>,
     extra_laps: Option<f32>,
     extra_fuel: Option<f32>,
     clear_tires: bool,
     take_tires: bool,
+    leave_tires: bool,
+    baseline_fuel: Option<f32>,
+    baseline_lap_time: Option<f32>,
+    provisional_tank_laps: Option<f32>,
+    db_session_limit: Option<u32>,
+    min_session_laps: Option<u32>,
+    exclude_blue_laps: bool,
+    session_tag: String,
+    exclude_tag: String,
+    laps_behind_leader: Option<f32>,
+    cooldown_laps: Option<i32>,
+    early_yellow_pit_laps: Option<i32>,
+    finish_reserve: Option<f32>,
+    fuel_use_margin: Option<f32>,
+    temp_unit: TempUnit,
+    track_temp_source: TrackTempSource,
+    fuel_round: FuelRound,
+    refuel_policy: RefuelPolicy,
+    pit_stop_loss: Option<f32>,
+    measured_pit_loss: bool,
+    pit_approach_ticks: Option<u32>,
+    commit_to_pit: bool,
+    incident_limit: Option<i32>,
+    ui_scale: Option<f32>,
+    theme: Theme,
+    compact_dash: bool,
+    restore_view: bool,
+    combined_car: bool,
+    opponent_fuel: bool,
+    lap_race_finish: bool,
+    debug: bool,
 }
 impl EditableSettings {
     fn load(&mut self, s: &UserSettings) {
         self.max_fuel_save = Some(s.max_fuel_save);
-        self.min_fuel = Some(s.min_fuel);
+        self.max_realistic_save = Some(s.max_realistic_save);
+        self.min_fuel = s.min_fuel;
         self.extra_laps = Some(s.extra_laps);
         self.extra_fuel = Some(s.extra_fuel);
         self.clear_tires = s.clear_tires;
         self.take_tires = s.take_tires;
+        self.leave_tires = s.leave_tires;
+        self.baseline_fuel = s.baseline_fuel;
+        self.baseline_lap_time = s.baseline_lap_time;
+        self.provisional_tank_laps = Some(s.provisional_tank_laps);
+        self.db_session_limit = s.db_session_limit;
+        self.min_session_laps = Some(s.min_session_laps);
+        self.exclude_blue_laps = s.exclude_blue_laps;
+        self.session_tag = s.session_tag.clone();
+        self.exclude_tag = s.exclude_tag.clone();
+        self.laps_behind_leader = Some(s.laps_behind_leader);
+        self.cooldown_laps = Some(s.cooldown_laps);
+        self.early_yellow_pit_laps = Some(s.early_yellow_pit_laps);
+        self.finish_reserve = Some(s.finish_reserve);
+        self.fuel_use_margin = Some(s.fuel_use_margin);
+        self.temp_unit = s.temp_unit;
+        self.track_temp_source = s.track_temp_source;
+        self.fuel_round = s.fuel_round;
+        self.refuel_policy = s.refuel_policy;
+        self.pit_stop_loss = Some(s.pit_stop_loss);
+        self.measured_pit_loss = s.measured_pit_loss;
+        self.pit_approach_ticks = Some(s.pit_approach_ticks);
+        self.commit_to_pit = s.commit_to_pit;
+        self.incident_limit = s.incident_limit;
+        self.ui_scale = Some(s.ui_scale);
+        self.theme = s.theme;
+        self.compact_dash = s.compact_dash;
+        self.restore_view = s.restore_view;
+        self.combined_car = s.combined_car;
+        self.opponent_fuel = s.opponent_fuel;
+        self.lap_race_finish = s.lap_race_finish;
+        self.debug = s.debug;
     }
     fn update(&self, s: &mut UserSettings) {
         if let Some(m) = self.max_fuel_save {
             s.max_fuel_save = m;
         }
-        if let Some(m) = self.min_fuel {
-            s.min_fuel = m;
+        if let Some(m) = self.max_realistic_save {
+            s.max_realistic_save = m;
         }
         if let Some(m) = self.extra_laps {
             s.extra_laps = m;
@@ -208,20 +589,109 @@ impl EditableSettings {
         if let Some(m) = self.extra_fuel {
             s.extra_fuel = m;
         }
+        if let Some(m) = self.laps_behind_leader {
+            s.laps_behind_leader = m;
+        }
+        if let Some(m) = self.ui_scale {
+            s.ui_scale = m;
+        }
+        if let Some(m) = self.pit_approach_ticks {
+            s.pit_approach_ticks = m.max(1);
+        }
+        s.commit_to_pit = self.commit_to_pit;
+        if let Some(m) = self.pit_stop_loss {
+            s.pit_stop_loss = m.max(0.0);
+        }
+        s.measured_pit_loss = self.measured_pit_loss;
+        if let Some(m) = self.provisional_tank_laps {
+            s.provisional_tank_laps = m.max(0.0);
+        }
+        if let Some(m) = self.finish_reserve {
+            s.finish_reserve = m.max(0.0);
+        }
+        if let Some(m) = self.fuel_use_margin {
+            s.fuel_use_margin = m.clamp(0.0, 0.5);
+        }
+        if let Some(m) = self.min_session_laps {
+            s.min_session_laps = m.max(1);
+        }
+        if let Some(m) = self.cooldown_laps {
+            s.cooldown_laps = m.max(0);
+        }
+        if let Some(m) = self.early_yellow_pit_laps {
+            s.early_yellow_pit_laps = m.max(0);
+        }
         s.clear_tires = self.clear_tires;
         s.take_tires = self.take_tires;
+        s.leave_tires = self.leave_tires;
+        s.exclude_blue_laps = self.exclude_blue_laps;
+        s.session_tag = self.session_tag.trim().to_string();
+        s.exclude_tag = self.exclude_tag.trim().to_string();
+        s.compact_dash = self.compact_dash;
+        s.restore_view = self.restore_view;
+        s.combined_car = self.combined_car;
+        s.opponent_fuel = self.opponent_fuel;
+        s.lap_race_finish = self.lap_race_finish;
+        s.theme = self.theme;
+        s.temp_unit = self.temp_unit;
+        s.track_temp_source = self.track_temp_source;
+        s.fuel_round = self.fuel_round;
+        s.refuel_policy = self.refuel_policy;
+        // an empty limit turns it off
+        s.incident_limit = self.incident_limit;
+        s.debug = self.debug;
+        // an empty baseline turns it off
+        s.baseline_fuel = self.baseline_fuel;
+        s.baseline_lap_time = self.baseline_lap_time;
+        // an empty min fuel uses the default for the car
+        s.min_fuel = self.min_fuel;
+        // an empty limit uses laps from all sessions
+        s.db_session_limit = self.db_session_limit;
     }
 }
 
 fn build_settings_widget() -> impl Widget<UiState> {
-    let mut w = GridWidget::new(2, 7);
+    let mut w = GridWidget::new(2, 40);
     for (r, s) in [
         "Max Fuel Save",
+        "Realistic Save",
         "Min Fuel",
         "Extra Laps",
         "Min Extra Fuel",
         "Clear Tires",
         "Take Tires",
+        "Leave Tires",
+        "Baseline Fuel",
+        "Baseline Lap Secs",
+        "Provisional Tank Laps",
+        "DB Sessions",
+        "Min Session Laps",
+        "Exclude Blue Laps",
+        "Session Tag",
+        "Exclude Tag",
+        "Laps Behind Leader",
+        "Cooldown Laps",
+        "Finish Reserve",
+        "Fuel Use Margin",
+        "Track Temp",
+        "Track Temp From",
+        "Refuel",
+        "Fuel Rounding",
+        "Pit Loss Secs",
+        "Measured Pit Loss",
+        "Pit Entry Ticks",
+        "Commit To Pit",
+        "Early Yellow Pit",
+        "Incident Limit",
+        "UI Scale",
+        "Theme",
+        "Compact Dash",
+        "Restore View",
+        "Combined Car",
+        "Opponent Fuel",
+        "Lap Race Finish",
+        "Debug",
+        "Share",
     ]
     .into_iter()
     .enumerate()
@@ -232,8 +702,8 @@ fn build_settings_widget() -> impl Widget<UiState> {
             lbl(s, UnitPoint::RIGHT).padding(6.0).border(GRID, GWIDTH),
         );
     }
-    fn edit_box() -> impl Widget<Option<f32>> {
-        Parse::new(TextBox::new().with_text_size(LABEL_TEXT_SIZE).align_left())
+    fn edit_box<T: FromStr + Display + Data>() -> impl Widget<Option<T>> {
+        Parse::new(TextBox::new().with_text_size(LABEL_SIZE_KEY).align_left())
     }
     let mut row = 0;
     w.set(
@@ -246,6 +716,16 @@ fn build_settings_widget() -> impl Widget<UiState> {
             .border(GRID, GWIDTH),
     );
     row += 1;
+    w.set(
+        1,
+        row,
+        edit_box()
+            .lens(EditableSettings::max_realistic_save)
+            .lens(UiState::settings_editor)
+            .padding(6.0)
+            .border(GRID, GWIDTH),
+    );
+    row += 1;
     w.set(
         1,
         row,
@@ -285,6 +765,7 @@ fn build_settings_widget() -> impl Widget<UiState> {
                 data.clear_tires = !data.clear_tires;
                 if data.clear_tires {
                     data.take_tires = false;
+                    data.leave_tires = false;
                 }
             })
             .lens(UiState::settings_editor)
@@ -302,6 +783,7 @@ fn build_settings_widget() -> impl Widget<UiState> {
                 data.take_tires = !data.take_tires;
                 if data.take_tires {
                     data.clear_tires = false;
+                    data.leave_tires = false;
                 }
             })
             .lens(UiState::settings_editor)
@@ -311,151 +793,721 @@ fn build_settings_widget() -> impl Widget<UiState> {
     );
     row += 1;
     w.set(
-        0,
+        1,
         row,
-        Button::from_label(Label::new("Cancel").with_text_size(LABEL_TEXT_SIZE))
-            .align_right()
+        Checkbox::new("")
+            .lens(EditableSettings::leave_tires)
+            .on_click(|_ctx, data, _env| {
+                data.leave_tires = !data.leave_tires;
+                if data.leave_tires {
+                    data.clear_tires = false;
+                    data.take_tires = false;
+                }
+            })
+            .lens(UiState::settings_editor)
+            .align_left()
             .padding(6.0)
-            .on_click(|_ctx, data: &mut UiState, _env| {
-                data.show_settings = false;
-            }),
+            .border(GRID, GWIDTH),
     );
+    row += 1;
     w.set(
         1,
         row,
-        Button::from_label(Label::new("Save").with_text_size(LABEL_TEXT_SIZE))
-            .align_left()
+        edit_box()
+            .lens(EditableSettings::baseline_fuel)
+            .lens(UiState::settings_editor)
             .padding(6.0)
-            .on_click(|_ctx, data: &mut UiState, _env| {
-                data.settings_editor.update(&mut data.settings);
-                let _ = data.settings.save(ircalc::default_settings_file());
-                data.show_settings = false;
-            }),
+            .border(GRID, GWIDTH),
     );
-
-    w
-}
-
-fn build_active_dash() -> impl Widget<UiState> {
-    let mut w = GridWidget::new(4, 8);
-    w.set_col_width(0, 150.0);
-    w.set_col_width(2, 175.0);
-    w.set_row_height(0, 45.0);
-    w.set_row_height(3, 15.0);
+    row += 1;
     w.set(
-        0,
-        0,
-        Button::new("S")
+        1,
+        row,
+        edit_box()
+            .lens(EditableSettings::baseline_lap_time)
+            .lens(UiState::settings_editor)
             .padding(6.0)
-            .on_click(|_, data: &mut UiState, _| {
-                data.settings_editor.load(&data.settings);
-                data.show_settings = true;
-            })
             .border(GRID, GWIDTH),
     );
-    for (r, s) in ["Car", "Race", "", "Last Lap", "Average"]
-        .into_iter()
-        .enumerate()
-    {
-        if !s.is_empty() {
-            w.set(
-                0,
-                r + 1,
-                lbl(s, UnitPoint::LEFT)
-                    .padding(Insets::new(6.0, 0.0, 0.0, 0.0))
-                    .border(GRID, GWIDTH),
-            );
-        } else {
-            w.set(0, r + 1, SizedBox::empty().width(10.0).height(10.0));
-        }
-    }
-
-    for (i, s) in ["Fuel", "Laps", "Time"].into_iter().enumerate() {
-        w.set(i + 1, 0, lbl(s, UnitPoint::CENTER).border(GRID, GWIDTH));
-    }
-    let fmt_f32 = |f: &f32, _e: &Env| format!("{:.2}", f);
-    let fmt_f32_blank_zero = |f: &f32, _e: &Env| {
-        if *f > 0.0 {
-            format!("{:.2}", f)
-        } else {
-            String::new()
-        }
-    };
-    let fmt_lap = |f: &f32, _: &Env| format!("{:.1}", f);
-    let fmt_i32 = |f: &i32, _e: &Env| format!("{:}", f);
-    let fmt_ps = |f: &Option<strat::Pitstop>, _e: &Env| match f {
-        None => "".to_string(),
-        Some(ps) => {
-            if ps.is_open() {
-                format!("{}", ps.close)
-            } else {
-                format!("{}-{}", ps.open, ps.close)
-            }
-        }
-    };
-    let fmt_tm = |f: &AmountLeft, _e: &Env| format!("{}", f.time);
+    row += 1;
     w.set(
         1,
+        row,
+        edit_box()
+            .lens(EditableSettings::provisional_tank_laps)
+            .lens(UiState::settings_editor)
+            .padding(6.0)
+            .border(GRID, GWIDTH),
+    );
+    row += 1;
+    w.set(
         1,
-        val(fmt_f32, None)
-            .lens(Estimation::car.then(AmountLeft::fuel))
-            .border(GRID, GWIDTH)
-            .background(COLOR_BG_KEY)
-            .env_scope(|env, data| {
-                env.set(
-                    COLOR_BG_KEY,
-                    colorer(data.connected, data.car.fuel, data.race.fuel, 1.0),
-                )
-            })
-            .lens(UiState::online),
+        row,
+        edit_box()
+            .lens(EditableSettings::db_session_limit)
+            .lens(UiState::settings_editor)
+            .padding(6.0)
+            .border(GRID, GWIDTH),
     );
+    row += 1;
     w.set(
-        2,
         1,
-        val(fmt_lap, None)
-            .lens(Estimation::car.then(AmountLeft::laps))
-            .border(GRID, GWIDTH)
-            .background(COLOR_BG_KEY)
-            .env_scope(|env, data| {
-                env.set(
-                    COLOR_BG_KEY,
-                    colorer(data.connected, data.car.laps, data.race.laps, 0.0),
-                )
-            })
-            .lens(UiState::online),
+        row,
+        edit_box()
+            .lens(EditableSettings::min_session_laps)
+            .lens(UiState::settings_editor)
+            .padding(6.0)
+            .border(GRID, GWIDTH),
     );
+    row += 1;
     w.set(
-        3,
         1,
-        val(fmt_tm, None)
-            .lens(Estimation::car)
-            .border(GRID, GWIDTH)
-            .background(COLOR_BG_KEY)
-            .env_scope(|env, data| {
-                env.set(
-                    COLOR_BG_KEY,
-                    colorer(
-                        data.connected,
-                        data.car.time,
-                        data.race.time,
-                        TimeSpan::ZERO,
-                    ),
-                )
-            })
-            .lens(UiState::online),
+        row,
+        Checkbox::new("")
+            .lens(EditableSettings::exclude_blue_laps)
+            .lens(UiState::settings_editor)
+            .align_left()
+            .padding(6.0)
+            .border(GRID, GWIDTH),
     );
+    row += 1;
     w.set(
         1,
-        2,
-        val(fmt_f32, None)
-            .lens(Estimation::race.then(AmountLeft::fuel))
-            .border(GRID, GWIDTH)
-            .lens(UiState::online),
+        row,
+        TextBox::new()
+            .with_text_size(LABEL_SIZE_KEY)
+            .align_left()
+            .lens(EditableSettings::session_tag)
+            .lens(UiState::settings_editor)
+            .padding(6.0)
+            .border(GRID, GWIDTH),
     );
+    row += 1;
     w.set(
-        2,
-        2,
-        val(fmt_lap, Some(KeyOrValue::Key(COLOR_KEY)))
+        1,
+        row,
+        TextBox::new()
+            .with_text_size(LABEL_SIZE_KEY)
+            .align_left()
+            .lens(EditableSettings::exclude_tag)
+            .lens(UiState::settings_editor)
+            .padding(6.0)
+            .border(GRID, GWIDTH),
+    );
+    row += 1;
+    w.set(
+        1,
+        row,
+        edit_box()
+            .lens(EditableSettings::laps_behind_leader)
+            .lens(UiState::settings_editor)
+            .padding(6.0)
+            .border(GRID, GWIDTH),
+    );
+    row += 1;
+    w.set(
+        1,
+        row,
+        edit_box()
+            .lens(EditableSettings::cooldown_laps)
+            .lens(UiState::settings_editor)
+            .padding(6.0)
+            .border(GRID, GWIDTH),
+    );
+    row += 1;
+    w.set(
+        1,
+        row,
+        edit_box()
+            .lens(EditableSettings::finish_reserve)
+            .lens(UiState::settings_editor)
+            .padding(6.0)
+            .border(GRID, GWIDTH),
+    );
+    row += 1;
+    w.set(
+        1,
+        row,
+        edit_box()
+            .lens(EditableSettings::fuel_use_margin)
+            .lens(UiState::settings_editor)
+            .padding(6.0)
+            .border(GRID, GWIDTH),
+    );
+    row += 1;
+    w.set(
+        1,
+        row,
+        DropdownSelect::new([TempUnit::C, TempUnit::F].map(|u| (u.to_string(), u)))
+            .align_left()
+            .lens(EditableSettings::temp_unit)
+            .lens(UiState::settings_editor)
+            .padding(6.0)
+            .border(GRID, GWIDTH),
+    );
+    row += 1;
+    w.set(
+        1,
+        row,
+        DropdownSelect::new(
+            [TrackTempSource::Crew, TrackTempSource::Sensor].map(|s| (s.to_string(), s)),
+        )
+        .align_left()
+        .lens(EditableSettings::track_temp_source)
+        .lens(UiState::settings_editor)
+        .padding(6.0)
+        .border(GRID, GWIDTH),
+    );
+    row += 1;
+    w.set(
+        1,
+        row,
+        DropdownSelect::new(
+            [
+                RefuelPolicy::Planned,
+                RefuelPolicy::BufferLaps,
+                RefuelPolicy::FillTank,
+            ]
+            .map(|p| (p.to_string(), p)),
+        )
+        .align_left()
+        .lens(EditableSettings::refuel_policy)
+        .lens(UiState::settings_editor)
+        .padding(6.0)
+        .border(GRID, GWIDTH),
+    );
+    row += 1;
+    w.set(
+        1,
+        row,
+        DropdownSelect::new(
+            [FuelRound::Ceil, FuelRound::Nearest, FuelRound::Exact].map(|r| (r.to_string(), r)),
+        )
+        .align_left()
+        .lens(EditableSettings::fuel_round)
+        .lens(UiState::settings_editor)
+        .padding(6.0)
+        .border(GRID, GWIDTH),
+    );
+    row += 1;
+    w.set(
+        1,
+        row,
+        edit_box()
+            .lens(EditableSettings::pit_stop_loss)
+            .lens(UiState::settings_editor)
+            .padding(6.0)
+            .border(GRID, GWIDTH),
+    );
+    row += 1;
+    w.set(
+        1,
+        row,
+        Checkbox::new("")
+            .lens(EditableSettings::measured_pit_loss)
+            .lens(UiState::settings_editor)
+            .align_left()
+            .padding(6.0)
+            .border(GRID, GWIDTH),
+    );
+    row += 1;
+    w.set(
+        1,
+        row,
+        edit_box()
+            .lens(EditableSettings::pit_approach_ticks)
+            .lens(UiState::settings_editor)
+            .padding(6.0)
+            .border(GRID, GWIDTH),
+    );
+    row += 1;
+    w.set(
+        1,
+        row,
+        Checkbox::new("")
+            .lens(EditableSettings::commit_to_pit)
+            .lens(UiState::settings_editor)
+            .align_left()
+            .padding(6.0)
+            .border(GRID, GWIDTH),
+    );
+    row += 1;
+    w.set(
+        1,
+        row,
+        edit_box()
+            .lens(EditableSettings::early_yellow_pit_laps)
+            .lens(UiState::settings_editor)
+            .padding(6.0)
+            .border(GRID, GWIDTH),
+    );
+    row += 1;
+    w.set(
+        1,
+        row,
+        edit_box()
+            .lens(EditableSettings::incident_limit)
+            .lens(UiState::settings_editor)
+            .padding(6.0)
+            .border(GRID, GWIDTH),
+    );
+    row += 1;
+    w.set(
+        1,
+        row,
+        edit_box()
+            .lens(EditableSettings::ui_scale)
+            .lens(UiState::settings_editor)
+            .padding(6.0)
+            .border(GRID, GWIDTH),
+    );
+    row += 1;
+    w.set(
+        1,
+        row,
+        DropdownSelect::new([Theme::Standard, Theme::ColorBlind].map(|t| (t.to_string(), t)))
+            .align_left()
+            .lens(EditableSettings::theme)
+            .lens(UiState::settings_editor)
+            .padding(6.0)
+            .border(GRID, GWIDTH),
+    );
+    row += 1;
+    w.set(
+        1,
+        row,
+        Checkbox::new("")
+            .lens(EditableSettings::compact_dash)
+            .lens(UiState::settings_editor)
+            .align_left()
+            .padding(6.0)
+            .border(GRID, GWIDTH),
+    );
+    row += 1;
+    w.set(
+        1,
+        row,
+        Checkbox::new("")
+            .lens(EditableSettings::restore_view)
+            .lens(UiState::settings_editor)
+            .align_left()
+            .padding(6.0)
+            .border(GRID, GWIDTH),
+    );
+    row += 1;
+    w.set(
+        1,
+        row,
+        Checkbox::new("")
+            .lens(EditableSettings::combined_car)
+            .lens(UiState::settings_editor)
+            .align_left()
+            .padding(6.0)
+            .border(GRID, GWIDTH),
+    );
+    row += 1;
+    w.set(
+        1,
+        row,
+        Checkbox::new("")
+            .lens(EditableSettings::opponent_fuel)
+            .lens(UiState::settings_editor)
+            .align_left()
+            .padding(6.0)
+            .border(GRID, GWIDTH),
+    );
+    row += 1;
+    w.set(
+        1,
+        row,
+        Checkbox::new("")
+            .lens(EditableSettings::lap_race_finish)
+            .lens(UiState::settings_editor)
+            .align_left()
+            .padding(6.0)
+            .border(GRID, GWIDTH),
+    );
+    row += 1;
+    w.set(
+        1,
+        row,
+        Flex::row()
+            .with_child(
+                Checkbox::new("")
+                    .lens(EditableSettings::debug)
+                    .lens(UiState::settings_editor),
+            )
+            .with_spacer(20.0)
+            .with_child(
+                // writes all the telemetry vars to the log file, for bug reports about
+                // missing or renamed vars.
+                Button::new("Dump Vars")
+                    .on_click(|_ctx, data: &mut UiState, _env| {
+                        data.online.dump_vars = true;
+                    })
+                    .disabled_if(|data: &UiState, _env| {
+                        !(data.settings.debug && data.online.connected)
+                    }),
+            )
+            .align_left()
+            .padding(6.0)
+            .border(GRID, GWIDTH),
+    );
+    row += 1;
+    w.set(
+        1,
+        row,
+        // swap settings with teammates via a file in the naf_calc folder, an import
+        // only fills in the editor, it still needs saving.
+        Flex::row()
+            .with_child(
+                Button::new("Export").on_click(|_ctx, data: &mut UiState, _env| {
+                    if let Some(f) = ircalc::default_export_file() {
+                        data.settings_error = match data.settings.export_to(&f) {
+                            Ok(_) => None,
+                            Err(e) => Some(format!("Failed to export settings: {}", e)),
+                        };
+                    }
+                }),
+            )
+            .with_spacer(20.0)
+            .with_child(
+                Button::new("Import").on_click(|_ctx, data: &mut UiState, _env| {
+                    if let Some(f) = ircalc::default_export_file() {
+                        data.settings_error = match UserSettings::import_from(&f) {
+                            Ok(s) => {
+                                data.settings_editor.load(&s);
+                                None
+                            }
+                            Err(e) => Some(format!("Failed to import settings: {}", e)),
+                        };
+                    }
+                }),
+            )
+            .align_left()
+            .padding(6.0)
+            .border(GRID, GWIDTH),
+    );
+    row += 1;
+    w.set(
+        0,
+        row,
+        Button::from_label(Label::new("Cancel").with_text_size(LABEL_SIZE_KEY))
+            .align_right()
+            .padding(6.0)
+            .on_click(|_ctx, data: &mut UiState, _env| {
+                data.show_settings = false;
+            }),
+    );
+    w.set(
+        1,
+        row,
+        Button::from_label(Label::new("Save").with_text_size(LABEL_SIZE_KEY))
+            .align_left()
+            .padding(6.0)
+            .on_click(|_ctx, data: &mut UiState, _env| {
+                data.settings_editor.update(&mut data.settings);
+                data.settings_error = match data.settings.save(ircalc::default_settings_file()) {
+                    Ok(_) => None,
+                    Err(e) => {
+                        warn!("failed to save settings {:?}", e);
+                        Some(format!("Failed to save settings: {}", e))
+                    }
+                };
+                if data.offline.db_session_limit != data.settings.db_session_limit
+                    || data.offline.tag_filter != data.settings.tag_filter()
+                    || data.offline.exclude_blue_laps != data.settings.exclude_blue_laps
+                {
+                    data.offline.db_session_limit = data.settings.db_session_limit;
+                    data.offline.tag_filter = data.settings.tag_filter();
+                    data.offline.exclude_blue_laps = data.settings.exclude_blue_laps;
+                    data.offline.on_session_change();
+                    data.offline.recalc();
+                }
+                data.show_settings = false;
+            }),
+    );
+    // there are more settings than fit in the window
+    Scroll::new(w).vertical()
+}
+
+fn build_reconnecting_widget() -> impl Widget<UiState> {
+    Flex::column()
+        .with_child(lbl("Waiting for iRacing...", UnitPoint::CENTER))
+        .with_spacer(20.0)
+        .with_child(
+            Button::from_label(Label::new("Planner").with_text_size(LABEL_SIZE_KEY)).on_click(
+                |_ctx, data: &mut UiState, _env| {
+                    data.online.reconnecting = false;
+                },
+            ),
+        )
+        .center()
+}
+
+// changes the live max fuel save override on the dash by delta.
+fn step_fuel_save(data: &mut UiState, delta: f32) {
+    let current = data
+        .online
+        .max_fuel_save
+        .unwrap_or(data.settings.max_fuel_save);
+    data.online.max_fuel_save = Some((current + delta).clamp(0.0, 0.5));
+}
+
+// highlights the incident count as it approaches the limit.
+fn incident_color(env: &Env, incidents: i32, limit: Option<i32>) -> Color {
+    match limit {
+        Some(l) if l > 0 => {
+            if incidents >= l {
+                env.get(BAD_COLOR_KEY)
+            } else if incidents * 4 >= l * 3 {
+                env.get(WARN_COLOR_KEY)
+            } else {
+                COLOR_CLEAR
+            }
+        }
+        _ => COLOR_CLEAR,
+    }
+}
+
+// the cells shared by the full and compact dashboards.
+fn car_fuel_cell() -> impl Widget<UiState> {
+    val(|f: &f32, _: &Env| format!("{:.2}", f), None)
+        .lens(Estimation::car.then(AmountLeft::fuel))
+        .border(GRID, GWIDTH)
+        .background(COLOR_BG_KEY)
+        .env_scope(|env, data| {
+            env.set(
+                COLOR_BG_KEY,
+                colorer(env, data.connected, data.car.fuel, data.race.fuel, 1.0),
+            )
+        })
+        .lens(UiState::online)
+}
+fn car_laps_cell() -> impl Widget<UiState> {
+    val(
+        |e: &Estimation, _: &Env| {
+            if e.car_laps_with_yellow - e.car.laps > 0.05 {
+                // show how much further we'll get with the remaining caution laps
+                format!("{:.1} ({:.1})", e.car.laps, e.car_laps_with_yellow)
+            } else {
+                format!("{:.1}", e.car.laps)
+            }
+        },
+        None,
+    )
+    .border(GRID, GWIDTH)
+    .background(COLOR_BG_KEY)
+    .env_scope(|env, data| {
+        env.set(
+            COLOR_BG_KEY,
+            colorer(env, data.connected, data.car.laps, data.race.laps, 0.0),
+        )
+    })
+    .lens(UiState::online)
+}
+// the area of a timeline covered by a pit window, x is the position of the end of a lap.
+fn pit_window_rect(stop: &strat::Pitstop, x: impl Fn(i32) -> f64, y0: f64, y1: f64) -> Rect {
+    Rect::new(x(stop.open), y0, x(stop.close), y1)
+}
+// how far along the planner's timeline the end of this many laps is, from 0 to 1. On the
+// time axis it's by race time rather than laps.
+fn plan_pos(s: &strat::Strategy, lap: i32, time_axis: bool) -> f64 {
+    let pos = if time_axis {
+        let total = s.total_time().as_secs_f64();
+        if total > 0.0 {
+            s.time_at_lap(lap).as_secs_f64() / total
+        } else {
+            0.0
+        }
+    } else {
+        let laps = s.total_laps();
+        if laps > 0 {
+            lap as f64 / laps as f64
+        } else {
+            0.0
+        }
+    };
+    pos.clamp(0.0, 1.0)
+}
+// a local wall clock time, e.g. 14:05:30
+fn fmt_clock(t: &DateTime<Local>) -> String {
+    t.format("%H:%M:%S").to_string()
+}
+fn fmt_pitstop(d: &Estimation, _e: &Env) -> String {
+    match &d.next_stop {
+        None => "".to_string(),
+        Some(ps) => pit_window_text(ps, d.lap),
+    }
+}
+// the laps to go until the next stop's window opens/closes, when on race lap lap.
+fn pit_window_text(ps: &strat::RaceStop, lap: i32) -> String {
+    match ps.status(lap) {
+        PitWindow::OpensIn(open) => format!("{}-{}", open, ps.close - lap),
+        PitWindow::ClosesIn(close) => format!("{}", close),
+        PitWindow::Missed => "Missed".to_string(),
+    }
+}
+fn pit_window_cell() -> impl Widget<UiState> {
+    val(fmt_pitstop, None)
+        .border(GRID, GWIDTH)
+        .background(COLOR_BG_KEY)
+        .env_scope(|env, data| {
+            env.set(
+                COLOR_BG_KEY,
+                match data.next_stop.map(|ps| ps.status(data.lap)) {
+                    None => COLOR_CLEAR,
+                    Some(PitWindow::ClosesIn(close)) if close > 1 => env.get(GOOD_COLOR_KEY),
+                    Some(PitWindow::ClosesIn(_) | PitWindow::Missed) => env.get(BAD_COLOR_KEY),
+                    Some(PitWindow::OpensIn(_)) => Color::BLACK,
+                },
+            )
+        })
+        .lens(UiState::online)
+        .border(GRID, GWIDTH)
+}
+
+// just the fuel, laps of fuel left and the pit window, for a small second screen.
+fn build_compact_dash() -> impl Widget<UiState> {
+    let mut w = GridWidget::new(2, 3);
+    w.set_col_width(0, 150.0);
+    for (r, s) in ["Fuel", "Laps", "Pits"].into_iter().enumerate() {
+        w.set(
+            0,
+            r,
+            lbl(s, UnitPoint::LEFT)
+                .padding(Insets::new(6.0, 0.0, 0.0, 0.0))
+                .border(GRID, GWIDTH),
+        );
+    }
+    w.set(1, 0, car_fuel_cell());
+    w.set(1, 1, car_laps_cell());
+    w.set(1, 2, pit_window_cell());
+    w
+}
+
+fn build_active_dash(combined_car: bool) -> impl Widget<UiState> {
+    let mut w = GridWidget::new(4, 13);
+    w.set_col_width(0, 150.0);
+    w.set_col_width(2, 175.0);
+    w.set_row_height(0, 45.0);
+    w.set_row_height(3, 15.0);
+    w.set(
+        0,
+        0,
+        Button::new("S")
+            .padding(6.0)
+            .on_click(|_, data: &mut UiState, _| {
+                data.settings_editor.load(&data.settings);
+                data.show_settings = true;
+            })
+            .border(GRID, GWIDTH),
+    );
+    for (r, s) in ["Car", "Race", "", "Last Lap", "Average"]
+        .into_iter()
+        .enumerate()
+    {
+        if s == "Average" {
+            // per lap figures from coarse fuel data are only approximate
+            w.set(
+                0,
+                r + 1,
+                lbl(
+                    |d: &UiState, _: &Env| {
+                        if d.online.coarse_fuel {
+                            "Average ~".to_string()
+                        } else {
+                            "Average".to_string()
+                        }
+                    },
+                    UnitPoint::LEFT,
+                )
+                .padding(Insets::new(6.0, 0.0, 0.0, 0.0))
+                .border(GRID, GWIDTH),
+            );
+        } else if !s.is_empty() {
+            w.set(
+                0,
+                r + 1,
+                lbl(s, UnitPoint::LEFT)
+                    .padding(Insets::new(6.0, 0.0, 0.0, 0.0))
+                    .border(GRID, GWIDTH),
+            );
+        } else {
+            w.set(0, r + 1, SizedBox::empty().width(10.0).height(10.0));
+        }
+    }
+
+    for (i, s) in ["Fuel", "Laps", "Time"].into_iter().enumerate() {
+        w.set(i + 1, 0, lbl(s, UnitPoint::CENTER).border(GRID, GWIDTH));
+    }
+    let fmt_f32 = |f: &f32, _e: &Env| format!("{:.2}", f);
+    let fmt_f32_blank_zero = |f: &f32, _e: &Env| {
+        if *f > 0.0 {
+            format!("{:.2}", f)
+        } else {
+            String::new()
+        }
+    };
+    let fmt_lap = |f: &f32, _: &Env| format!("{:.1}", f);
+    let fmt_i32 = |f: &i32, _e: &Env| format!("{:}", f);
+    let fmt_tm = |f: &AmountLeft, _e: &Env| format!("{}", f.time);
+    if combined_car {
+        // everything left in the car on one line
+        w.set_span(
+            1,
+            1,
+            3,
+            1,
+            lbl(|d: &AmountLeft, _: &Env| d.combined(), UnitPoint::CENTER)
+                .lens(Estimation::car)
+                .border(GRID, GWIDTH)
+                .background(COLOR_BG_KEY)
+                .env_scope(|env, data| {
+                    env.set(
+                        COLOR_BG_KEY,
+                        colorer(env, data.connected, data.car.laps, data.race.laps, 0.0),
+                    )
+                })
+                .lens(UiState::online),
+        );
+    } else {
+        w.set(1, 1, car_fuel_cell());
+        w.set(2, 1, car_laps_cell());
+        w.set(
+            3,
+            1,
+            val(fmt_tm, None)
+                .lens(Estimation::car)
+                .border(GRID, GWIDTH)
+                .background(COLOR_BG_KEY)
+                .env_scope(|env, data| {
+                    env.set(
+                        COLOR_BG_KEY,
+                        colorer(
+                            env,
+                            data.connected,
+                            data.car.time,
+                            data.race.time,
+                            TimeSpan::ZERO,
+                        ),
+                    )
+                })
+                .lens(UiState::online),
+        );
+    }
+    w.set(
+        1,
+        2,
+        val(fmt_f32, None)
+            .lens(Estimation::race.then(AmountLeft::fuel))
+            .border(GRID, GWIDTH)
+            .lens(UiState::online),
+    );
+    w.set(
+        2,
+        2,
+        val(fmt_lap, Some(KeyOrValue::Key(COLOR_KEY)))
             .lens(Estimation::race.then(AmountLeft::laps))
             .border(GRID, GWIDTH)
             .env_scope(|env, data| {
@@ -512,11 +1564,32 @@ fn build_active_dash() -> impl Widget<UiState> {
             .border(GRID, GWIDTH)
             .lens(UiState::online),
     );
+    // how much the average can be trusted, shows when it's just a default rate
+    let sample_size = Label::new(|d: &Estimation, _: &Env| match d.green_laps {
+        _ if d.provisional => "(provisional)".to_string(),
+        0 => "(default)".to_string(),
+        1 => "(1 lap)".to_string(),
+        n => format!("({} laps)", n),
+    })
+    .with_text_size(SMALL_SIZE_KEY)
+    .with_text_color(Color::grey8(200));
+    // are we using more or less than our average from previous sessions
+    let vs_average = Label::new(|d: &Estimation, _: &Env| match d.green_vs_average() {
+        Some(x) => format!("{:+.2}L vs avg", x),
+        None => "".to_string(),
+    })
+    .with_text_size(SMALL_SIZE_KEY)
+    .with_text_color(Color::grey8(200));
     w.set(
         1,
         5,
-        val(fmt_f32_blank_zero, None)
-            .lens(Estimation::green.then(Rate::fuel))
+        Flex::row()
+            .main_axis_alignment(MainAxisAlignment::Center)
+            .with_child(val(fmt_f32_blank_zero, None).lens(Estimation::green.then(Rate::fuel)))
+            .with_spacer(4.0)
+            .with_child(sample_size)
+            .with_spacer(4.0)
+            .with_child(vs_average)
             .border(GRID, GWIDTH)
             .lens(UiState::online),
     );
@@ -530,34 +1603,44 @@ fn build_active_dash() -> impl Widget<UiState> {
     w.set(
         3,
         5,
-        val(fmt_f32_blank_zero, None)
-            .lens(Estimation::save_target)
-            .border(GRID, GWIDTH)
-            .background(COLOR_BG_KEY)
-            .env_scope(|env, data| {
-                env.set(
-                    COLOR_BG_KEY,
-                    if data.save_target > 0.0 {
-                        if data.fuel_last_lap <= data.save_target {
-                            Color::GREEN
-                        } else {
-                            Color::BLUE
-                        }
+        Either::new(
+            |d: &Estimation, _: &Env| d.dash_save_target().1,
+            val(
+                move |d: &Estimation, e: &Env| fmt_f32_blank_zero(&d.dash_save_target().0, e),
+                None,
+            ),
+            lbl("Not achievable", UnitPoint::CENTER),
+        )
+        .border(GRID, GWIDTH)
+        .background(COLOR_BG_KEY)
+        .env_scope(|env, data| {
+            let (target, achievable) = data.dash_save_target();
+            env.set(
+                COLOR_BG_KEY,
+                if !achievable {
+                    env.get(BAD_COLOR_KEY)
+                } else if target > 0.0 {
+                    if data.fuel_last_lap <= target {
+                        env.get(GOOD_COLOR_KEY)
                     } else {
-                        COLOR_CLEAR
-                    },
-                )
-            })
-            .lens(UiState::online),
+                        env.get(INFO_COLOR_KEY)
+                    }
+                } else {
+                    COLOR_CLEAR
+                },
+            )
+        })
+        .lens(UiState::online),
     );
     w.set(
         0,
         6,
         lbl(
-            |d: &Option<strat::Pitstop>, _: &Env| {
-                match d {
+            |d: &Estimation, _: &Env| {
+                match d.next_stop {
+                    _ if d.last_lap => "Last Lap",
                     Some(ps) => {
-                        if ps.is_open() {
+                        if ps.is_open(d.lap) {
                             "Pits OPEN"
                         } else {
                             "Pits"
@@ -570,111 +1653,407 @@ fn build_active_dash() -> impl Widget<UiState> {
             UnitPoint::LEFT,
         )
         .padding(Insets::new(0.6, 0.0, 0.0, 0.0))
-        .lens(UiState::online.then(Estimation::next_stop))
+        .lens(UiState::online)
         .border(GRID, GWIDTH),
     );
+    w.set(1, 6, pit_window_cell());
     w.set(
-        1,
+        2,
+        6,
+        lbl("Stops", UnitPoint::RIGHT)
+            .padding(pad_right)
+            .border(GRID, GWIDTH),
+    );
+    w.set(
+        3,
         6,
-        val(fmt_ps, None)
+        val(fmt_i32, Some(KeyOrValue::Key(COLOR_KEY)))
+            .lens(Estimation::stops)
             .border(GRID, GWIDTH)
-            .background(COLOR_BG_KEY)
             .env_scope(|env, data| {
+                // a different stop count is pending
                 env.set(
-                    COLOR_BG_KEY,
-                    match data {
-                        None => COLOR_CLEAR,
-                        Some(ps) => {
-                            if ps.is_open() && ps.close <= 1 {
-                                Color::RED
-                            } else if ps.is_open() {
-                                Color::GREEN
-                            } else {
-                                Color::BLACK
-                            }
-                        }
+                    COLOR_KEY,
+                    if data.stops_stable {
+                        Color::WHITE
+                    } else {
+                        Color::grey8(150)
                     },
                 )
             })
-            .lens(UiState::online.then(Estimation::next_stop))
+            .lens(UiState::online),
+    );
+
+    w.set(
+        0,
+        7,
+        lbl("Trk Temp", UnitPoint::RIGHT)
+            .padding(pad_right)
             .border(GRID, GWIDTH),
     );
+    w.set(
+        1,
+        7,
+        val(
+            |d: &UiState, _e: &Env| {
+                let (f, unit) = (&d.online, d.settings.temp_unit);
+                format!(
+                    "{:0.1}  {:+0.1}",
+                    unit.convert(f.track_temp),
+                    unit.convert_delta(f.track_temp - f.start_track_temp)
+                )
+            },
+            None,
+        )
+        .background(COLOR_BG_KEY)
+        .env_scope(|env, data: &UiState| {
+            let unit = data.settings.temp_unit;
+            let delta = unit.convert_delta(data.online.track_temp - data.online.start_track_temp);
+            let threshold = unit.convert_delta(1.0);
+            env.set(
+                COLOR_BG_KEY,
+                if delta < -threshold {
+                    env.get(GOOD_COLOR_KEY)
+                } else if delta > threshold {
+                    env.get(BAD_COLOR_KEY)
+                } else {
+                    COLOR_CLEAR
+                },
+            )
+        })
+        .border(GRID, GWIDTH),
+    );
     w.set(
         2,
-        6,
-        lbl("Stops", UnitPoint::RIGHT)
+        7,
+        lbl("Time", UnitPoint::RIGHT)
             .padding(pad_right)
             .border(GRID, GWIDTH),
     );
     w.set(
         3,
-        6,
-        val(fmt_i32, None)
-            .lens(UiState::online.then(Estimation::stops))
+        7,
+        val(|f: &Estimation, _e: &Env| fmt_clock(&f.now), None)
+            .lens(UiState::online)
             .border(GRID, GWIDTH),
     );
-
     w.set(
         0,
-        7,
-        lbl("Trk Temp", UnitPoint::RIGHT)
+        8,
+        Flex::row()
+            .with_child(
+                // resends the fuel to add, e.g. after changing the pit settings in the car
+                Button::new("Send").on_click(|_ctx, data: &mut UiState, _env| {
+                    data.online.send_fuel = true;
+                }),
+            )
+            .with_flex_child(lbl("Add Fuel", UnitPoint::RIGHT), 1.0)
+            .padding(pad_right)
+            .border(GRID, GWIDTH),
+    );
+    w.set(
+        1,
+        8,
+        val(
+            // the whole liters sent to the car
+            |d: &UiState, _e: &Env| d.online.fuel_to_add.to_string(),
+            None,
+        )
+        .border(GRID, GWIDTH),
+    );
+    w.set(
+        2,
+        8,
+        lbl("Dry on Lap", UnitPoint::RIGHT)
+            .padding(pad_right)
+            .border(GRID, GWIDTH),
+    );
+    w.set(
+        3,
+        8,
+        val(
+            |f: &Option<i32>, _e: &Env| match f {
+                Some(l) => format!("{:}", l),
+                None => String::new(),
+            },
+            None,
+        )
+        .lens(UiState::online.then(Estimation::dry_lap))
+        .border(GRID, GWIDTH),
+    );
+    w.set(
+        0,
+        9,
+        lbl("Max Save", UnitPoint::RIGHT)
+            .padding(pad_right)
+            .border(GRID, GWIDTH),
+    );
+    w.set(
+        1,
+        9,
+        val(
+            |d: &UiState, _e: &Env| {
+                let save = d.online.max_fuel_save.unwrap_or(d.settings.max_fuel_save);
+                format!("{:.0}%", save * 100.0)
+            },
+            None,
+        )
+        .border(GRID, GWIDTH),
+    );
+    w.set(
+        2,
+        9,
+        Button::from_label(Label::new("-").with_text_size(LABEL_SIZE_KEY))
+            .on_click(|_ctx, data: &mut UiState, _env| step_fuel_save(data, -0.05))
+            .padding(2.0)
+            .border(GRID, GWIDTH),
+    );
+    w.set(
+        3,
+        9,
+        Button::from_label(Label::new("+").with_text_size(LABEL_SIZE_KEY))
+            .on_click(|_ctx, data: &mut UiState, _env| step_fuel_save(data, 0.05))
+            .padding(2.0)
+            .border(GRID, GWIDTH),
+    );
+    w.set(
+        0,
+        10,
+        lbl("Incidents", UnitPoint::RIGHT)
+            .padding(pad_right)
+            .border(GRID, GWIDTH),
+    );
+    w.set(
+        1,
+        10,
+        val(
+            |d: &UiState, _e: &Env| match d.settings.incident_limit {
+                Some(l) => format!("{} / {}", d.online.incidents, l),
+                None => format!("{}", d.online.incidents),
+            },
+            None,
+        )
+        .background(COLOR_BG_KEY)
+        .env_scope(|env, data: &UiState| {
+            env.set(
+                COLOR_BG_KEY,
+                incident_color(env, data.online.incidents, data.settings.incident_limit),
+            )
+        })
+        .border(GRID, GWIDTH),
+    );
+    w.set(
+        2,
+        10,
+        lbl("Finish", UnitPoint::RIGHT)
+            .padding(pad_right)
+            .border(GRID, GWIDTH),
+    );
+    w.set(
+        3,
+        10,
+        val(
+            |d: &UiState, _e: &Env| {
+                if d.settings.lap_race_finish && d.online.race_tm_estimated {
+                    fmt_clock(&d.online.estimated_finish)
+                } else {
+                    fmt_clock(&d.online.projected_finish)
+                }
+            },
+            None,
+        )
+        .border(GRID, GWIDTH),
+    );
+    w.set(
+        0,
+        11,
+        lbl("Caution", UnitPoint::RIGHT)
+            .padding(pad_right)
+            .border(GRID, GWIDTH),
+    );
+    w.set(
+        1,
+        11,
+        val(
+            |f: &Option<PitCall>, _e: &Env| match f {
+                Some(c) => c.to_string(),
+                None => String::new(),
+            },
+            None,
+        )
+        .background(COLOR_BG_KEY)
+        .env_scope(|env, data: &Option<PitCall>| {
+            env.set(
+                COLOR_BG_KEY,
+                match data {
+                    Some(PitCall::PitNow) => env.get(GOOD_COLOR_KEY),
+                    _ => COLOR_CLEAR,
+                },
+            )
+        })
+        .lens(UiState::online.then(Estimation::pit_call))
+        .border(GRID, GWIDTH),
+    );
+    w.set(
+        2,
+        11,
+        lbl("Laps Gain", UnitPoint::RIGHT)
+            .padding(pad_right)
+            .border(GRID, GWIDTH),
+    );
+    w.set(
+        3,
+        11,
+        val(
+            |f: &Option<f32>, _e: &Env| match f {
+                Some(g) => format!("{:+.1}", g),
+                None => String::new(),
+            },
+            None,
+        )
+        .lens(UiState::online.then(Estimation::yellow_pit_gain))
+        .border(GRID, GWIDTH),
+    );
+    w.set(
+        0,
+        12,
+        lbl("Stint Fuel", UnitPoint::RIGHT)
+            .padding(pad_right)
+            .border(GRID, GWIDTH),
+    );
+    w.set(
+        1,
+        12,
+        val(fmt_f32, None)
+            .lens(UiState::online.then(Estimation::stint_fuel_used))
+            .border(GRID, GWIDTH),
+    );
+    w.set(
+        2,
+        12,
+        lbl("Fuel Margin", UnitPoint::RIGHT)
             .padding(pad_right)
             .border(GRID, GWIDTH),
     );
     w.set(
-        1,
-        7,
+        3,
+        12,
+        // laps of fuel we have over the average opponent, blank without opponent data
         val(
-            |f: &Estimation, _e: &Env| {
-                format!(
-                    "{:0.1}  {:+0.1}",
-                    f.track_temp,
-                    f.track_temp - f.start_track_temp
-                )
+            |f: &Option<f32>, _e: &Env| match f {
+                Some(m) => format!("{:+.1}", m),
+                None => String::new(),
+            },
+            None,
+        )
+        .lens(UiState::online.then(Estimation::fuel_margin))
+        .border(GRID, GWIDTH),
+    );
+    let black_flag = Either::new(
+        |d: &Estimation, _e: &Env| d.black_flag.is_some(),
+        val(
+            |d: &Estimation, _e: &Env| match d.black_flag {
+                Some(f) => f.to_string(),
+                None => String::new(),
             },
             None,
         )
+        .expand_width()
         .background(COLOR_BG_KEY)
-        .env_scope(|env, data| {
-            let delta = data.track_temp - data.start_track_temp;
+        .env_scope(|env, data: &Estimation| {
             env.set(
                 COLOR_BG_KEY,
-                if delta < -1.0 {
-                    Color::GREEN
-                } else if delta > 1.0 {
-                    Color::RED
-                } else {
-                    COLOR_CLEAR
+                match data.black_flag {
+                    Some(BlackFlagKind::Repair) => Color::rgb8(255, 140, 0),
+                    _ => env.get(BAD_COLOR_KEY),
                 },
             )
-        })
-        .lens(UiState::online)
-        .border(GRID, GWIDTH),
-    );
-    w.set(
-        2,
-        7,
-        lbl("Time", UnitPoint::RIGHT)
-            .padding(pad_right)
-            .border(GRID, GWIDTH),
-    );
-    w.set(
-        3,
-        7,
+        }),
+        SizedBox::empty(),
+    )
+    .lens(UiState::online);
+    let pit_critical = Either::new(
+        |d: &Estimation, _e: &Env| d.pit_critical,
         val(
-            |f: &Estimation, _e: &Env| f.now.format("%H:%M:%S").to_string(),
+            |d: &Estimation, _e: &Env| {
+                format!(
+                    "Fuel for {:.1} laps, pit before the window opens",
+                    d.car.laps
+                )
+            },
             None,
         )
-        .lens(UiState::online)
-        .border(GRID, GWIDTH),
-    );
-    w
+        .expand_width()
+        .background(BAD_COLOR_KEY),
+        SizedBox::empty(),
+    )
+    .lens(UiState::online);
+    // the rest of the race with the upcoming pit windows.
+    let timeline = Painter::new(|ctx: &mut PaintCtx, data: &Estimation, env: &Env| {
+        let bounds = ctx
+            .size()
+            .to_rect()
+            .inset(Insets::new(-10.0, -5.0, -10.0, -5.0));
+        ctx.fill(bounds, &Color::rgb8(0, 64, 0));
+        let laps = data.race.laps as f64;
+        if laps > 0.0 {
+            // the timeline starts at the current lap
+            let x = |lap: i32| bounds.width() * (lap as f64 / laps).clamp(0.0, 1.0) + bounds.x0;
+            for stop in &data.all_stops {
+                let b = pit_window_rect(&stop.relative_to(data.lap), x, bounds.y0, bounds.y1);
+                ctx.fill(
+                    b,
+                    &if stop.is_open(data.lap) {
+                        env.get(GOOD_COLOR_KEY)
+                    } else {
+                        Color::grey8(160)
+                    },
+                );
+            }
+        }
+        ctx.stroke(bounds, &Color::GRAY, 1.0);
+    })
+    .fix_height(30.0)
+    .lens(UiState::online);
+    Flex::column()
+        .with_child(black_flag)
+        .with_child(pit_critical)
+        .with_flex_child(w, 1.0)
+        .with_child(timeline)
+}
+
+// about to run out of fuel, the dash is replaced with a banner that flashes so it can't
+// be missed.
+fn with_fuel_emergency(dash: impl Widget<UiState> + 'static) -> impl Widget<UiState> {
+    Either::new(
+        |d: &UiState, _e: &Env| d.online.fuel_emergency,
+        Label::new("OUT OF FUEL, PIT NOW")
+            .with_font(VALUE_FONT_KEY)
+            .center()
+            .expand()
+            .background(COLOR_BG_KEY)
+            .env_scope(|env, data: &Estimation| {
+                let flash = data.now.timestamp_subsec_millis() < 500;
+                env.set(
+                    COLOR_BG_KEY,
+                    if flash {
+                        env.get(BAD_COLOR_KEY)
+                    } else {
+                        Color::BLACK
+                    },
+                )
+            })
+            .lens(UiState::online),
+        dash,
+    )
 }
 
-#[derive(Data, Debug, Clone, Copy, PartialEq)]
+#[derive(Data, Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 enum UiView {
     Offline,
     Online,
+    Compact,
+    Reconnecting,
     Settings,
 }
 
@@ -685,6 +2064,9 @@ struct UiState {
     settings_editor: EditableSettings,
     settings: UserSettings,
     show_settings: bool,
+    settings_error: Option<String>, // the last error loading or saving the settings
+    forced_view: Option<UiView>,    // a view picked from the keyboard, None to pick automatically
+    last_view: Option<UiView>,      // the last of the dash or the planner to be shown
 }
 #[derive(Data, Lens, Clone, Debug, PartialEq)]
 struct OfflineState {
@@ -695,51 +2077,226 @@ struct OfflineState {
     time: Option<TimeSpan>,
     fuel_tank_size: Option<f32>,
     max_fuel_save: Option<f32>,
+    target_stops: Option<i32>,
+    expected_caution_laps: Option<i32>, // laps expected to be run under caution at the yellow rate
+    time_axis: bool,                    // draw the strategy bar against race time rather than laps
     #[data(same_fn = "PartialEq::eq")]
     strat: Option<strat::Strategy>,
+    db_session_limit: Option<u32>,
+    #[data(same_fn = "PartialEq::eq")]
+    tag_filter: Option<TagFilter>,
+    exclude_blue_laps: bool,
+    #[data(same_fn = "PartialEq::eq")]
+    laps_db: Option<PathBuf>,
 }
+// the offline planner inputs that are remembered between runs. The rates aren't
+// included as they come from the laps DB.
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+#[serde(default)]
+struct PlannerInputs {
+    car_id: Option<i64>,
+    track_id: Option<i64>,
+    laps: Option<i32>,
+    time_secs: Option<f64>,
+    fuel_tank_size: Option<f32>,
+    max_fuel_save: Option<f32>,
+    target_stops: Option<i32>,
+    expected_caution_laps: Option<i32>,
+    time_axis: bool,
+}
+
 impl OfflineState {
+    fn inputs(&self) -> PlannerInputs {
+        PlannerInputs {
+            car_id: Some(self.session.car_id),
+            track_id: Some(self.session.track_id),
+            laps: self.laps,
+            time_secs: self.time.map(|t| t.as_secs_f64()),
+            fuel_tank_size: self.fuel_tank_size,
+            max_fuel_save: self.max_fuel_save,
+            target_stops: self.target_stops,
+            expected_caution_laps: self.expected_caution_laps,
+            time_axis: self.time_axis,
+        }
+    }
+    // selects the saved session if it's still in sessions, and sets the inputs. The tank
+    // size and max fuel save fallback to the session's.
+    fn restore(&mut self, p: &PlannerInputs, sessions: &[RaceSession]) {
+        if let Some(s) = sessions
+            .iter()
+            .find(|s| Some(s.car_id) == p.car_id && Some(s.track_id) == p.track_id)
+        {
+            self.session = s.clone();
+        }
+        self.laps = p.laps;
+        // a hand edited file could have anything in it
+        self.time = p
+            .time_secs
+            .filter(|t| t.is_finite() && *t >= 0.0)
+            .map(TimeSpan::from_secs_f64);
+        self.fuel_tank_size = p.fuel_tank_size.or(Some(self.session.fuel_tank_size));
+        self.max_fuel_save = p.max_fuel_save.or(Some(self.session.max_fuel_save));
+        self.target_stops = p.target_stops;
+        self.expected_caution_laps = p.expected_caution_laps;
+        self.time_axis = p.time_axis;
+    }
+    // restores the inputs saved by save_inputs, and the rates for the session. If nothing
+    // has been saved yet, this is the same as on_session_change.
+    fn load_inputs(
+        &mut self,
+        path: Option<PathBuf>,
+        sessions: &[RaceSession],
+    ) -> Result<(), JsonLoadError> {
+        let file = match path.map(File::open) {
+            Some(Ok(f)) => f,
+            Some(Err(e)) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {
+                self.on_session_change();
+                return Ok(());
+            }
+        };
+        let p: PlannerInputs = serde_json::from_reader(BufReader::new(file))?;
+        self.restore(&p, sessions);
+        self.load_rates();
+        Ok(())
+    }
+    fn save_inputs(&self, path: Option<PathBuf>) -> Result<(), JsonLoadError> {
+        if let Some(p) = path {
+            let file = File::create(p)?;
+            serde_json::to_writer_pretty(file, &self.inputs())?;
+        }
+        Ok(())
+    }
     fn on_session_change(&mut self) {
         self.fuel_tank_size = Some(self.session.fuel_tank_size);
         self.max_fuel_save = Some(self.session.max_fuel_save);
-        let _ = history::Db::new(&ircalc::default_laps_db().unwrap()).map(|db| {
-            self.green = db.db_green_laps(self.session.car_id, self.session.track_id);
-            self.yellow = db.db_yellow_laps(self.session.car_id, self.session.track_id);
+        self.load_rates();
+    }
+    // loads the green & yellow rates for the session from the laps DB.
+    fn load_rates(&mut self) {
+        let _ = history::Db::new(self.laps_db.as_ref().unwrap()).map(|db| {
+            let (car_id, track_id) = (self.session.car_id, self.session.track_id);
+            let tags = self.tag_filter.as_ref();
+            let (limit, blue) = (self.db_session_limit, self.exclude_blue_laps);
+            self.green = db.db_green_laps(car_id, track_id, blue, limit, tags);
+            self.yellow = db.db_yellow_laps(car_id, track_id, self.db_session_limit, tags);
         });
     }
+    // why no plan can be made from the inputs, None if they're all usable.
+    fn problem(&self) -> Option<&'static str> {
+        if self.green.is_none() {
+            return Some("No green flag laps in the laps DB for this car & track");
+        }
+        if !valid_green(&self.green) {
+            return Some("The green flag lap rate from the laps DB isn't usable");
+        }
+        if self.laps.is_none() && self.time.is_none() {
+            return Some("Enter the race length in laps and/or time");
+        }
+        [
+            (valid_laps(&self.laps), "Laps should be between 1 and 10000"),
+            (valid_time(&self.time), "Time should be between 1m and 48h"),
+            (
+                self.fuel_tank_size.is_some() && valid_tank(&self.fuel_tank_size),
+                "Fuel tank size should be between 0.1L and 1000L",
+            ),
+            (
+                self.max_fuel_save.is_some() && valid_max_save(&self.max_fuel_save),
+                "Max save should be a fraction between 0 and 0.5",
+            ),
+            (
+                valid_stops(&self.target_stops),
+                "Stops should be between 0 and 100",
+            ),
+            (
+                valid_cautions(&self.expected_caution_laps),
+                "Cautions should be between 0 and 1000 laps",
+            ),
+            (
+                self.yellow.is_some() || self.expected_caution_laps.unwrap_or(0) == 0,
+                "No yellow flag laps in the laps DB to plan the cautions with",
+            ),
+        ]
+        .iter()
+        .find(|(ok, _)| !ok)
+        .map(|(_, msg)| *msg)
+    }
     fn recalc(&mut self) {
-        if self.fuel_tank_size.is_some()
+        if self.problem().is_some() {
+            self.strat = None;
+        } else if self.fuel_tank_size.is_some()
             && self.max_fuel_save.is_some()
             && (self.laps.is_some() || self.time.is_some())
             && self.green.is_some()
             && self.fuel_tank_size.unwrap() > 0.0
         {
-            let r = StratRequest {
-                fuel_left: self.fuel_tank_size.unwrap(),
-                tank_size: self.fuel_tank_size.unwrap(),
-                max_fuel_save: self.max_fuel_save.unwrap(),
-                min_fuel: self.session.min_fuel,
-                yellow_togo: 0,
-                ends: match (self.laps, &self.time) {
-                    (Some(l), None) => EndsWith::Laps(l),
-                    (None, Some(t)) => EndsWith::Time(*t),
-                    (Some(l), Some(t)) => EndsWith::LapsOrTime(l, *t),
-                    (None, None) => unreachable!(),
-                },
-                green: self.green.unwrap(),
-                yellow: Rate::default(),
+            let ends = match (self.laps, &self.time) {
+                (Some(l), None) => EndsWith::Laps(l),
+                (None, Some(t)) => EndsWith::Time(*t),
+                (Some(l), Some(t)) => EndsWith::LapsOrTime(l, *t),
+                (None, None) => unreachable!(),
+            };
+            let mut b =
+                StratRequestBuilder::new(self.fuel_tank_size.unwrap(), ends, self.green.unwrap())
+                    .max_fuel_save(self.max_fuel_save.unwrap())
+                    .min_fuel(self.session.min_fuel);
+            if let (Some(yellow), Some(cautions)) = (self.yellow, self.expected_caution_laps) {
+                b = b.yellow(yellow, cautions);
+            }
+            let r = b.build();
+            self.strat = match self.target_stops {
+                Some(n) => r.compute_for_stops(n),
+                None => r.compute().ok(),
             };
-            self.strat = r.compute();
         }
     }
 }
 
-fn build_offline_widget() -> impl Widget<UiState> {
-    let sessions = history::Db::new(&ircalc::default_laps_db().unwrap())
-        .map(|db| db.sessions())
-        .unwrap()
-        .unwrap();
-    let mut grid = GridWidget::new(3, 7);
+// the sane range for each planner input, None is valid here as it's an input that's
+// not been filled in yet.
+fn in_range<T: PartialOrd>(v: &Option<T>, min: T, max: T) -> bool {
+    match v {
+        Some(v) => *v >= min && *v <= max,
+        None => true,
+    }
+}
+fn valid_laps(v: &Option<i32>) -> bool {
+    in_range(v, 1, 10_000)
+}
+fn valid_time(v: &Option<TimeSpan>) -> bool {
+    in_range(v, TimeSpan::ONE_MIN, TimeSpan::from_secs_f64(48.0 * 3600.0))
+}
+fn valid_tank(v: &Option<f32>) -> bool {
+    in_range(v, 0.1, 1000.0)
+}
+// a fraction of the green lap fuel, the same range as the max save on the dash.
+fn valid_max_save(v: &Option<f32>) -> bool {
+    in_range(v, 0.0, 0.5)
+}
+fn valid_stops(v: &Option<i32>) -> bool {
+    in_range(v, 0, 100)
+}
+fn valid_cautions(v: &Option<i32>) -> bool {
+    in_range(v, 0, 1000)
+}
+fn valid_green(v: &Option<Rate>) -> bool {
+    match v {
+        Some(r) => r.fuel > 0.0 && r.time > TimeSpan::ZERO && r.time < TimeSpan::ONE_HR,
+        None => true,
+    }
+}
+
+fn build_offline_widget(laps_db: &Path, min_laps: u32) -> impl Widget<UiState> {
+    let sessions = history::Db::new(laps_db)
+        .and_then(|db| db.sessions(min_laps))
+        .unwrap_or_else(|e| {
+            panic!(
+                "couldn't read the laps database {}: {}",
+                laps_db.display(),
+                e
+            )
+        });
+    let mut grid = GridWidget::new(3, 10);
     grid.set_col_width(0, 200.0);
     grid.set_col_width(2, 50.0);
     grid.set(
@@ -761,6 +2318,9 @@ fn build_offline_widget() -> impl Widget<UiState> {
         "Time",
         "Fuel Tank Size",
         "Max Save",
+        "Stops",
+        "Cautions",
+        "Time Axis",
     ]
     .iter()
     .enumerate()
@@ -769,7 +2329,7 @@ fn build_offline_widget() -> impl Widget<UiState> {
             0,
             i,
             Label::new(*l)
-                .with_text_size(24.0)
+                .with_text_size(SMALL_SIZE_KEY)
                 .align_right()
                 .padding(Insets::new(0.0, 0.0, 3.0, 0.0)),
         );
@@ -803,36 +2363,67 @@ fn build_offline_widget() -> impl Widget<UiState> {
     grid.set(
         1,
         3,
-        Parse::new(TextBox::new().align_left())
+        Validate::new(Parse::new(TextBox::new()), valid_laps)
+            .align_left()
             .lens(OfflineState::laps)
             .lens(os()),
     );
     grid.set(
         1,
         4,
-        Parse::new(TextBox::new().align_left())
+        Validate::new(Parse::new(TextBox::new()), valid_time)
+            .align_left()
             .lens(OfflineState::time)
             .lens(os()),
     );
     grid.set(
         1,
         5,
-        Parse::new(TextBox::new().align_left())
+        Validate::new(Parse::new(TextBox::new()), valid_tank)
+            .align_left()
             .lens(OfflineState::fuel_tank_size)
             .lens(os()),
     );
     grid.set(
         1,
         6,
-        Parse::new(TextBox::new().align_left())
+        Validate::new(Parse::new(TextBox::new()), valid_max_save)
+            .align_left()
             .lens(OfflineState::max_fuel_save)
             .lens(os()),
     );
+    grid.set(
+        1,
+        7,
+        Validate::new(Parse::new(TextBox::new()), valid_stops)
+            .align_left()
+            .lens(OfflineState::target_stops)
+            .lens(os()),
+    );
+    grid.set(
+        1,
+        8,
+        Validate::new(Parse::new(TextBox::new()), valid_cautions)
+            .align_left()
+            .lens(OfflineState::expected_caution_laps)
+            .lens(os()),
+    );
+    grid.set(
+        1,
+        9,
+        Checkbox::new("")
+            .align_left()
+            .lens(OfflineState::time_axis)
+            .lens(os()),
+    );
     let strat = Painter::new(|ctx: &mut PaintCtx, data: &OfflineState, _env: &Env| {
         fn draw_lap_num(ctx: &mut PaintCtx, lap: i32, pos: Point) {
+            draw_label(ctx, format!("{}", lap), pos)
+        }
+        fn draw_label(ctx: &mut PaintCtx, text: String, pos: Point) {
             let t = ctx
                 .text()
-                .new_text_layout(format!("{}", lap))
+                .new_text_layout(text)
                 .text_color(Color::WHITE)
                 .build()
                 .unwrap();
@@ -849,14 +2440,22 @@ fn build_offline_widget() -> impl Widget<UiState> {
         if let Some(s) = &data.strat {
             let laps: i32 = s.stints.iter().map(|s| s.laps).sum();
             draw_lap_num(ctx, laps, Point::new(bounds.x1, bounds.y0 - 40.0));
-            let l64 = laps as f64;
-            for stop in &s.stops {
-                let b = Rect::new(
-                    bounds.width() / l64 * (stop.open as f64) + bounds.x0,
-                    bounds.y0 - 20.0,
-                    bounds.width() / l64 * (stop.close as f64) + bounds.x0,
-                    bounds.y0,
+            let x = |lap: i32| bounds.width() * plan_pos(s, lap, data.time_axis) + bounds.x0;
+            // label each stint with the fuel it needs, including any caution laps and the
+            // finish reserve
+            let mut start = 0;
+            for stint in &s.stints {
+                let mid = (x(start) + x(start + stint.laps)) / 2.0;
+                draw_label(
+                    ctx,
+                    format!("{:.1}L", stint.fuel),
+                    Point::new(mid, bounds.y0),
                 );
+                start += stint.laps;
+            }
+            // the plan starts at the green flag, so the stops are race laps
+            for stop in &s.stops {
+                let b = pit_window_rect(stop, x, bounds.y0 - 20.0, bounds.y0);
                 ctx.fill(b, &Color::rgb8(0, 64, 0));
                 ctx.stroke(bounds, &Color::grey8(220), 1.0);
                 draw_lap_num(ctx, stop.open, Point::new(b.x0, b.y0 - 20.0));
@@ -878,21 +2477,31 @@ fn build_offline_widget() -> impl Widget<UiState> {
                         if s.stops.len() == 1 { "" } else { "s" }
                     ),
                     Some(stint) => format!(
-                        "{} stop{}. Green flag stint is {} laps / {} time",
+                        "{} stop{}. Green flag stint is {} laps / {} time / {:.1}L",
                         s.stops.len(),
                         if s.stops.len() == 1 { "" } else { "s" },
                         stint.laps,
-                        stint.time
+                        stint.time,
+                        stint.fuel
                     ),
                 },
             })
-            .with_text_size(24.0)
+            .with_text_size(SMALL_SIZE_KEY)
             .lens(os()),
             1.0,
         )
         .with_flex_child(strat.lens(os()), 1.0)
         .with_flex_child(
             Label::new(|d: &OfflineState, _: &Env| {
+                if let Some(p) = d.problem() {
+                    return p.into();
+                }
+                if let Some(n) = d.target_stops {
+                    return match &d.strat {
+                        Some(s) => format!("Fuel lap target {:.2}L for {} stops", s.green.fuel, n),
+                        None => format!("{} stops isn't possible within the max save", n),
+                    };
+                }
                 if let Some(s) = &d.strat {
                     if s.fuel_to_save > 0.0 {
                         return format!(
@@ -904,7 +2513,7 @@ fn build_offline_widget() -> impl Widget<UiState> {
                 }
                 "".into()
             })
-            .with_text_size(24.0)
+            .with_text_size(SMALL_SIZE_KEY)
             .lens(os()),
             1.0,
         )
@@ -954,6 +2563,7 @@ type Options<T> = Vec<Option<T>>;
 
 struct GridWidget<T: Data> {
     cells: Options<WidgetPod<T, Box<dyn Widget<T>>>>,
+    spans: Vec<(usize, usize)>, // (cols, rows) covered by the cell
     cols: usize,
     rows: usize,
     col_widths: Vec<Option<f64>>,
@@ -965,6 +2575,7 @@ impl<T: Data> GridWidget<T> {
             cols,
             rows,
             cells: Vec::with_capacity(cols * rows),
+            spans: vec![(1, 1); cols * rows],
             col_widths: Vec::with_capacity(cols),
             row_heights: Vec::with_capacity(rows),
         };
@@ -974,8 +2585,35 @@ impl<T: Data> GridWidget<T> {
         w
     }
     fn set(&mut self, col: usize, row: usize, cell: impl Widget<T> + 'static) {
+        self.set_span(col, row, 1, 1, cell);
+    }
+    // sets a cell that covers colspan columns and rowspan rows, the cells it covers
+    // must be left empty.
+    fn set_span(
+        &mut self,
+        col: usize,
+        row: usize,
+        colspan: usize,
+        rowspan: usize,
+        cell: impl Widget<T> + 'static,
+    ) {
+        assert!(col + colspan <= self.cols && row + rowspan <= self.rows);
+        assert!(
+            !self.covered(col, row),
+            "cell {col},{row} is covered by another cell"
+        );
+        for r in row..row + rowspan {
+            for c in col..col + colspan {
+                let idx = self.cell_idx(c, r);
+                assert!(
+                    (c, r) == (col, row) || self.cells[idx].is_none(),
+                    "cell {c},{r} is covered by the cell at {col},{row}"
+                );
+            }
+        }
         let idx = self.cell_idx(col, row);
         self.cells[idx] = Some(WidgetPod::new(cell).boxed());
+        self.spans[idx] = (colspan, rowspan);
     }
     fn set_row_height(&mut self, row: usize, height: f64) {
         self.row_heights[row] = Some(height);
@@ -987,6 +2625,63 @@ impl<T: Data> GridWidget<T> {
         // across, then down
         row * self.cols + col
     }
+    // true if the cell is inside the span of a cell before it.
+    fn covered(&self, col: usize, row: usize) -> bool {
+        (0..=row).any(|r| {
+            (0..=col).any(|c| {
+                let idx = self.cell_idx(c, r);
+                let (colspan, rowspan) = self.spans[idx];
+                (c, r) != (col, row)
+                    && self.cells[idx].is_some()
+                    && col < c + colspan
+                    && row < r + rowspan
+            })
+        })
+    }
+    // the height of each row, from the laid out heights of the cells in it. Cells that
+    // span rows don't count, they're laid out afterwards to the height of their rows.
+    fn row_heights(&self, cell_heights: &[Option<f64>]) -> Vec<f64> {
+        (0..self.rows)
+            .map(|r| {
+                (0..self.cols)
+                    .map(|c| self.cell_idx(c, r))
+                    .filter(|idx| self.spans[*idx].1 == 1)
+                    .filter_map(|idx| cell_heights[idx])
+                    .fold(0.0, f64::max)
+            })
+            .collect()
+    }
+    // the total width of count columns starting at col, flex is the width of a column
+    // without a fixed width.
+    fn span_width(&self, col: usize, count: usize, flex: f64) -> f64 {
+        self.col_widths[col..col + count]
+            .iter()
+            .map(|w| w.unwrap_or(flex))
+            .sum()
+    }
+    fn span_height(&self, row: usize, count: usize, flex: f64) -> f64 {
+        self.row_heights[row..row + count]
+            .iter()
+            .map(|h| h.unwrap_or(flex))
+            .sum()
+    }
+    // the size of a cell without a fixed width/height when the grid is the given size,
+    // the fixed size rows/cols get their size first and the rest share what's left.
+    fn flex_cell_size(&self, grid: Size) -> Size {
+        fn share(total: f64, fixed: &[Option<f64>]) -> f64 {
+            let flex = fixed.iter().filter(|f| f.is_none()).count();
+            if flex == 0 {
+                // everything's fixed, there's nothing to share out
+                return 0.0;
+            }
+            let fixed: f64 = fixed.iter().flatten().sum();
+            ((total - fixed) / flex as f64).max(0.0)
+        }
+        Size::new(
+            share(grid.width, &self.col_widths),
+            share(grid.height, &self.row_heights),
+        )
+    }
 }
 
 impl<T: Data> Widget<T> for GridWidget<T> {
@@ -1021,46 +2716,57 @@ impl<T: Data> Widget<T> for GridWidget<T> {
         data: &T,
         env: &Env,
     ) -> druid::Size {
-        let fixed_w: f64 = self.col_widths.iter().flatten().sum();
-        let fixed_wc = self.col_widths.iter().flatten().count();
-        let fixed_h: f64 = self.row_heights.iter().flatten().sum();
-        let fixed_hc = self.row_heights.iter().flatten().count();
-        let cell_min = Size::new(
-            (bc.min().width - fixed_w) / (self.cols - fixed_wc) as f64,
-            (bc.min().height - fixed_h) / (self.rows - fixed_hc) as f64,
-        );
-        let cell_max = Size::new(
-            (bc.max().width - fixed_w) / (self.cols - fixed_wc) as f64,
-            (bc.max().height - fixed_h) / (self.rows - fixed_hc) as f64,
-        );
-        let mut y = 0f64;
-        for r in 0..self.rows {
-            let mut cell_bc = BoxConstraints::new(cell_min, cell_max);
-            if let Some(h) = self.row_heights[r] {
-                cell_bc =
-                    BoxConstraints::new(Size::new(cell_min.width, h), Size::new(cell_max.width, h));
+        let cell_min = self.flex_cell_size(bc.min());
+        let cell_max = self.flex_cell_size(bc.max());
+        // the rows are as tall as their cells, so the cells in a single row go first
+        let mut cell_heights = vec![None; self.cells.len()];
+        for (idx, height) in cell_heights.iter_mut().enumerate() {
+            let (c, r) = (idx % self.cols, idx / self.cols);
+            let (colspan, rowspan) = self.spans[idx];
+            if rowspan > 1 {
+                continue;
             }
-            let mut max_height = 0f64;
-            let mut x = 0f64;
-            for c in 0..self.cols {
-                let idx = self.cell_idx(c, r);
-                let this_bc = match self.col_widths[c] {
-                    None => cell_bc,
-                    Some(w) => BoxConstraints::new(
-                        Size::new(w, cell_bc.min().height),
-                        Size::new(w, cell_bc.max().height),
-                    ),
-                };
-                if let Some(w) = &mut self.cells[idx] {
-                    let cs = w.layout(ctx, &this_bc, data, env);
-                    max_height = f64::max(max_height, cs.height);
-                    w.set_origin(ctx, data, env, Point::new(x, y));
-                    x += cs.width;
+            let this_bc = BoxConstraints::new(
+                Size::new(
+                    self.span_width(c, colspan, cell_min.width),
+                    self.span_height(r, 1, cell_min.height),
+                ),
+                Size::new(
+                    self.span_width(c, colspan, cell_max.width),
+                    self.span_height(r, 1, cell_max.height),
+                ),
+            );
+            if let Some(w) = &mut self.cells[idx] {
+                *height = Some(w.layout(ctx, &this_bc, data, env).height);
+            }
+        }
+        let rows = self.row_heights(&cell_heights);
+        for idx in 0..self.cells.len() {
+            let (c, r) = (idx % self.cols, idx / self.cols);
+            let (colspan, rowspan) = self.spans[idx];
+            let x = self.span_width(0, c, cell_max.width);
+            let y = rows[..r].iter().sum();
+            let height = rows[r..r + rowspan].iter().sum();
+            let width = (
+                self.span_width(c, colspan, cell_min.width),
+                self.span_width(c, colspan, cell_max.width),
+            );
+            if let Some(w) = &mut self.cells[idx] {
+                if rowspan > 1 {
+                    let this_bc =
+                        BoxConstraints::new(Size::new(width.0, height), Size::new(width.1, height));
+                    w.layout(ctx, &this_bc, data, env);
                 }
+                w.set_origin(ctx, data, env, Point::new(x, y));
             }
-            y += max_height;
         }
-        bc.max()
+        let y = rows.iter().sum();
+        if bc.is_height_bounded() {
+            bc.max()
+        } else {
+            // e.g. in a Scroll, the rows are as tall as their content
+            Size::new(bc.max().width, y)
+        }
     }
 
     fn paint(&mut self, ctx: &mut druid::PaintCtx, data: &T, env: &Env) {
@@ -1124,6 +2830,51 @@ impl<T: Data, W: Widget<T>, F: FnMut(&mut T)> Widget<T> for TimerWidget<T, W, F>
     }
 }
 
+/// Draws a border in the bad color around the wrapped input while its value fails the check.
+struct Validate<T, W> {
+    widget: W,
+    valid: fn(&T) -> bool,
+}
+
+impl<T, W> Validate<T, W> {
+    pub fn new(widget: W, valid: fn(&T) -> bool) -> Self {
+        Self { widget, valid }
+    }
+}
+
+impl<T: Data, W: Widget<T>> Widget<T> for Validate<T, W> {
+    fn event(&mut self, ctx: &mut EventCtx, event: &Event, data: &mut T, env: &Env) {
+        self.widget.event(ctx, event, data, env)
+    }
+
+    fn lifecycle(&mut self, ctx: &mut LifeCycleCtx, event: &LifeCycle, data: &T, env: &Env) {
+        self.widget.lifecycle(ctx, event, data, env)
+    }
+
+    fn update(&mut self, ctx: &mut UpdateCtx, old_data: &T, data: &T, env: &Env) {
+        if (self.valid)(old_data) != (self.valid)(data) {
+            ctx.request_paint();
+        }
+        self.widget.update(ctx, old_data, data, env)
+    }
+
+    fn layout(&mut self, ctx: &mut LayoutCtx, bc: &BoxConstraints, data: &T, env: &Env) -> Size {
+        self.widget.layout(ctx, bc, data, env)
+    }
+
+    fn paint(&mut self, ctx: &mut PaintCtx, data: &T, env: &Env) {
+        self.widget.paint(ctx, data, env);
+        if !(self.valid)(data) {
+            let r = ctx.size().to_rect().inset(-1.0);
+            ctx.stroke(r, &env.get(BAD_COLOR_KEY), 2.0);
+        }
+    }
+
+    fn id(&self) -> Option<WidgetId> {
+        self.widget.id()
+    }
+}
+
 /// Converts a `Widget<String>` to a `Widget<Option<T>>`, mapping parse errors to None
 /// This a modified version of the druid supplied Parse widget, which has issues when
 /// the parse/to_string() can loose characters e.g. for f32 "1.0" -> "1"
@@ -1142,10 +2893,25 @@ impl<T> Parse<T> {
     }
 }
 
+/// Returns true if text is the start of a number that'll parse once more is typed, e.g. "-" or "1."
+fn is_partial_number(text: &str) -> bool {
+    matches!(text, "-" | "." | "-.") || (text.len() > 1 && text.ends_with('.'))
+}
+
+/// Parses the input text, while text is a partially typed number the last value is kept
+/// so that editing doesn't briefly flip the value to None.
+fn parse_input<T: FromStr + Clone>(text: &str, last: &Option<T>) -> Option<T> {
+    match text.parse() {
+        Ok(v) => Some(v),
+        Err(_) if is_partial_number(text) => last.clone(),
+        Err(_) => None,
+    }
+}
+
 impl<T: FromStr + Display + Data, W: Widget<String>> Widget<Option<T>> for Parse<W> {
     fn event(&mut self, ctx: &mut EventCtx, event: &Event, data: &mut Option<T>, env: &Env) {
         self.widget.event(ctx, event, &mut self.state, env);
-        *data = self.state.parse().ok();
+        *data = parse_input(&self.state, data);
     }
 
     fn lifecycle(
@@ -1223,3 +2989,485 @@ impl<T: FromStr + Display + Data, W: Widget<String>> Widget<Option<T>> for Parse
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        clear_session_tag, dash_or_planner, palette, parse_input, pit_window_rect, pit_window_text,
+        plan_pos, scaled, select_view, valid_green, valid_laps, valid_max_save, valid_tank,
+        valid_time, view_key, EditableSettings, Estimation, GridWidget, OfflineState,
+        PlannerInputs, SavedView, Theme, UiState, UiView, UserSettings,
+    };
+    use crate::history::RaceSession;
+    use crate::strat::{Pitstop, RaceStop, Rate, Stint, Strategy, TimeSpan};
+    use druid::widget::SizedBox;
+    use druid::{Color, KbKey, Modifiers, Rect, Size};
+
+    fn session(car_id: i64, track_id: i64) -> RaceSession {
+        RaceSession {
+            fuel_tank_size: 20.0,
+            max_fuel_save: 0.1,
+            min_fuel: 0.2,
+            track_id,
+            track_name: format!("track {}", track_id),
+            layout_name: String::new(),
+            car_id,
+            car: format!("car {}", car_id),
+        }
+    }
+
+    fn offline(session: RaceSession) -> OfflineState {
+        OfflineState {
+            session,
+            green: None,
+            yellow: None,
+            laps: None,
+            time: Some(TimeSpan::new(50 * 60, 0)),
+            fuel_tank_size: None,
+            max_fuel_save: None,
+            target_stops: None,
+            expected_caution_laps: None,
+            time_axis: false,
+            strat: None,
+            db_session_limit: None,
+            tag_filter: None,
+            exclude_blue_laps: false,
+            laps_db: None,
+        }
+    }
+
+    // the planner for a session at car 1 / track 10, with nothing connected.
+    fn ui_state(settings: UserSettings) -> UiState {
+        UiState {
+            offline: offline(session(1, 10)),
+            online: Estimation::default(),
+            settings_editor: EditableSettings::default(),
+            settings,
+            show_settings: false,
+            settings_error: None,
+            forced_view: None,
+            last_view: None,
+        }
+    }
+
+    #[test]
+    fn planner_inputs_round_trip() {
+        let sessions = vec![session(1, 10), session(2, 20), session(3, 30)];
+        let mut s = offline(sessions[1].clone());
+        s.laps = Some(120);
+        s.time = Some(TimeSpan::new(90 * 60, 500_000_000));
+        s.fuel_tank_size = Some(18.5);
+        s.max_fuel_save = Some(0.2);
+        s.target_stops = Some(3);
+        s.time_axis = true;
+        let json = serde_json::to_string(&s.inputs()).unwrap();
+        let p: PlannerInputs = serde_json::from_str(&json).unwrap();
+
+        let mut r = offline(sessions[0].clone());
+        r.restore(&p, &sessions);
+        assert_eq!(sessions[1], r.session);
+        assert_eq!(s.inputs(), r.inputs());
+        assert_eq!(s.time, r.time);
+
+        // a session that's no longer in the DB keeps the current one, and the
+        // session's tank size is used if there isn't one saved.
+        let p: PlannerInputs = serde_json::from_str(r#"{"car_id":4,"track_id":40}"#).unwrap();
+        let mut r = offline(sessions[2].clone());
+        r.restore(&p, &sessions);
+        assert_eq!(sessions[2], r.session);
+        assert_eq!(Some(20.0), r.fuel_tank_size);
+        assert_eq!(None, r.time);
+
+        // a time that isn't a valid duration is ignored
+        for t in [-60.0, f64::NAN, f64::INFINITY] {
+            let p = PlannerInputs {
+                time_secs: Some(t),
+                ..PlannerInputs::default()
+            };
+            r.restore(&p, &sessions);
+            assert_eq!(None, r.time);
+        }
+    }
+    #[test]
+    fn offline_input_validation() {
+        assert!(valid_laps(&None));
+        assert!(valid_laps(&Some(1)));
+        assert!(!valid_laps(&Some(0)));
+        assert!(!valid_tank(&Some(0.0)));
+        assert!(valid_tank(&Some(18.5)));
+        assert!(valid_max_save(&Some(0.0)));
+        assert!(valid_max_save(&Some(0.5)));
+        assert!(!valid_max_save(&Some(0.6)));
+        assert!(!valid_max_save(&Some(-0.1)));
+        assert!(!valid_time(&Some(TimeSpan::new(30, 0))));
+        assert!(!valid_green(&Some(Rate {
+            fuel: 0.5,
+            time: TimeSpan::ZERO
+        })));
+
+        let mut s = offline(session(1, 10));
+        s.fuel_tank_size = Some(20.0);
+        s.max_fuel_save = Some(0.1);
+        assert_eq!(
+            Some("No green flag laps in the laps DB for this car & track"),
+            s.problem()
+        );
+        s.green = Some(Rate {
+            fuel: 0.5,
+            time: TimeSpan::new(30, 0),
+        });
+        assert_eq!(None, s.problem());
+        s.recalc();
+        assert!(s.strat.is_some());
+        s.max_fuel_save = Some(5.0);
+        assert_eq!(
+            Some("Max save should be a fraction between 0 and 0.5"),
+            s.problem()
+        );
+        s.max_fuel_save = Some(0.1);
+        s.fuel_tank_size = Some(0.0);
+        assert_eq!(
+            Some("Fuel tank size should be between 0.1L and 1000L"),
+            s.problem()
+        );
+        s.recalc();
+        assert!(s.strat.is_none());
+    }
+    #[test]
+    fn offline_cautions_cost_laps() {
+        let mut s = offline(session(1, 10));
+        s.fuel_tank_size = Some(20.0);
+        s.max_fuel_save = Some(0.0);
+        s.green = Some(Rate {
+            fuel: 1.0,
+            time: TimeSpan::new(60, 0),
+        });
+        s.yellow = Some(Rate {
+            fuel: 0.3,
+            time: TimeSpan::new(120, 0),
+        });
+        s.recalc();
+        let green_only = s.strat.as_ref().unwrap().total_laps();
+        assert_eq!(51, green_only);
+        // 10 slow caution laps in the 50 minutes leaves time for fewer laps
+        s.expected_caution_laps = Some(10);
+        s.recalc();
+        assert_eq!(41, s.strat.as_ref().unwrap().total_laps());
+        // cautions need a yellow rate to plan with
+        s.yellow = None;
+        assert_eq!(
+            Some("No yellow flag laps in the laps DB to plan the cautions with"),
+            s.problem()
+        );
+    }
+    #[test]
+    fn saved_view_round_trip() {
+        let f = std::env::temp_dir().join(format!("naf_calc_view_{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&f);
+        // nothing saved yet is the default view
+        assert_eq!(
+            SavedView::default(),
+            SavedView::load(Some(f.clone())).unwrap()
+        );
+        let v = SavedView {
+            forced_view: Some(UiView::Online),
+            show_settings: true,
+            last_view: Some(UiView::Offline),
+        };
+        v.save(Some(f.clone())).unwrap();
+        assert_eq!(v, SavedView::load(Some(f.clone())).unwrap());
+        let _ = std::fs::remove_file(&f);
+    }
+    #[test]
+    fn settings_editor_clamps_pit_loss() {
+        let mut s = UserSettings::default();
+        let e = EditableSettings {
+            pit_stop_loss: Some(-20.0),
+            ..EditableSettings::default()
+        };
+        e.update(&mut s);
+        assert_eq!(0.0, s.pit_stop_loss);
+    }
+    #[test]
+    fn parse_input_partial_numbers() {
+        let mut v: Option<f32> = Some(4.0);
+        // select all and type -1.5 over the old value
+        for (text, exp) in [
+            ("-", Some(4.0)),
+            ("-1", Some(-1.0)),
+            ("-1.", Some(-1.0)),
+            ("-1.5", Some(-1.5)),
+        ] {
+            v = parse_input(text, &v);
+            assert_eq!(exp, v, "after typing {}", text);
+        }
+        assert_eq!(Some(-1.5), parse_input(".", &v));
+        assert_eq!(Some(0.5), parse_input(".5", &v));
+        assert_eq!(None, parse_input("", &v));
+        assert_eq!(None, parse_input("bob", &v));
+        assert_eq!(None::<i32>, parse_input("-", &None));
+    }
+    #[test]
+    fn grid_all_fixed() {
+        let mut g = GridWidget::<()>::new(2, 2);
+        g.set_col_width(0, 100.0);
+        let s = g.flex_cell_size(Size::new(300.0, 200.0));
+        assert_eq!(Size::new(200.0, 100.0), s);
+        g.set_col_width(1, 50.0);
+        g.set_row_height(0, 20.0);
+        g.set_row_height(1, 20.0);
+        for grid in [Size::new(300.0, 200.0), Size::ZERO] {
+            let s = g.flex_cell_size(grid);
+            assert!(s.width.is_finite() && s.height.is_finite());
+            assert_eq!(Size::ZERO, s);
+        }
+    }
+    #[test]
+    fn grid_span() {
+        let mut g = GridWidget::<()>::new(3, 2);
+        g.set_col_width(0, 60.0);
+        g.set_row_height(1, 20.0);
+        let flex = g.flex_cell_size(Size::new(300.0, 100.0));
+        assert_eq!(Size::new(120.0, 80.0), flex);
+        // a 2 wide span is the width of both its columns
+        assert_eq!(180.0, g.span_width(0, 2, flex.width));
+        assert_eq!(240.0, g.span_width(1, 2, flex.width));
+        assert_eq!(300.0, g.span_width(0, 3, flex.width));
+        assert_eq!(100.0, g.span_height(0, 2, flex.height));
+        // cells start after the columns before them
+        assert_eq!(0.0, g.span_width(0, 0, flex.width));
+    }
+    #[test]
+    fn grid_row_span() {
+        let mut g = GridWidget::<()>::new(3, 3);
+        g.set_span(0, 0, 2, 2, SizedBox::empty());
+        g.set(2, 0, SizedBox::empty());
+        g.set(2, 1, SizedBox::empty());
+        g.set_span(0, 2, 3, 1, SizedBox::empty());
+        // the cells under a span are left empty
+        for (c, r) in [(1, 0), (0, 1), (1, 1), (1, 2), (2, 2)] {
+            assert!(g.covered(c, r));
+            assert!(g.cells[g.cell_idx(c, r)].is_none());
+        }
+        assert!(!g.covered(2, 0) && !g.covered(2, 1));
+        // the tall cell doesn't decide the height of the rows it spans
+        let mut heights = vec![None; 9];
+        heights[g.cell_idx(0, 0)] = Some(100.0);
+        heights[g.cell_idx(2, 0)] = Some(20.0);
+        heights[g.cell_idx(2, 1)] = Some(30.0);
+        heights[g.cell_idx(0, 2)] = Some(10.0);
+        assert_eq!(vec![20.0, 30.0, 10.0], g.row_heights(&heights));
+    }
+    #[test]
+    #[should_panic]
+    fn grid_set_covered_cell() {
+        let mut g = GridWidget::<()>::new(3, 2);
+        g.set_span(0, 0, 2, 1, SizedBox::empty());
+        g.set(1, 0, SizedBox::empty());
+    }
+    #[test]
+    fn session_tag_cleared_once_saved() {
+        let mut s = ui_state(UserSettings {
+            session_tag: "league".to_string(),
+            ..UserSettings::default()
+        });
+        s.settings_editor.load(&s.settings);
+        assert!(!clear_session_tag(&mut s));
+        assert_eq!("league", s.settings.session_tag);
+        s.online.session_tagged = true;
+        assert!(clear_session_tag(&mut s));
+        assert!(!s.online.session_tagged);
+        assert_eq!("", s.settings.session_tag);
+        assert_eq!("", s.settings_editor.session_tag);
+    }
+    #[test]
+    fn select_forced_view() {
+        let mut s = ui_state(UserSettings::default());
+        assert_eq!(UiView::Offline, select_view(&s));
+        s.online.connected = true;
+        assert_eq!(UiView::Online, select_view(&s));
+        // a forced view wins over the connection state
+        s.forced_view = Some(UiView::Offline);
+        assert_eq!(UiView::Offline, select_view(&s));
+        s.online.connected = false;
+        s.forced_view = Some(UiView::Online);
+        assert_eq!(UiView::Online, select_view(&s));
+        // but not over the settings
+        s.show_settings = true;
+        assert_eq!(UiView::Settings, select_view(&s));
+        s.show_settings = false;
+        s.forced_view = None;
+        s.online.reconnecting = true;
+        assert_eq!(UiView::Reconnecting, select_view(&s));
+    }
+    #[test]
+    fn view_keys() {
+        let mut s = ui_state(UserSettings::default());
+        let key = |c: &str| KbKey::Character(c.to_string());
+        let none = Modifiers::empty();
+        s.online.connected = true;
+        assert!(view_key(&mut s, &key("F"), none));
+        assert_eq!(UiView::Offline, select_view(&s));
+        // the planner has text boxes, so the keys are left for them without Alt
+        assert!(!view_key(&mut s, &key("o"), none));
+        assert_eq!(UiView::Offline, select_view(&s));
+        // select all and copy are the text box's
+        assert!(!view_key(&mut s, &key("a"), Modifiers::CONTROL));
+        assert!(!view_key(&mut s, &key("c"), Modifiers::CONTROL));
+        assert_eq!(Some(UiView::Offline), s.forced_view);
+        assert!(!s.settings.compact_dash);
+        assert!(view_key(&mut s, &key("o"), Modifiers::ALT));
+        assert_eq!(UiView::Online, select_view(&s));
+        assert!(view_key(&mut s, &key("c"), none));
+        assert_eq!(UiView::Compact, select_view(&s));
+        assert!(!view_key(&mut s, &KbKey::Enter, none));
+        assert!(!view_key(&mut s, &key("x"), none));
+        assert!(view_key(&mut s, &key("a"), none));
+        assert_eq!(None, s.forced_view);
+        assert!(view_key(&mut s, &key("s"), none));
+        assert_eq!(UiView::Settings, select_view(&s));
+        assert!(!view_key(&mut s, &key("s"), Modifiers::ALT));
+        assert!(!view_key(&mut s, &key("a"), Modifiers::CONTROL));
+        assert!(view_key(&mut s, &key("o"), Modifiers::ALT));
+    }
+    #[test]
+    fn select_initial_view() {
+        let state = || ui_state(UserSettings::default());
+        let restored = |last_view| {
+            let mut s = state();
+            SavedView {
+                last_view,
+                ..SavedView::default()
+            }
+            .restore(&mut s);
+            s
+        };
+        assert_eq!(UiView::Offline, select_view(&restored(None)));
+        assert_eq!(
+            UiView::Offline,
+            select_view(&restored(Some(UiView::Offline)))
+        );
+        // the dash was in use, so wait for iRacing
+        let mut s = restored(Some(UiView::Online));
+        assert_eq!(UiView::Reconnecting, select_view(&s));
+        // and still switch to the dash when a session appears
+        s.online.connected = true;
+        assert_eq!(UiView::Online, select_view(&s));
+        let mut s = restored(Some(UiView::Offline));
+        s.online.connected = true;
+        assert_eq!(UiView::Online, select_view(&s));
+
+        assert_eq!(Some(UiView::Online), dash_or_planner(UiView::Compact));
+        assert_eq!(Some(UiView::Offline), dash_or_planner(UiView::Offline));
+        assert_eq!(None, dash_or_planner(UiView::Settings));
+    }
+    #[test]
+    fn select_compact_view() {
+        let mut s = ui_state(UserSettings {
+            compact_dash: true,
+            ..UserSettings::default()
+        });
+        // only replaces the full dash
+        assert_eq!(UiView::Offline, select_view(&s));
+        s.online.connected = true;
+        assert_eq!(UiView::Compact, select_view(&s));
+        s.settings.compact_dash = false;
+        assert_eq!(UiView::Online, select_view(&s));
+        s.settings.compact_dash = true;
+        s.online.connected = false;
+        s.forced_view = Some(UiView::Online);
+        assert_eq!(UiView::Compact, select_view(&s));
+    }
+    #[test]
+    fn scaled_text_size() {
+        assert_eq!(32.0, scaled(32.0, 1.0));
+        assert_eq!(48.0, scaled(32.0, 1.5));
+        assert_eq!(96.0, scaled(48.0, 2.0));
+        // out of range scales are clamped
+        assert_eq!(16.0, scaled(32.0, 0.0));
+        assert_eq!(128.0, scaled(32.0, 10.0));
+    }
+    #[test]
+    fn theme_palette() {
+        let standard = palette(Theme::Standard);
+        assert_eq!(Color::GREEN, standard.good);
+        assert_eq!(Color::RED, standard.bad);
+        let cb = palette(Theme::ColorBlind);
+        assert_ne!(standard, cb);
+        assert_ne!(standard.good, cb.good);
+        assert_ne!(standard.bad, cb.bad);
+        // every color in the palette is distinct
+        let colors = [cb.good, cb.marginal, cb.bad, cb.warn, cb.info];
+        for (i, c) in colors.iter().enumerate() {
+            assert!(!colors[i + 1..].contains(c));
+        }
+    }
+    #[test]
+    fn pit_window_status() {
+        // the window is open from the end of lap 13 to the end of lap 22
+        let stop = RaceStop::new(13, 22);
+        assert_eq!("3-12", pit_window_text(&stop, 10));
+        assert_eq!("9", pit_window_text(&stop, 13));
+        assert_eq!("4", pit_window_text(&stop, 18));
+        assert_eq!("0", pit_window_text(&stop, 22));
+        assert_eq!("Missed", pit_window_text(&stop, 23));
+    }
+    #[test]
+    fn pit_window_position() {
+        // the dash timeline, 20 laps to go over 200 pixels starting at 10
+        let x = |lap: i32| 200.0 * (lap as f64 / 20.0).clamp(0.0, 1.0) + 10.0;
+        // on lap 10 of the race
+        let live = RaceStop::new(15, 20).relative_to(10);
+        assert_eq!(
+            Rect::new(60.0, 0.0, 110.0, 30.0),
+            pit_window_rect(&live, x, 0.0, 30.0)
+        );
+        // an open window starts at the current lap
+        let open = RaceStop::new(8, 14).relative_to(10);
+        assert_eq!(
+            Rect::new(10.0, 0.0, 50.0, 30.0),
+            pit_window_rect(&open, x, 0.0, 30.0)
+        );
+        // the planner, a 40 lap race over 400 pixels
+        let x = |lap: i32| 400.0 * lap as f64 / 40.0;
+        let planned = Pitstop::new(12, 20);
+        assert_eq!(
+            Rect::new(120.0, 5.0, 200.0, 10.0),
+            pit_window_rect(&planned, x, 5.0, 10.0)
+        );
+    }
+    #[test]
+    fn plan_position() {
+        let stint = |laps, secs| Stint {
+            laps,
+            fuel: laps as f32,
+            time: TimeSpan::new(secs, 0),
+        };
+        // the 2nd stint's laps take twice as long
+        let s = Strategy {
+            stints: vec![stint(10, 300), stint(10, 600)],
+            ..Strategy::default()
+        };
+        assert_eq!(0.0, plan_pos(&s, 0, false));
+        assert_eq!(0.5, plan_pos(&s, 10, false));
+        assert_eq!(1.0, plan_pos(&s, 20, false));
+        assert_eq!(1.0, plan_pos(&s, 25, false));
+        assert_eq!(0.0, plan_pos(&s, 0, true));
+        assert!((plan_pos(&s, 10, true) - 1.0 / 3.0).abs() < 1e-9);
+        assert!((plan_pos(&s, 15, true) - 2.0 / 3.0).abs() < 1e-9);
+        assert_eq!(1.0, plan_pos(&s, 20, true));
+        // less than a lap of fuel left, so the plan starts with an empty stint
+        let s = Strategy {
+            stints: vec![stint(0, 0), stint(10, 300)],
+            ..Strategy::default()
+        };
+        assert_eq!(0.5, plan_pos(&s, 5, true));
+        // nothing to plan
+        let s = Strategy {
+            stints: vec![stint(0, 0)],
+            ..Strategy::default()
+        };
+        assert_eq!(0.0, plan_pos(&s, 0, true));
+        assert_eq!(0.0, plan_pos(&s, 0, false));
+    }
+}
@@ -19,6 +19,18 @@ bitflags! {
         const PACE_LAP =    0x04;
         const ONE_TO_GREEN = 0x08;
         const TWO_TO_GREEN = 0x10;
+        const LOCAL_YELLOW = 0x20;
+        // shown the blue flag at some point during the lap, i.e. moving over for faster cars.
+        const BLUE =        0x40;
+        // any of these mean the lap isn't representative of green flag running.
+        const NOT_GREEN = Self::YELLOW.bits | Self::PITTED.bits | Self::PACE_LAP.bits;
+    }
+}
+impl LapState {
+    // A lap is green if it wasn't under caution, a pace lap or an in/out lap. Other
+    // conditions such as a local yellow don't make enough difference to exclude the lap.
+    pub fn is_green(&self) -> bool {
+        !self.intersects(LapState::NOT_GREEN)
     }
 }
 
@@ -112,6 +124,12 @@ impl Mul<u32> for TimeSpan {
         TimeSpan { d: self.d * rhs }
     }
 }
+impl Mul<f32> for TimeSpan {
+    type Output = Self;
+    fn mul(self, rhs: f32) -> TimeSpan {
+        TimeSpan::from_secs_f64(self.as_secs_f64() * rhs as f64)
+    }
+}
 impl From<TimeSpan> for Duration {
     fn from(a: TimeSpan) -> Self {
         a.d
@@ -136,7 +154,16 @@ impl FromStr for TimeSpan {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         lazy_static! {
             static ref DURATION_REGEX: Regex =
-                Regex::new(r"^\s*(?:(\d{1,2}):)??(\d{2}):(\d{2})\s*$").unwrap();
+                Regex::new(r"^\s*(?:(\d{1,2}):)??(\d{1,2}):(\d{2})\s*$").unwrap();
+            static ref SECONDS_REGEX: Regex = Regex::new(r"^\s*(\d+(?:\.\d+)?)\s*$").unwrap();
+        }
+        // without a colon it's just seconds, e.g. "90" or "90.5"
+        if let Some(cap) = SECONDS_REGEX.captures(s) {
+            // too many digits won't fit in a Duration
+            let secs = f64::from_str(cap.get(1).unwrap().as_str()).unwrap();
+            return Duration::try_from_secs_f64(secs)
+                .map(TimeSpan::of)
+                .map_err(|_| ParseError::Bogus);
         }
         match DURATION_REGEX.captures(s) {
             None => Err(ParseError::Empty),
@@ -197,6 +224,16 @@ impl Default for Rate {
         }
     }
 }
+impl Rate {
+    // can a driver realistically hit the target fuel per lap, i.e. does it need
+    // saving no more than max_save (a fraction) of the usual fuel per lap.
+    pub fn can_save_to(&self, target: f32, max_save: f32) -> bool {
+        if target <= 0.0 || self.fuel <= 0.0 {
+            return true;
+        }
+        1.0 - target / self.fuel <= max_save + 0.0001
+    }
+}
 impl Add<&Lap> for Rate {
     type Output = Self;
     fn add(self, rhs: &Lap) -> Self {
@@ -213,6 +250,23 @@ impl AddAssign<&Lap> for Rate {
     }
 }
 
+// the number of laps the fuel will last, running yellow_togo laps at the yellow rate
+// before switching to the green rate.
+pub fn laps_to_empty(fuel: f32, yellow_togo: i32, yellow: Rate, green: Rate) -> f32 {
+    let yellow_fuel = yellow.fuel * yellow_togo as f32;
+    if fuel <= 0.0 {
+        0.0
+    } else if fuel <= yellow_fuel {
+        fuel / yellow.fuel
+    } else if green.fuel > 0.0 {
+        yellow_togo as f32 + (fuel - yellow_fuel) / green.fuel
+    } else {
+        0.0
+    }
+}
+
+// open & close are laps counted from the start of the strategy the stop is part of. A live
+// strategy starts at the current lap, the offline planner's starts at the green flag.
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Data)]
 pub struct Pitstop {
     pub open: i32,
@@ -222,10 +276,50 @@ impl Pitstop {
     pub fn new(open: i32, close: i32) -> Pitstop {
         Pitstop { open, close }
     }
-    pub fn is_open(&self) -> bool {
-        self.open <= 0
+    // the stop in race lap numbers, for a strategy that started at the end of race lap start.
+    pub fn to_race_laps(self, start: i32) -> RaceStop {
+        RaceStop {
+            open: self.open + start,
+            close: self.close + start,
+        }
+    }
+}
+// a pit window whose open & close are race lap numbers, i.e. laps completed. These don't
+// change as the race goes on, the laps to go are worked out from the current lap.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Data)]
+pub struct RaceStop {
+    pub open: i32,
+    pub close: i32,
+}
+impl RaceStop {
+    pub fn new(open: i32, close: i32) -> RaceStop {
+        RaceStop { open, close }
+    }
+    pub fn is_open(&self, lap: i32) -> bool {
+        lap >= self.open
+    }
+    // the stop as part of a strategy that starts at the end of race lap lap, i.e. its
+    // open/close are the laps to go.
+    pub fn relative_to(&self, lap: i32) -> Pitstop {
+        Pitstop::new(self.open - lap, self.close - lap)
+    }
+    // where the window is at lap.
+    pub fn status(&self, lap: i32) -> PitWindow {
+        if lap < self.open {
+            PitWindow::OpensIn(self.open - lap)
+        } else if lap <= self.close {
+            PitWindow::ClosesIn(self.close - lap)
+        } else {
+            PitWindow::Missed
+        }
     }
 }
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PitWindow {
+    OpensIn(i32),  // laps until the window opens
+    ClosesIn(i32), // the window is open, laps until it closes
+    Missed,        // the window closed without stopping
+}
 impl fmt::Display for Pitstop {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
@@ -298,16 +392,109 @@ impl Strategy {
     pub fn total_time(&self) -> TimeSpan {
         self.stints.iter().map(|s| s.time).sum()
     }
+    // the race time at the end of this many laps, laps within a stint are assumed to take the
+    // same time as each other.
+    pub fn time_at_lap(&self, lap: i32) -> TimeSpan {
+        let mut lap = lap.max(0);
+        let mut tm = TimeSpan::ZERO;
+        // e.g. a leading stint with no laps when there's less than a lap of fuel
+        for s in self.stints.iter().filter(|s| s.laps > 0) {
+            if lap <= s.laps {
+                let part = s.time.as_secs_f64() * lap as f64 / s.laps as f64;
+                return tm + TimeSpan::from_secs_f64(part);
+            }
+            tm += s.time;
+            lap -= s.laps;
+        }
+        tm
+    }
     pub fn fuel_target(&self) -> f32 {
-        if self.fuel_to_save > 0.0 {
+        self.fuel_target_at(0.0, 0)
+    }
+    // the strategy laps laps after it started, assuming they were run to plan. The laps come off
+    // the first stint, and the pit windows are that many laps closer.
+    pub fn after_laps(&self, laps: i32) -> Strategy {
+        let mut s = self.clone();
+        if let Some(first) = s.stints.first_mut() {
+            let done = laps.clamp(0, first.laps);
+            if done > 0 {
+                let left = (first.laps - done) as f64 / first.laps as f64;
+                first.laps -= done;
+                first.fuel *= left as f32;
+                first.time = TimeSpan::from_secs_f64(first.time.as_secs_f64() * left);
+            }
+        }
+        for stop in &mut s.stops {
+            *stop = Pitstop::new(stop.open - laps, stop.close - laps);
+        }
+        s
+    }
+    // the stops still to make once the fuel_to_save is saved, saving it skips the last stop.
+    pub fn planned_stops(&self) -> &[Pitstop] {
+        if self.fuel_to_save > 0.0 && !self.stops.is_empty() {
+            &self.stops[..self.stops.len() - 1]
+        } else {
+            &self.stops
+        }
+    }
+    // the fuel_target part way through the strategy, laps_done laps after it started and having
+    // used fuel_used since. Whatever's left of the fuel that can be used to still skip the last
+    // stop is spread over the laps still to go until then, so it tightens if we use too much.
+    pub fn fuel_target_at(&self, fuel_used: f32, laps_done: i32) -> f32 {
+        if self.fuel_to_save > 0.0 && self.stints.len() > 1 {
             let laps_til_last_stop: i32 = self.stints.iter().rev().skip(1).map(|s| s.laps).sum();
-            if laps_til_last_stop > 0 {
+            let laps_left = laps_til_last_stop - laps_done;
+            if laps_left > 0 {
                 let fuel_to_last_stop: f32 = self.stints.iter().rev().skip(1).map(|s| s.fuel).sum();
-                return (fuel_to_last_stop - self.fuel_to_save) / (laps_til_last_stop as f32);
+                return (fuel_to_last_stop - self.fuel_to_save - fuel_used) / (laps_left as f32);
             }
         }
         0.0
     }
+    // the fuel per lap to average from now, having run laps_completed laps since the strategy
+    // started, for fuel_now to last until the stop after next, skipping the next stop. None if
+    // there's no stop to skip.
+    pub fn break_even_target(&self, fuel_now: f32, laps_completed: i32) -> Option<f32> {
+        if self.stops.is_empty() || fuel_now <= 0.0 {
+            return None;
+        }
+        let laps_left = self.stints.iter().take(2).map(|s| s.laps).sum::<i32>() - laps_completed;
+        if laps_left > 0 {
+            Some(fuel_now / (laps_left as f32))
+        } else {
+            None
+        }
+    }
+    // the per lap fuel target for each stint to save total_save over the whole race. The
+    // saving is spread over every lap evenly, so each stint saves its share by laps.
+    pub fn spread_save(&self, total_save: f32) -> Vec<f32> {
+        let laps = self.total_laps();
+        self.stints
+            .iter()
+            .map(|s| {
+                if s.laps > 0 {
+                    let share = total_save * (s.laps as f32) / (laps as f32);
+                    (s.fuel - share) / (s.laps as f32)
+                } else {
+                    0.0
+                }
+            })
+            .collect()
+    }
+    // the fuel to use on a single lift and coast lap, with every other lap run normally, to
+    // skip the last stop. None if there's nothing to save, or it's more than one lap can save.
+    pub fn single_save_lap_target(&self) -> Option<f32> {
+        let laps_til_last_stop: i32 = self.stints.iter().rev().skip(1).map(|s| s.laps).sum();
+        if self.fuel_to_save <= 0.0 || laps_til_last_stop == 0 {
+            return None;
+        }
+        let target = self.green.fuel - self.fuel_to_save;
+        if target > 0.0 {
+            Some(target)
+        } else {
+            None
+        }
+    }
 }
 
 #[derive(Clone, Copy, Debug, PartialEq)]
@@ -316,6 +503,23 @@ pub enum EndsWith {
     Time(TimeSpan),            // race ends after this much more time
     LapsOrTime(i32, TimeSpan), // first of the above 2 to happen
 }
+impl EndsWith {
+    // reduces the time left in the race by t, a laps race is unchanged.
+    pub fn less_time(&self, t: TimeSpan) -> EndsWith {
+        match *self {
+            EndsWith::Laps(l) => EndsWith::Laps(l),
+            EndsWith::Time(d) => EndsWith::Time(d - d.min(t)),
+            EndsWith::LapsOrTime(l, d) => EndsWith::LapsOrTime(l, d - d.min(t)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StratError {
+    MinFuelExceedsTank, // the min fuel setting leaves no room in the tank for any laps
+    RaceOver,           // there are no laps left to go
+    LapExceedsTank,     // a single lap uses more fuel than the tank holds above min fuel
+}
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct StratRequest {
@@ -324,22 +528,99 @@ pub struct StratRequest {
     pub max_fuel_save: f32,
     pub min_fuel: f32,
     pub yellow_togo: i32,
+    pub pre_green_laps: i32, // formation/parade laps to run before the race laps in ends start
+    pub pre_green_rate: Rate, // fuel/time per lap for the pre_green_laps
+    pub cooldown_laps: i32,  // laps to run under power after the race ends, at the green rate
+    pub finish_reserve: f32, // fuel on top of min_fuel to still have in the tank at the finish
     pub ends: EndsWith, // for a laps race, EndsWith laps is total laps to go, regardless of yellow/green.
     pub green: Rate,
     pub yellow: Rate,
 }
 
+// Builds a StratRequest, anything not set defaults to starting with a full tank, no min fuel,
+// fuel save, caution, pace or cooldown laps, and no reserve at the finish.
+pub struct StratRequestBuilder {
+    r: StratRequest,
+}
+impl StratRequestBuilder {
+    pub fn new(tank_size: f32, ends: EndsWith, green: Rate) -> StratRequestBuilder {
+        StratRequestBuilder {
+            r: StratRequest {
+                fuel_left: tank_size,
+                tank_size,
+                max_fuel_save: 0.0,
+                min_fuel: 0.0,
+                yellow_togo: 0,
+                pre_green_laps: 0,
+                pre_green_rate: Rate::default(),
+                cooldown_laps: 0,
+                finish_reserve: 0.0,
+                ends,
+                green,
+                yellow: Rate::default(),
+            },
+        }
+    }
+    pub fn fuel_left(mut self, fuel_left: f32) -> Self {
+        self.r.fuel_left = fuel_left;
+        self
+    }
+    pub fn max_fuel_save(mut self, max_fuel_save: f32) -> Self {
+        self.r.max_fuel_save = max_fuel_save;
+        self
+    }
+    pub fn min_fuel(mut self, min_fuel: f32) -> Self {
+        self.r.min_fuel = min_fuel;
+        self
+    }
+    // yellow_togo caution laps are still to run at the yellow rate.
+    pub fn yellow(mut self, yellow: Rate, yellow_togo: i32) -> Self {
+        self.r.yellow = yellow;
+        self.r.yellow_togo = yellow_togo;
+        self
+    }
+    pub fn pre_green(mut self, laps: i32, rate: Rate) -> Self {
+        self.r.pre_green_laps = laps;
+        self.r.pre_green_rate = rate;
+        self
+    }
+    pub fn cooldown_laps(mut self, laps: i32) -> Self {
+        self.r.cooldown_laps = laps;
+        self
+    }
+    pub fn finish_reserve(mut self, reserve: f32) -> Self {
+        self.r.finish_reserve = reserve;
+        self
+    }
+    pub fn build(self) -> StratRequest {
+        self.r
+    }
+}
+
 impl StratRequest {
     // Compute fuel strategy. The base strategy repeatedly runs the tank dry until the end of the race.
     // Pit stop windows are extended based on the size of the last stint. If the last stint isn't a full
     // tank then you can stop earlier and still complete the last stint. This cascades back into all
     // the pit windows.
-    pub fn compute(&self) -> Option<Strategy> {
+    pub fn compute(&self) -> Result<Strategy, StratError> {
+        if self.min_fuel >= self.tank_size {
+            // stints() would never be able to complete a lap
+            return Err(StratError::MinFuelExceedsTank);
+        }
+        let yellow_fuel = if self.yellow_togo > 0 {
+            self.yellow.fuel
+        } else {
+            0.0
+        };
+        if self.tank_size < self.green.fuel.max(yellow_fuel) + self.min_fuel {
+            // stints() would pit every lap, and still not make it round
+            return Err(StratError::LapExceedsTank);
+        }
         let stints = self.stints();
         if stints.is_empty() {
-            None
+            Err(StratError::RaceOver)
         } else {
-            Some(Strategy {
+            Ok(Strategy {
                 fuel_to_save: self.fuel_save(&stints),
                 stops: self.stops(&stints),
                 stints,
@@ -349,6 +630,85 @@ impl StratRequest {
         }
     }
 
+    // The total fuel needed to run the rest of the race, regardless of the fuel currently
+    // in the car. This includes any pace, caution and cooldown laps, and the finish reserve.
+    pub fn race_fuel_requirement(&self) -> f32 {
+        let pre_green = self.pre_green_rate.fuel * self.pre_green_laps.max(0) as f32;
+        self.stints()
+            .iter()
+            .fold(pre_green, |total, s| total + s.fuel)
+    }
+
+    // Estimates the laps gained (positive) or lost (negative) by pitting for a full tank now under
+    // the caution rather than staying out. pit_loss is the time lost to a pitstop under green, under
+    // caution the field is slower so the stop costs less. None if either strategy can't be computed.
+    pub fn yellow_pit_gain(&self, pit_loss: TimeSpan) -> Option<f32> {
+        if self.green.time == TimeSpan::ZERO {
+            return None;
+        }
+        let stay_out = self.compute().ok()?;
+        let pit_now = StratRequest {
+            fuel_left: self.tank_size,
+            ..self.clone()
+        }
+        .compute()
+        .ok()?;
+        let green_loss = pit_loss.as_secs_f32();
+        let yellow_loss = (green_loss
+            - (self.yellow.time.as_secs_f32() - self.green.time.as_secs_f32()))
+        .max(0.0);
+        let stay_out_loss = stay_out.stops.len() as f32 * green_loss;
+        let pit_now_loss = yellow_loss + pit_now.stops.len() as f32 * green_loss;
+        Some((stay_out_loss - pit_now_loss) / self.green.time.as_secs_f32())
+    }
+
+    // Compute a strategy that makes exactly n stops by saving fuel, the green rate of the returned
+    // strategy is the per lap fuel target needed. Returns None if n stops can't be done within
+    // max_fuel_save, or if n is more stops than are needed without saving.
+    pub fn compute_for_stops(&self, n: i32) -> Option<Strategy> {
+        let stops = |fuel: f32| {
+            let r = StratRequest {
+                green: Rate {
+                    fuel,
+                    time: self.green.time,
+                },
+                ..self.clone()
+            };
+            r.compute().ok().map(|s| (s.stops.len() as i32, s))
+        };
+        let (base, s) = stops(self.green.fuel)?;
+        if base == n {
+            return Some(s);
+        }
+        let mut lo = self.green.fuel * (1.0 - self.max_fuel_save);
+        let mut hi = self.green.fuel;
+        if base < n || stops(lo)?.0 > n {
+            return None;
+        }
+        // lo always makes n or fewer stops, hi always makes more than n.
+        for _ in 0..32 {
+            let mid = (lo + hi) / 2.0;
+            if stops(mid)?.0 <= n {
+                lo = mid;
+            } else {
+                hi = mid;
+            }
+        }
+        let (count, mut s) = stops(lo)?;
+        if count != n {
+            return None;
+        }
+        // this is already the saving needed, there's no more to save.
+        s.fuel_to_save = 0.0;
+        Some(s)
+    }
+
+    // the per lap green fuel use needed to make exactly stops stops, None if that's not possible
+    // even at the max fuel save.
+    pub fn save_target_for_stops(&self, stops: i32) -> Option<f32> {
+        self.compute_for_stops(stops).map(|s| s.green.fuel)
+    }
+
     fn stints(&self) -> Vec<Stint> {
         let yellow = iter::repeat(self.yellow).take(self.yellow_togo as usize);
         let mut tm = TimeSpan::ZERO;
@@ -365,13 +725,29 @@ impl StratRequest {
             laps += 1;
             continu
         });
+        // some tracks need cooldown laps under power after the flag, so don't plan to run dry at the line.
+        let mut laps = laps.peekable();
+        let cooldown = match laps.peek() {
+            Some(_) => self.cooldown_laps.max(0) as usize,
+            None => 0,
+        };
+        let laps = laps.chain(iter::repeat_n(self.green, cooldown));
         // the laps iterator will return the sequence of predicted laps until the conclusion of the race
+        // followed by any cooldown laps.
 
         let mut stints = Vec::with_capacity(4);
-        let mut f = self.fuel_left;
+        // fuel burnt before the green flag isn't available for the first stint
+        let mut f =
+            (self.fuel_left - self.pre_green_rate.fuel * self.pre_green_laps as f32).max(0.0);
         let mut stint = Stint::new();
-        for lap in laps {
-            if f < lap.fuel + self.min_fuel {
+        let mut laps = laps.peekable();
+        while let Some(lap) = laps.next() {
+            // the final lap needs to leave the finish reserve in the tank as well
+            let reserve = match laps.peek() {
+                Some(_) => 0.0,
+                None => self.finish_reserve,
+            };
+            if f < lap.fuel + self.min_fuel + reserve {
                 stints.push(stint);
                 stint = Stint::new();
                 f = self.tank_size;
@@ -380,6 +756,8 @@ impl StratRequest {
             f -= lap.fuel;
         }
         if stint.laps > 0 {
+            // plan to have the reserve left at the finish
+            stint.fuel += self.finish_reserve;
             stints.push(stint);
         }
         stints
@@ -387,7 +765,10 @@ impl StratRequest {
 
     fn stops(&self, stints: &[Stint]) -> Vec<Pitstop> {
         let mut stops = Vec::with_capacity(stints.len());
-        let full_stint_len = round::floor((self.tank_size / self.green.fuel) as f64, 0) as i32;
+        let full_stint_len = round::floor(
+            ((self.tank_size - self.finish_reserve) / self.green.fuel) as f64,
+            0,
+        ) as i32;
         let mut lap_open = 0;
         let mut lap_close = 0;
         let mut ext = full_stint_len - stints.last().unwrap().laps;
@@ -403,6 +784,10 @@ impl StratRequest {
     }
 
     fn fuel_save(&self, stints: &[Stint]) -> f32 {
+        if stints.len() < 2 {
+            // no stops, so there's no stop to save
+            return 0.0;
+        }
         let total: f32 = stints.iter().map(|s| s.fuel).sum();
         let max_save = total * self.max_fuel_save;
         let last_stint_fuel = stints.last().unwrap().fuel;
@@ -439,6 +824,17 @@ mod tests {
         );
     }
 
+    #[test]
+    fn lap_state_green() {
+        assert!(LapState::empty().is_green());
+        assert!(LapState::LOCAL_YELLOW.is_green());
+        assert!(!LapState::YELLOW.is_green());
+        assert!(!LapState::PITTED.is_green());
+        assert!(!LapState::PACE_LAP.is_green());
+        assert!(!(LapState::YELLOW | LapState::ONE_TO_GREEN).is_green());
+        assert!(!(LapState::LOCAL_YELLOW | LapState::PITTED).is_green());
+    }
+
     #[test]
     fn strat_no_stops() {
         let d = TimeSpan::new(40, 0);
@@ -448,6 +844,10 @@ mod tests {
             max_fuel_save: 0.0,
             min_fuel: 0.0,
             yellow_togo: 0,
+            pre_green_laps: 0,
+            pre_green_rate: Rate::default(),
+            cooldown_laps: 0,
+            finish_reserve: 0.0,
             ends: EndsWith::Laps(5),
             green: Rate { fuel: 0.5, time: d },
             yellow: Rate { fuel: 0.1, time: d },
@@ -457,6 +857,79 @@ mod tests {
         assert_eq!(Vec::<Pitstop>::new(), s.stops);
     }
 
+    #[test]
+    fn strat_request_builder() {
+        let d = TimeSpan::new(25, 0);
+        let green = Rate { fuel: 0.5, time: d };
+        let ends = EndsWith::Time(TimeSpan::new(105, 0));
+        let r = StratRequestBuilder::new(20.0, ends, green).build();
+        assert_eq!(
+            StratRequest {
+                fuel_left: 20.0,
+                tank_size: 20.0,
+                max_fuel_save: 0.0,
+                min_fuel: 0.0,
+                yellow_togo: 0,
+                pre_green_laps: 0,
+                pre_green_rate: Rate::default(),
+                cooldown_laps: 0,
+                finish_reserve: 0.0,
+                ends,
+                green,
+                yellow: Rate::default(),
+            },
+            r
+        );
+        assert_eq!(vec![5], r.compute().unwrap().laps());
+
+        let yellow = Rate {
+            fuel: 0.1,
+            time: d * 4,
+        };
+        let r = StratRequestBuilder::new(20.0, EndsWith::Laps(50), green)
+            .fuel_left(9.5)
+            .max_fuel_save(0.1)
+            .min_fuel(0.5)
+            .yellow(yellow, 2)
+            .pre_green(1, yellow)
+            .cooldown_laps(1)
+            .finish_reserve(1.0)
+            .build();
+        assert_eq!(9.5, r.fuel_left);
+        assert_eq!(0.1, r.max_fuel_save);
+        assert_eq!(0.5, r.min_fuel);
+        assert_eq!((yellow, 2), (r.yellow, r.yellow_togo));
+        assert_eq!((1, yellow), (r.pre_green_laps, r.pre_green_rate));
+        assert_eq!(1, r.cooldown_laps);
+        assert_eq!(1.0, r.finish_reserve);
+    }
+
+    #[test]
+    fn race_fuel_requirement() {
+        let d = TimeSpan::new(30, 0);
+        let green = Rate { fuel: 0.5, time: d };
+        let mut r = StratRequestBuilder::new(20.0, EndsWith::Laps(50), green)
+            .fuel_left(3.0)
+            .build();
+        assert_eq!(25.0, r.race_fuel_requirement());
+        // the same however much fuel is in the car
+        r.fuel_left = 20.0;
+        assert_eq!(25.0, r.race_fuel_requirement());
+        let yellow = Rate {
+            fuel: 0.1,
+            time: d * 2,
+        };
+        let r = StratRequestBuilder::new(20.0, EndsWith::Laps(50), green)
+            .yellow(yellow, 2)
+            .pre_green(1, yellow)
+            .finish_reserve(1.0)
+            .build();
+        // 1 pace lap, 2 yellow & 48 green laps, plus the reserve
+        assert!((r.race_fuel_requirement() - 25.3).abs() < 0.0001);
+        let r = StratRequestBuilder::new(20.0, EndsWith::Laps(0), green).build();
+        assert_eq!(0.0, r.race_fuel_requirement());
+    }
+
     #[test]
     fn strat_timed_race() {
         let d = TimeSpan::new(25, 0);
@@ -466,6 +939,10 @@ mod tests {
             max_fuel_save: 0.0,
             min_fuel: 0.0,
             yellow_togo: 0,
+            pre_green_laps: 0,
+            pre_green_rate: Rate::default(),
+            cooldown_laps: 0,
+            finish_reserve: 0.0,
             ends: EndsWith::Time(TimeSpan::new(105, 0)),
             green: Rate { fuel: 0.5, time: d },
             yellow: Rate { fuel: 0.1, time: d },
@@ -483,12 +960,40 @@ mod tests {
             max_fuel_save: 0.1,
             min_fuel: 0.0,
             yellow_togo: 0,
+            pre_green_laps: 0,
+            pre_green_rate: Rate::default(),
+            cooldown_laps: 0,
+            finish_reserve: 0.0,
             ends: EndsWith::Laps(0),
             green: Rate { fuel: 0.5, time: d },
             yellow: Rate { fuel: 0.1, time: d },
         };
         let s = r.compute();
-        assert!(s.is_none());
+        assert_eq!(Err(StratError::RaceOver), s);
+    }
+
+    #[test]
+    fn strat_min_fuel_exceeds_tank() {
+        let d = TimeSpan::new(40, 0);
+        let mut r = StratRequest {
+            fuel_left: 9.0,
+            tank_size: 10.0,
+            max_fuel_save: 0.0,
+            min_fuel: 12.0,
+            yellow_togo: 0,
+            pre_green_laps: 0,
+            pre_green_rate: Rate::default(),
+            cooldown_laps: 0,
+            finish_reserve: 0.0,
+            ends: EndsWith::Laps(20),
+            green: Rate { fuel: 0.5, time: d },
+            yellow: Rate { fuel: 0.1, time: d },
+        };
+        assert_eq!(Err(StratError::MinFuelExceedsTank), r.compute());
+        r.min_fuel = 10.0;
+        assert_eq!(Err(StratError::MinFuelExceedsTank), r.compute());
+        r.min_fuel = 1.0;
+        assert_eq!(vec![16, 4], r.compute().unwrap().laps());
     }
 
     #[test]
@@ -500,6 +1005,10 @@ mod tests {
             max_fuel_save: 0.0,
             min_fuel: 0.0,
             yellow_togo: 0,
+            pre_green_laps: 0,
+            pre_green_rate: Rate::default(),
+            cooldown_laps: 0,
+            finish_reserve: 0.0,
             ends: EndsWith::Laps(34),
             green: Rate { fuel: 0.5, time: d },
             yellow: Rate { fuel: 0.1, time: d },
@@ -518,6 +1027,10 @@ mod tests {
             max_fuel_save: 0.0,
             min_fuel: 0.0,
             yellow_togo: 2,
+            pre_green_laps: 0,
+            pre_green_rate: Rate::default(),
+            cooldown_laps: 0,
+            finish_reserve: 0.0,
             ends: EndsWith::Time(TimeSpan::new(300, 0)),
             green: Rate { fuel: 1.0, time: d },
             yellow: Rate {
@@ -548,6 +1061,10 @@ mod tests {
             max_fuel_save: 0.0,
             min_fuel: 0.0,
             yellow_togo: 2,
+            pre_green_laps: 0,
+            pre_green_rate: Rate::default(),
+            cooldown_laps: 0,
+            finish_reserve: 0.0,
             ends: EndsWith::LapsOrTime(100, TimeSpan::new(300, 0)),
             green: Rate { fuel: 1.0, time: d },
             yellow: Rate {
@@ -578,6 +1095,10 @@ mod tests {
             max_fuel_save: 0.0,
             min_fuel: 0.0,
             yellow_togo: 2,
+            pre_green_laps: 0,
+            pre_green_rate: Rate::default(),
+            cooldown_laps: 0,
+            finish_reserve: 0.0,
             ends: EndsWith::LapsOrTime(10, TimeSpan::new(3000, 0)),
             green: Rate { fuel: 1.0, time: d },
             yellow: Rate {
@@ -599,6 +1120,10 @@ mod tests {
             max_fuel_save: 0.0,
             min_fuel: 0.0,
             yellow_togo: 3,
+            pre_green_laps: 0,
+            pre_green_rate: Rate::default(),
+            cooldown_laps: 0,
+            finish_reserve: 0.0,
             ends: EndsWith::Laps(23),
             green: Rate { fuel: 0.5, time: d },
             yellow: Rate {
@@ -620,6 +1145,10 @@ mod tests {
             min_fuel: 0.0,
             max_fuel_save: 0.0,
             yellow_togo: 0,
+            pre_green_laps: 0,
+            pre_green_rate: Rate::default(),
+            cooldown_laps: 0,
+            finish_reserve: 0.0,
             ends: EndsWith::Laps(49),
             green: Rate { fuel: 0.5, time: d },
             yellow: Rate { fuel: 0.1, time: d },
@@ -638,6 +1167,10 @@ mod tests {
             min_fuel: 0.0,
             max_fuel_save: 0.0,
             yellow_togo: 0,
+            pre_green_laps: 0,
+            pre_green_rate: Rate::default(),
+            cooldown_laps: 0,
+            finish_reserve: 0.0,
             ends: EndsWith::Laps(24),
             green: Rate { fuel: 0.5, time: d },
             yellow: Rate { fuel: 0.1, time: d },
@@ -656,6 +1189,10 @@ mod tests {
             min_fuel: 0.0,
             max_fuel_save: 0.0,
             yellow_togo: 0,
+            pre_green_laps: 0,
+            pre_green_rate: Rate::default(),
+            cooldown_laps: 0,
+            finish_reserve: 0.0,
             ends: EndsWith::Laps(29),
             green: Rate { fuel: 0.5, time: d },
             yellow: Rate { fuel: 0.1, time: d },
@@ -674,6 +1211,10 @@ mod tests {
             min_fuel: 0.0,
             max_fuel_save: 0.0,
             yellow_togo: 0,
+            pre_green_laps: 0,
+            pre_green_rate: Rate::default(),
+            cooldown_laps: 0,
+            finish_reserve: 0.0,
             ends: EndsWith::Laps(58),
             green: Rate { fuel: 0.5, time: d },
             yellow: Rate { fuel: 0.1, time: d },
@@ -692,6 +1233,10 @@ mod tests {
             min_fuel: 0.0,
             max_fuel_save: 0.1, //10%
             yellow_togo: 0,
+            pre_green_laps: 0,
+            pre_green_rate: Rate::default(),
+            cooldown_laps: 0,
+            finish_reserve: 0.0,
             ends: EndsWith::Laps(50),
             green: Rate { fuel: 1.0, time: d },
             yellow: Rate {
@@ -715,6 +1260,177 @@ mod tests {
         // div by the 49 laps to get to the last stint gives us
         // our per lap fuel target
         assert_eq!((9.0 + 20.0 + 20.0 - 1.0) / 49.0, s.fuel_target());
+        // saving a whole lap's worth of fuel can't be done in one lap
+        assert_eq!(None, s.single_save_lap_target());
+    }
+
+    #[test]
+    fn test_single_save_lap_target() {
+        let d = TimeSpan::new(30, 0);
+        let stint = |laps: i32| Stint {
+            laps,
+            fuel: laps as f32,
+            time: d * laps as u32,
+        };
+        let mut s = Strategy {
+            stints: vec![stint(9), stint(20), stint(1)],
+            fuel_to_save: 0.4,
+            green: Rate { fuel: 1.0, time: d },
+            ..Strategy::default()
+        };
+        // one lap using 0.6l rather than 1.0l saves the last stop
+        assert_eq!(Some(0.6), s.single_save_lap_target());
+        s.fuel_to_save = 0.0;
+        assert_eq!(None, s.single_save_lap_target());
+        s.fuel_to_save = 1.5;
+        assert_eq!(None, s.single_save_lap_target());
+        // no stops, nothing to skip
+        s.stints = vec![stint(30)];
+        s.fuel_to_save = 0.4;
+        assert_eq!(None, s.single_save_lap_target());
+    }
+
+    #[test]
+    fn test_fuel_target_at() {
+        let d = TimeSpan::new(30, 0);
+        let s = Strategy {
+            stints: vec![
+                Stint {
+                    laps: 10,
+                    fuel: 10.0,
+                    time: d * 10,
+                },
+                Stint {
+                    laps: 2,
+                    fuel: 1.0,
+                    time: d * 2,
+                },
+            ],
+            fuel_to_save: 1.0,
+            green: Rate { fuel: 1.0, time: d },
+            ..Strategy::default()
+        };
+        assert!((s.fuel_target() - 0.9).abs() < 0.0001);
+        assert!((s.fuel_target_at(0.0, 0) - 0.9).abs() < 0.0001);
+        // hitting the target keeps it the same
+        assert!((s.fuel_target_at(4.5, 5) - 0.9).abs() < 0.0001);
+        // not saving anything for the first half of the stint tightens it
+        assert!((s.fuel_target_at(5.0, 5) - 0.8).abs() < 0.0001);
+        assert!((s.fuel_target_at(8.0, 8) - 0.5).abs() < 0.0001);
+        // saving more than needed loosens it
+        assert!((s.fuel_target_at(4.0, 5) - 1.0).abs() < 0.0001);
+        // past the last stop there's nothing to target
+        assert_eq!(0.0, s.fuel_target_at(10.0, 10));
+    }
+    #[test]
+    fn no_stop_race_has_nothing_to_save() {
+        let d = TimeSpan::new(30, 0);
+        let green = Rate { fuel: 1.0, time: d };
+        let mut r = StratRequestBuilder::new(10.0, EndsWith::Laps(8), green)
+            .max_fuel_save(0.2)
+            .build();
+        let s = r.compute().unwrap();
+        assert_eq!(vec![8], s.laps());
+        assert_eq!(0.0, s.fuel_to_save);
+        assert_eq!(0.0, s.fuel_target());
+        // even when the max save is more than the whole stint
+        r.max_fuel_save = 2.0;
+        let s = r.compute().unwrap();
+        assert_eq!(0.0, s.fuel_to_save);
+        assert_eq!(0.0, s.fuel_target());
+        // and a strategy that claims a save anyway has no target
+        let s = Strategy {
+            fuel_to_save: 1.0,
+            ..s
+        };
+        assert_eq!(0.0, s.fuel_target());
+    }
+    #[test]
+    fn test_break_even_target() {
+        let d = TimeSpan::new(30, 0);
+        let stint = |laps| Stint {
+            laps,
+            fuel: laps as f32,
+            time: d * (laps as u32),
+        };
+        let s = Strategy {
+            stints: vec![stint(10), stint(10), stint(5)],
+            stops: vec![Pitstop::new(5, 10), Pitstop::new(15, 20)],
+            green: Rate { fuel: 1.0, time: d },
+            ..Strategy::default()
+        };
+        // 15L has to last the 20 laps to the stop after next
+        assert!((s.break_even_target(15.0, 0).unwrap() - 0.75).abs() < 0.0001);
+        assert!((s.break_even_target(12.0, 4).unwrap() - 0.75).abs() < 0.0001);
+        // with one stop, skipping it means making the finish
+        let one = Strategy {
+            stints: vec![stint(10), stint(5)],
+            stops: vec![Pitstop::new(5, 10)],
+            ..s.clone()
+        };
+        assert!((one.break_even_target(12.0, 0).unwrap() - 0.8).abs() < 0.0001);
+        // nothing to skip
+        let none = Strategy {
+            stints: vec![stint(10)],
+            stops: vec![],
+            ..s.clone()
+        };
+        assert_eq!(None, none.break_even_target(10.0, 0));
+        assert_eq!(None, s.break_even_target(5.0, 20));
+        assert_eq!(None, s.break_even_target(0.0, 0));
+    }
+    #[test]
+    fn test_spread_save() {
+        let d = TimeSpan::new(30, 0);
+        let stint = |laps| Stint {
+            laps,
+            fuel: laps as f32,
+            time: d * (laps as u32),
+        };
+        let s = Strategy {
+            stints: vec![stint(20), stint(20)],
+            green: Rate { fuel: 1.0, time: d },
+            ..Strategy::default()
+        };
+        let t = s.spread_save(2.0);
+        assert_eq!(2, t.len());
+        assert!(t.iter().all(|t| (t - 0.95).abs() < 0.0001));
+        // nothing to save is the normal rate
+        assert!(s.spread_save(0.0).iter().all(|t| (t - 1.0).abs() < 0.0001));
+        // a shorter stint saves its share by laps, which is the same per lap
+        let s = Strategy {
+            stints: vec![stint(30), stint(10)],
+            ..s
+        };
+        assert!(s.spread_save(2.0).iter().all(|t| (t - 0.95).abs() < 0.0001));
+    }
+
+    #[test]
+    fn pitstop_window() {
+        let ps = RaceStop::new(10, 29);
+        assert!(!ps.is_open(9));
+        assert!(ps.is_open(10));
+        assert_eq!(PitWindow::OpensIn(10), ps.status(0));
+        assert_eq!(PitWindow::OpensIn(1), ps.status(9));
+        assert_eq!(PitWindow::ClosesIn(19), ps.status(10));
+        assert_eq!(PitWindow::ClosesIn(0), ps.status(29));
+        assert_eq!(PitWindow::Missed, ps.status(30));
+    }
+
+    #[test]
+    fn pitstop_race_laps() {
+        // a live strategy computed at the end of lap 12
+        let live = Pitstop::new(3, 12);
+        let race = live.to_race_laps(12);
+        assert_eq!(RaceStop::new(15, 24), race);
+        assert_eq!(live, race.relative_to(12));
+        assert_eq!(PitWindow::OpensIn(3), race.status(12));
+        // 5 laps later the window's open, and closes in 7 laps
+        assert_eq!(Pitstop::new(-2, 7), race.relative_to(17));
+        assert!(race.is_open(17));
+        assert_eq!(PitWindow::ClosesIn(7), race.status(17));
+        // the offline planner starts at the green flag, so they're already race laps
+        assert_eq!(RaceStop::new(3, 12), live.to_race_laps(0));
     }
 
     #[test]
@@ -730,6 +1446,25 @@ mod tests {
         assert!(TimeSpan::from_str("").is_err());
         assert!(TimeSpan::from_str("bob").is_err());
     }
+    #[test]
+    fn test_timespan_parse_seconds() {
+        assert_eq!(TimeSpan::from_str("90").unwrap().d.as_secs(), 90);
+        assert_eq!(TimeSpan::from_str(" 90 ").unwrap().d.as_secs(), 90);
+        assert_eq!(
+            TimeSpan::from_str("90.5").unwrap(),
+            TimeSpan::from_secs_f64(90.5)
+        );
+        // a colon is still minutes & seconds
+        assert_eq!(TimeSpan::from_str("1:30").unwrap().d.as_secs(), 90);
+        assert_eq!(TimeSpan::from_str("01:30").unwrap().d.as_secs(), 90);
+        assert!(TimeSpan::from_str("90.").is_err());
+        assert!(TimeSpan::from_str("-90").is_err());
+        assert!(TimeSpan::from_str("1:3").is_err());
+        assert_eq!(
+            Err(ParseError::Bogus),
+            TimeSpan::from_str("99999999999999999999")
+        );
+    }
 
     #[test]
     fn test_timespan_display() {
@@ -743,4 +1478,321 @@ mod tests {
         assert_eq!(format!("{}", TimeSpan::new(3600, 0)), "1:00:00");
         assert_eq!(format!("{}", TimeSpan::new(3600 * 5 + 5, 0)), "5:00:05");
     }
+
+    #[test]
+    fn pre_green_laps() {
+        let d = TimeSpan::new(30, 0);
+        let mut r = StratRequest {
+            fuel_left: 10.0,
+            tank_size: 10.0,
+            max_fuel_save: 0.0,
+            min_fuel: 0.0,
+            yellow_togo: 0,
+            pre_green_laps: 0,
+            pre_green_rate: Rate { fuel: 0.5, time: d },
+            cooldown_laps: 0,
+            finish_reserve: 0.0,
+            ends: EndsWith::Laps(25),
+            green: Rate { fuel: 1.0, time: d },
+            yellow: Rate { fuel: 0.5, time: d },
+        };
+        let s = r.compute().unwrap();
+        assert_eq!(vec![10, 10, 5], s.laps());
+        assert_eq!(vec![Pitstop::new(5, 10), Pitstop::new(15, 20)], s.stops);
+        // 2 parade laps use a lap's worth of fuel from the first stint
+        r.pre_green_laps = 2;
+        let s = r.compute().unwrap();
+        assert_eq!(vec![9, 10, 6], s.laps());
+        assert_eq!(vec![Pitstop::new(5, 9), Pitstop::new(15, 19)], s.stops);
+    }
+    #[test]
+    fn compute_for_stops() {
+        let d = TimeSpan::new(30, 0);
+        let r = StratRequest {
+            fuel_left: 10.0,
+            tank_size: 10.0,
+            max_fuel_save: 0.1,
+            min_fuel: 0.0,
+            yellow_togo: 0,
+            pre_green_laps: 0,
+            pre_green_rate: Rate::default(),
+            cooldown_laps: 0,
+            finish_reserve: 0.0,
+            ends: EndsWith::Laps(32),
+            green: Rate { fuel: 1.0, time: d },
+            yellow: Rate { fuel: 0.5, time: d },
+        };
+        let s = r.compute().unwrap();
+        assert_eq!(3, s.stops.len());
+        // the same number of stops needs no saving
+        let s = r.compute_for_stops(3).unwrap();
+        assert_eq!(1.0, s.green.fuel);
+        // 2 stops needs 11 laps from each tank
+        let s = r.compute_for_stops(2).unwrap();
+        assert_eq!(2, s.stops.len());
+        assert_eq!(vec![11, 11, 10], s.laps());
+        assert!(s.green.fuel <= 10.0 / 11.0);
+        assert!(s.green.fuel > 0.909);
+        assert_eq!(0.0, s.fuel_to_save);
+        // 1 stop would need more than the 10% max fuel save
+        assert!(r.compute_for_stops(1).is_none());
+        // more stops than needed isn't a fuel saving question
+        assert!(r.compute_for_stops(4).is_none());
+    }
+    #[test]
+    fn save_target_for_stops() {
+        let d = TimeSpan::new(30, 0);
+        let mut r = StratRequestBuilder::new(10.0, EndsWith::Laps(25), Rate { fuel: 1.0, time: d })
+            .max_fuel_save(0.25)
+            .build();
+        assert_eq!(2, r.compute().unwrap().stops.len());
+        assert_eq!(Some(1.0), r.save_target_for_stops(2));
+        // 1 stop needs 13 laps from the first tank
+        let target = r.save_target_for_stops(1).unwrap();
+        assert!(target <= 10.0 / 13.0);
+        assert!(target > 0.769);
+        // which is more than a 10% save
+        r.max_fuel_save = 0.1;
+        assert_eq!(None, r.save_target_for_stops(1));
+        assert_eq!(None, r.save_target_for_stops(0));
+    }
+    #[test]
+    fn laps_to_empty_with_yellow() {
+        let d = TimeSpan::new(30, 0);
+        let green = Rate { fuel: 0.5, time: d };
+        let yellow = Rate { fuel: 0.1, time: d };
+        // 3 yellow laps use 0.3, the remaining 4.7 is 9.4 green laps
+        assert_eq!(12.4, laps_to_empty(5.0, 3, yellow, green));
+        assert_eq!(10.0, laps_to_empty(5.0, 0, yellow, green));
+        // runs out during the caution
+        assert_eq!(2.0, laps_to_empty(0.2, 3, yellow, green));
+        assert_eq!(0.0, laps_to_empty(0.0, 3, yellow, green));
+        assert_eq!(0.0, laps_to_empty(5.0, 0, yellow, Rate::default()));
+    }
+    #[test]
+    fn cooldown_laps() {
+        let d = TimeSpan::new(30, 0);
+        let mut r = StratRequest {
+            fuel_left: 10.0,
+            tank_size: 10.0,
+            max_fuel_save: 0.0,
+            min_fuel: 0.0,
+            yellow_togo: 0,
+            pre_green_laps: 0,
+            pre_green_rate: Rate::default(),
+            cooldown_laps: 0,
+            finish_reserve: 0.0,
+            ends: EndsWith::Laps(25),
+            green: Rate { fuel: 1.0, time: d },
+            yellow: Rate { fuel: 0.5, time: d },
+        };
+        assert_eq!(vec![10, 10, 5], r.compute().unwrap().laps());
+        r.cooldown_laps = 1;
+        let s = r.compute().unwrap();
+        assert_eq!(vec![10, 10, 6], s.laps());
+        assert_eq!(6.0, s.stints.last().unwrap().fuel);
+        assert_eq!(vec![Pitstop::new(6, 10), Pitstop::new(16, 20)], s.stops);
+        // cooldown laps can push it to another stop
+        r.cooldown_laps = 6;
+        assert_eq!(vec![10, 10, 10, 1], r.compute().unwrap().laps());
+        // once the race is over, there's nothing to plan
+        r.ends = EndsWith::Laps(0);
+        assert_eq!(Err(StratError::RaceOver), r.compute());
+    }
+    #[test]
+    fn strat_lap_exceeds_tank() {
+        let d = TimeSpan::new(30, 0);
+        let mut r = StratRequest {
+            fuel_left: 10.0,
+            tank_size: 10.0,
+            max_fuel_save: 0.0,
+            min_fuel: 0.5,
+            yellow_togo: 0,
+            pre_green_laps: 0,
+            pre_green_rate: Rate::default(),
+            cooldown_laps: 0,
+            finish_reserve: 0.0,
+            ends: EndsWith::Laps(25),
+            green: Rate {
+                fuel: 12.0,
+                time: d,
+            },
+            yellow: Rate {
+                fuel: 20.0,
+                time: d,
+            },
+        };
+        assert_eq!(Err(StratError::LapExceedsTank), r.compute());
+        // min fuel counts against the tank
+        r.green.fuel = 9.8;
+        assert_eq!(Err(StratError::LapExceedsTank), r.compute());
+        // the yellow rate only matters when there are yellow laps to go
+        r.green.fuel = 9.5;
+        assert_eq!(25, r.compute().unwrap().total_laps());
+        r.yellow_togo = 2;
+        assert_eq!(Err(StratError::LapExceedsTank), r.compute());
+    }
+    #[test]
+    fn yellow_pit_gain() {
+        let mut r = StratRequest {
+            fuel_left: 4.0,
+            tank_size: 10.0,
+            max_fuel_save: 0.0,
+            min_fuel: 0.0,
+            yellow_togo: 2,
+            pre_green_laps: 0,
+            pre_green_rate: Rate::default(),
+            cooldown_laps: 0,
+            finish_reserve: 0.0,
+            ends: EndsWith::Laps(21),
+            green: Rate {
+                fuel: 1.0,
+                time: TimeSpan::new(30, 0),
+            },
+            yellow: Rate {
+                fuel: 0.5,
+                time: TimeSpan::new(60, 0),
+            },
+        };
+        let pit_loss = TimeSpan::new(40, 0);
+        // staying out needs 2 more stops, pitting now under yellow saves one of them.
+        assert_eq!(Some(1.0), r.yellow_pit_gain(pit_loss));
+        // staying out only needs 1 stop, pitting now doesn't save one.
+        r.fuel_left = 9.0;
+        r.ends = EndsWith::Laps(19);
+        let gain = r.yellow_pit_gain(pit_loss).unwrap();
+        assert!((gain + 0.333).abs() < 0.001, "{}", gain);
+        // can make it to the end
+        r.ends = EndsWith::Laps(10);
+        let gain = r.yellow_pit_gain(pit_loss).unwrap();
+        assert!((gain + 0.333).abs() < 0.001, "{}", gain);
+        r.ends = EndsWith::Laps(0);
+        assert_eq!(None, r.yellow_pit_gain(pit_loss));
+    }
+    #[test]
+    fn time_at_lap() {
+        let s = Strategy {
+            stints: vec![
+                Stint {
+                    laps: 10,
+                    fuel: 10.0,
+                    time: TimeSpan::new(500, 0),
+                },
+                Stint {
+                    laps: 4,
+                    fuel: 4.0,
+                    time: TimeSpan::new(400, 0),
+                },
+            ],
+            stops: vec![Pitstop::new(4, 10)],
+            fuel_to_save: 0.0,
+            green: Rate::default(),
+            yellow: Rate::default(),
+        };
+        assert_eq!(TimeSpan::ZERO, s.time_at_lap(0));
+        assert_eq!(TimeSpan::new(200, 0), s.time_at_lap(4));
+        assert_eq!(TimeSpan::new(500, 0), s.time_at_lap(10));
+        // the 2nd stint has slower laps
+        assert_eq!(TimeSpan::new(700, 0), s.time_at_lap(12));
+        assert_eq!(s.total_time(), s.time_at_lap(14));
+        assert_eq!(s.total_time(), s.time_at_lap(20));
+        // an empty stint is skipped
+        let mut empty_first = s.clone();
+        empty_first.stints.insert(0, Stint::new());
+        assert_eq!(TimeSpan::ZERO, empty_first.time_at_lap(0));
+        assert_eq!(TimeSpan::new(200, 0), empty_first.time_at_lap(4));
+    }
+    #[test]
+    fn finish_reserve() {
+        let d = TimeSpan::new(30, 0);
+        let mut r = StratRequest {
+            fuel_left: 10.0,
+            tank_size: 10.0,
+            max_fuel_save: 0.0,
+            min_fuel: 0.0,
+            yellow_togo: 0,
+            pre_green_laps: 0,
+            pre_green_rate: Rate::default(),
+            cooldown_laps: 0,
+            finish_reserve: 0.0,
+            ends: EndsWith::Laps(25),
+            green: Rate { fuel: 1.0, time: d },
+            yellow: Rate { fuel: 0.5, time: d },
+        };
+        let s = r.compute().unwrap();
+        assert_eq!(5.0, s.stints.last().unwrap().fuel);
+        r.finish_reserve = 0.5;
+        let s = r.compute().unwrap();
+        assert_eq!(vec![10, 10, 5], s.laps());
+        // the final stint needs enough fuel to finish with the reserve
+        assert_eq!(5.5, s.stints.last().unwrap().fuel);
+        assert_eq!(25.5, s.total_fuel());
+        // the last stint can only be extended to 9 laps with the reserve
+        assert_eq!(vec![Pitstop::new(6, 10), Pitstop::new(16, 20)], s.stops);
+        // the reserve can need another stop
+        r.ends = EndsWith::Laps(20);
+        let s = r.compute().unwrap();
+        assert_eq!(vec![10, 9, 1], s.laps());
+        assert_eq!(1.5, s.stints.last().unwrap().fuel);
+    }
+    #[test]
+    fn time_span_mul_f32() {
+        let t = TimeSpan::new(30, 0) * 1.1;
+        assert!((t.as_secs_f64() - 33.0).abs() < 0.001);
+        assert_eq!(TimeSpan::new(60, 0), TimeSpan::new(30, 0) * 2.0);
+    }
+    #[test]
+    fn can_save_to() {
+        let r = Rate {
+            fuel: 2.0,
+            time: TimeSpan::new(40, 0),
+        };
+        // no saving needed
+        assert!(r.can_save_to(0.0, 0.1));
+        assert!(r.can_save_to(2.0, 0.1));
+        // 5% and 10% are fine, 15% and 50% aren't
+        assert!(r.can_save_to(1.9, 0.1));
+        assert!(r.can_save_to(1.8, 0.1));
+        assert!(!r.can_save_to(1.7, 0.1));
+        assert!(!r.can_save_to(1.0, 0.1));
+        assert!(r.can_save_to(1.0, 0.5));
+        // no rate yet
+        assert!(Rate::default().can_save_to(1.0, 0.1));
+    }
+    #[test]
+    fn planned_stops_skip_the_saved_stop() {
+        let green = Rate {
+            fuel: 1.0,
+            time: TimeSpan::new(30, 0),
+        };
+        let mut r = StratRequestBuilder::new(10.0, EndsWith::Laps(21), green).build();
+        let s = r.compute().unwrap();
+        assert_eq!(2, s.stops.len());
+        assert_eq!(s.stops, s.planned_stops());
+        r.max_fuel_save = 0.1;
+        let s = r.compute().unwrap();
+        assert_eq!(1.0, s.fuel_to_save);
+        assert_eq!(2, s.stops.len());
+        assert_eq!(&s.stops[..1], s.planned_stops());
+    }
+    #[test]
+    fn strategy_after_laps() {
+        let green = Rate {
+            fuel: 1.0,
+            time: TimeSpan::new(30, 0),
+        };
+        let s = StratRequestBuilder::new(10.0, EndsWith::Laps(25), green)
+            .fuel_left(6.0)
+            .build()
+            .compute()
+            .unwrap();
+        assert_eq!(vec![6, 10, 9], s.laps());
+        assert_eq!(vec![Pitstop::new(5, 6), Pitstop::new(15, 16)], s.stops);
+        let a = s.after_laps(2);
+        assert_eq!(vec![4, 10, 9], a.laps());
+        assert_eq!(4.0, a.stints[0].fuel);
+        assert_eq!(TimeSpan::new(120, 0), a.stints[0].time);
+        assert_eq!(vec![Pitstop::new(3, 4), Pitstop::new(13, 14)], a.stops);
+        assert_eq!(s, s.after_laps(0));
+    }
 }